@@ -0,0 +1,398 @@
+use std::io::{Read, Seek, SeekFrom};
+use super::processor::*;
+
+// Match found by suffix search is used when it is this much better than extending previous match
+const MIN_MATCH_GAIN: usize = 8;
+
+// Data of delta and old file is processed in parts of this size
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// Size of record header: u64 diff length, u64 extra length, i64 seek in old file
+const RECORD_HEADER_SIZE: usize = 24;
+
+// Longest LEB128 encoding of u64
+const MAX_VARINT_SIZE: usize = 10;
+
+// Largest old and new file of bsdiff delta, both files are kept in memory
+// and suffix array of old file takes another 12 bytes per byte of old file while it is sorted, about 1 GiB in total
+pub const MAX_BSDIFF_INPUT_SIZE: u64 = 64 * 1024 * 1024;
+
+
+// bsdiff delta format, written after hdiff delta file header:
+// sequence of records, every record contains:
+// u64 diff length, u64 extra length, i64 seek in old file, diff block, extra block
+// diff block is added byte-wise to data of old file, it is encoded as pairs of varints
+// (zero run length, literal length) followed by literal bytes, zero runs copy old data unchanged
+// extra block is written to output as it is, seek moves position in old file after the record
+// all numbers are little endian, varints are LEB128
+pub fn process_bsdiff<S: ProcessorDataOutput>(old: &[u8], new: &[u8], output_file: &mut S) -> Result<(), ProcessorError> {
+    if old.len() as u64 > MAX_BSDIFF_INPUT_SIZE || new.len() as u64 > MAX_BSDIFF_INPUT_SIZE {
+        return Err(ProcessorError::InputTooLarge)
+    }
+    let suffixes = suffix_array(old);
+
+    let (mut scan, mut len, mut pos) = (0, 0, 0);
+    let (mut last_scan, mut last_pos, mut last_offset) = (0, 0, 0i64);
+    while scan < new.len() {
+        // find next match which is not only continuation of previous one
+        let mut old_score = 0i64;
+        scan += len;
+        let mut score_scan = scan;
+        while scan < new.len() {
+            (pos, len) = search(&suffixes, old, &new[scan..]);
+            while score_scan < scan + len {
+                if matches_at(old, new, score_scan, last_offset) {
+                    old_score += 1;
+                }
+                score_scan += 1;
+            }
+            if ( len as i64 == old_score && len != 0 ) || len as i64 > old_score + MIN_MATCH_GAIN as i64 {
+                break
+            }
+            if matches_at(old, new, scan, last_offset) {
+                old_score -= 1;
+            }
+            scan += 1;
+        }
+
+        if len as i64 != old_score || scan == new.len() {
+            // previous match is extended forwards while at least half of bytes are equal
+            let (mut equal, mut best, mut len_forward) = (0i64, 0i64, 0);
+            let mut i = 0;
+            while last_scan + i < scan && last_pos + i < old.len() {
+                if old[last_pos + i] == new[last_scan + i] {
+                    equal += 1;
+                }
+                i += 1;
+                if equal * 2 - i as i64 > best * 2 - len_forward as i64 {
+                    best = equal;
+                    len_forward = i;
+                }
+            }
+
+            // new match is extended backwards in the same way
+            let mut len_backward = 0;
+            if scan < new.len() {
+                let (mut equal, mut best) = (0i64, 0i64);
+                let mut i = 1;
+                while scan >= last_scan + i && pos >= i {
+                    if old[pos - i] == new[scan - i] {
+                        equal += 1;
+                    }
+                    if equal * 2 - i as i64 > best * 2 - len_backward as i64 {
+                        best = equal;
+                        len_backward = i;
+                    }
+                    i += 1;
+                }
+            }
+
+            // overlapping extensions are split where the most bytes are equal
+            if last_scan + len_forward > scan - len_backward {
+                let overlap = last_scan + len_forward - (scan - len_backward);
+                let (mut equal, mut best, mut len_split) = (0i64, 0i64, 0);
+                for i in 0..overlap {
+                    if new[last_scan + len_forward - overlap + i] == old[last_pos + len_forward - overlap + i] {
+                        equal += 1;
+                    }
+                    if new[scan - len_backward + i] == old[pos - len_backward + i] {
+                        equal -= 1;
+                    }
+                    if equal > best {
+                        best = equal;
+                        len_split = i + 1;
+                    }
+                }
+                len_forward = len_forward + len_split - overlap;
+                len_backward -= len_split;
+            }
+
+            let diff: Vec<u8> = (0..len_forward).map(|i| new[last_scan + i].wrapping_sub(old[last_pos + i])).collect();
+            let extra = &new[last_scan + len_forward..scan - len_backward];
+            let seek = (pos - len_backward) as i64 - (last_pos + len_forward) as i64;
+            write_record(output_file, &diff, extra, seek)?;
+
+            last_scan = scan - len_backward;
+            last_pos = pos - len_backward;
+            last_offset = pos as i64 - scan as i64;
+        }
+    }
+    Ok(())
+}
+
+pub fn process_bsdiff_patch<R, U, S>(old_file: &mut R, delta_file: &mut U, output_file: &mut S) -> Result<(), ProcessorError>
+    where R: Read + Seek, U: ProcessorDataInput, S: ProcessorDataOutput
+{
+    let mut old_buffer = vec![0; BUFFER_SIZE];
+    let mut old_pos = 0u64;
+    loop {
+        delta_file.set_chunk_size(RECORD_HEADER_SIZE);
+        let header = delta_file.get_next_data();
+        if header.is_empty() {
+            return Ok(())
+        }
+        if header.len() != RECORD_HEADER_SIZE {
+            return Err(ProcessorError::InvalidDelta)
+        }
+        let diff_len = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let mut extra_len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let seek = i64::from_le_bytes(header[16..24].try_into().unwrap());
+
+        if old_file.seek(SeekFrom::Start(old_pos)).is_err() {
+            return Err(ProcessorError::FileSeek)
+        }
+
+        // diff block is added to old data
+        let mut remaining = diff_len;
+        while remaining > 0 {
+            let zero_run = read_varint(delta_file)?;
+            let literal_len = read_varint(delta_file)?;
+            // empty pair would never finish diff block
+            if zero_run.checked_add(literal_len).is_none_or(|len| len == 0 || len > remaining) {
+                return Err(ProcessorError::InvalidDelta)
+            }
+            remaining -= zero_run + literal_len;
+
+            let mut len = zero_run;
+            while len > 0 {
+                let part = &mut old_buffer[..std::cmp::min(len, BUFFER_SIZE as u64) as usize];
+                read_old(old_file, part)?;
+                if !output_file.write_data(part) {
+                    return Err(ProcessorError::FileWrite)
+                }
+                len -= part.len() as u64;
+            }
+
+            let mut len = literal_len;
+            while len > 0 {
+                let part = &mut old_buffer[..std::cmp::min(len, BUFFER_SIZE as u64) as usize];
+                read_old(old_file, part)?;
+                delta_file.set_chunk_size(part.len());
+                let data = delta_file.get_next_data();
+                if data.len() != part.len() {
+                    return Err(ProcessorError::InvalidDelta)
+                }
+                part.iter_mut().zip(data).for_each(|(old, diff)| *old = old.wrapping_add(*diff));
+                if !output_file.write_data(part) {
+                    return Err(ProcessorError::FileWrite)
+                }
+                len -= part.len() as u64;
+            }
+        }
+
+        // extra block is written unchanged
+        while extra_len > 0 {
+            delta_file.set_chunk_size(std::cmp::min(extra_len, BUFFER_SIZE as u64) as usize);
+            let data = delta_file.get_next_data();
+            if data.is_empty() {
+                return Err(ProcessorError::InvalidDelta)
+            }
+            if !output_file.write_data(data) {
+                return Err(ProcessorError::FileWrite)
+            }
+            extra_len -= data.len() as u64;
+        }
+
+        // position before start of old file means delta was made for other file
+        old_pos = match old_pos.checked_add(diff_len).and_then(|pos| pos.checked_add_signed(seek)) {
+            Some(pos) => pos,
+            None => return Err(ProcessorError::InvalidDelta)
+        };
+    }
+}
+
+// Suffix array of data including empty suffix, suffixes are sorted by prefix doubling
+// ranks of suffixes are compared by first k bytes, k is doubled until all ranks are different
+// positions and ranks are u32, input is limited by MAX_BSDIFF_INPUT_SIZE
+fn suffix_array(data: &[u8]) -> Vec<u32> {
+    let n = data.len();
+    let mut suffixes: Vec<u32> = (0..=n as u32).collect();
+    let mut rank: Vec<u32> = data.iter().map(|byte| *byte as u32 + 1).chain(std::iter::once(0)).collect();
+    let mut next_rank = vec![0u32; n + 1];
+    let mut k = 1;
+    loop {
+        let key = |i: u32| (rank[i as usize], if i as usize + k <= n { rank[i as usize + k] } else { 0 });
+        suffixes.sort_unstable_by_key(|i| key(*i));
+
+        next_rank[suffixes[0] as usize] = 0;
+        for i in 1..=n {
+            next_rank[suffixes[i] as usize] = next_rank[suffixes[i - 1] as usize] + u32::from(key(suffixes[i - 1]) != key(suffixes[i]));
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+        if rank[suffixes[n] as usize] as usize == n {
+            return suffixes
+        }
+        k *= 2;
+    }
+}
+
+// Binary search of the longest prefix of data present in old file, returns its position and length
+fn search(suffixes: &[u32], old: &[u8], data: &[u8]) -> (usize, usize) {
+    let (mut start, mut end) = (0, suffixes.len() - 1);
+    while end - start >= 2 {
+        let middle = start + (end - start) / 2;
+        if old[suffixes[middle] as usize..] < *data {
+            start = middle;
+        } else {
+            end = middle;
+        }
+    }
+    let (start, end) = (suffixes[start] as usize, suffixes[end] as usize);
+    let start_len = match_len(&old[start..], data);
+    let end_len = match_len(&old[end..], data);
+    if start_len > end_len { (start, start_len) } else { (end, end_len) }
+}
+
+fn match_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+// Checks if byte of new data is equal to old data moved by offset
+fn matches_at(old: &[u8], new: &[u8], index: usize, offset: i64) -> bool {
+    let old_index = index as i64 + offset;
+    old_index >= 0 && (old_index as usize) < old.len() && old[old_index as usize] == new[index]
+}
+
+fn write_record<S: ProcessorDataOutput>(output_file: &mut S, diff: &[u8], extra: &[u8], seek: i64) -> Result<(), ProcessorError> {
+    let mut data = Vec::with_capacity(RECORD_HEADER_SIZE + diff.len() + extra.len());
+    data.extend_from_slice(&(diff.len() as u64).to_le_bytes());
+    data.extend_from_slice(&(extra.len() as u64).to_le_bytes());
+    data.extend_from_slice(&seek.to_le_bytes());
+
+    // diff block is mostly zeros, only bytes at changed addresses are written
+    let mut i = 0;
+    while i < diff.len() {
+        let zero_run = diff[i..].iter().take_while(|byte| **byte == 0).count();
+        let literal_len = diff[i + zero_run..].iter().take_while(|byte| **byte != 0).count();
+        write_varint(&mut data, zero_run as u64);
+        write_varint(&mut data, literal_len as u64);
+        data.extend_from_slice(&diff[i + zero_run..i + zero_run + literal_len]);
+        i += zero_run + literal_len;
+    }
+    data.extend_from_slice(extra);
+
+    if output_file.write_data(&data) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn read_varint<U: ProcessorDataInput>(delta_file: &mut U) -> Result<u64, ProcessorError> {
+    delta_file.set_chunk_size(1);
+    let mut value = 0;
+    for i in 0..MAX_VARINT_SIZE {
+        let byte = match delta_file.get_next_data() {
+            [byte] => *byte,
+            _ => return Err(ProcessorError::InvalidDelta)
+        };
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+    Err(ProcessorError::InvalidDelta)
+}
+
+// Reading outside of old file means delta was made for other file
+fn read_old<R: Read>(old_file: &mut R, data: &mut [u8]) -> Result<(), ProcessorError> {
+    old_file.read_exact(data).map_err(|_| ProcessorError::InvalidDelta)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mem_data::*;
+
+    fn delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        process_bsdiff(old, new, &mut output).unwrap();
+        output
+    }
+
+    fn patch(old: &[u8], delta: &[u8]) -> Result<Vec<u8>, ProcessorError> {
+        let mut output = Vec::new();
+        process_bsdiff_patch(&mut std::io::Cursor::new(old), &mut MemInput::new(delta, 1), &mut output)?;
+        Ok(output)
+    }
+
+    // Imitates compiled code, instructions are followed by absolute addresses
+    fn binary(code_start: u32, len: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..len {
+            data.extend_from_slice(&[0x55, 0x48, 0x89, 0xe5, 0x48, 0x83, 0xec, (i * 7 % 256) as u8, 0x89, 0x7d, 0xfc, 0x8b, 0x45, 0xfc]);
+            data.extend_from_slice(&[0x83, 0xc0, (i % 251) as u8, 0x89, 0xc7, 0x31, 0xc0, 0x48, 0x8b, 0x4d, 0x10, 0x0f, 0xaf, 0xc1, 0xe8]);
+            data.extend_from_slice(&(code_start + i * 13 % 4096).to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_bsdiff_suffix_array() {
+        // bsdiff test
+        // scenario: suffixes of known data are sorted, longest match is found
+
+        assert_eq!( suffix_array(b"banana"), [6,5,3,1,0,4,2] );
+        assert_eq!( suffix_array(b""), [0] );
+        assert_eq!( suffix_array(b"aaaa"), [4,3,2,1,0] );
+
+        let suffixes = suffix_array(b"banana");
+        assert_eq!( search(&suffixes, b"banana", b"anab"), (1, 3) );
+        assert_eq!( search(&suffixes, b"banana", b"x").1, 0 );
+        assert_eq!( search(&suffix_array(b""), b"", b"abc"), (0, 0) );
+    }
+
+    #[test]
+    fn test_bsdiff_patch() {
+        // bsdiff test
+        // scenario: known delta with diff, zero runs and extra data is applied
+
+        let delta = [4,0,0,0,0,0,0,0, 2,0,0,0,0,0,0,0, 2,0,0,0,0,0,0,0, 2,1,1, 1,0, b'X',b'Y',
+                     2,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,0, 2,0];
+        assert_eq!( patch(b"abcdefgh", &delta).unwrap(), b"abddXYgh" );
+        assert_eq!( patch(b"abcdefgh", &[]).unwrap(), b"" );
+    }
+
+    #[test]
+    fn test_bsdiff_delta() {
+        // bsdiff test
+        // scenario: delta between binaries with shifted code and addresses is small and recreates new file
+
+        let old = binary(0x401000, 2000);
+        let mut new = vec![0x90; 100];
+        new.extend_from_slice(&binary(0x401064, 2000));
+        new[5000..5010].copy_from_slice(b"0123456789");
+
+        let bsdiff_delta = delta(&old, &new);
+        assert!( bsdiff_delta.len() < new.len() / 4 );
+        assert_eq!( patch(&old, &bsdiff_delta).unwrap(), new );
+
+        for (old, new) in [(&b""[..], &b"abc"[..]), (b"abc", b""), (b"abc", b"abc"), (b"aaaaaaaa", b"aaabaaaa")] {
+            assert_eq!( patch(old, &delta(old, new)).unwrap(), new );
+        }
+    }
+
+    #[test]
+    fn test_bsdiff_patch_invalid() {
+        // bsdiff test
+        // scenario: truncated delta, diff outside of old file, seek before start of old file, diff longer than record and empty pair are refused
+
+        let delta = delta(b"abcdefgh", b"abcXefghij");
+        assert!( matches!( patch(b"abcdefgh", &delta[..delta.len() - 1]), Err(ProcessorError::InvalidDelta) ) );
+        assert!( matches!( patch(b"abcdefgh", &delta[..10]), Err(ProcessorError::InvalidDelta) ) );
+        assert!( matches!( patch(b"abc", &delta), Err(ProcessorError::InvalidDelta) ) );
+
+        let seek = [0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,0, 0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff];
+        assert!( matches!( patch(b"abcdefgh", &seek), Err(ProcessorError::InvalidDelta) ) );
+
+        let diff = [2,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,0, 3,0];
+        assert!( matches!( patch(b"abcdefgh", &diff), Err(ProcessorError::InvalidDelta) ) );
+        let empty_pair = [2,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,0, 0,0,0,0,0,0,0,0, 0,0];
+        assert!( matches!( patch(b"abcdefgh", &empty_pair), Err(ProcessorError::InvalidDelta) ) );
+    }
+}
@@ -0,0 +1,552 @@
+use std::{error::Error, fs, io::Write, path::{Path, PathBuf}, collections::{BTreeSet, HashSet}, convert::TryFrom};
+use sha2::{Digest, Sha256};
+use super::processor::*;
+use super::input_file::*;
+use super::output_file::*;
+
+// Magic value at the beginning of version manifests
+const MANIFEST_MAGIC: &[u8; 8] = b"HDSTMANI";
+
+// Directories of the store
+const CHUNKS_DIR: &str = "chunks";
+const VERSIONS_DIR: &str = "versions";
+const LOCK_FILE: &str = "lock";
+
+// Path of a file in chunks directory and hash parsed from its name
+type ChunkFile = (PathBuf, Option<[u8; HASH_SIZE]>);
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum StoreError {
+    InvalidVersion(String),
+    UnknownVersion(String),
+    VersionExists(String),
+    InvalidManifest(String),
+    MissingChunk(String),
+    CorruptedChunk(String),
+    DigestMismatch(String),
+    Locked
+}
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreError::InvalidVersion(version) => write!(f, "Invalid version name: {}", version),
+            StoreError::UnknownVersion(version) => write!(f, "Version is not stored: {}", version),
+            StoreError::VersionExists(version) => write!(f, "Version is already stored: {}", version),
+            StoreError::InvalidManifest(version) => write!(f, "Manifest of version is malformed: {}", version),
+            StoreError::MissingChunk(chunk) => write!(f, "Chunk is missing in store: {}", chunk),
+            StoreError::CorruptedChunk(chunk) => write!(f, "Chunk is corrupted: {}", chunk),
+            StoreError::DigestMismatch(version) => write!(f, "Digest of rebuilt version does not match: {}", version),
+            StoreError::Locked => write!(f, "Store is used by another command")
+        }
+    }
+}
+impl Error for StoreError {}
+
+
+// List of chunks of a stored file
+// manifest format:
+// magic, u32 chunk size, u64 file length, file digest, chunk hashes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub chunk_size: usize,
+    pub len: u64,
+    pub digest: [u8; HASH_SIZE],
+    pub chunks: Vec<[u8; HASH_SIZE]>
+}
+
+impl Manifest {
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = Vec::with_capacity(MANIFEST_MAGIC.len() + 12 + HASH_SIZE * (self.chunks.len() + 1));
+        data.extend_from_slice(MANIFEST_MAGIC);
+        data.extend_from_slice(&u32::try_from(self.chunk_size)?.to_le_bytes());
+        data.extend_from_slice(&self.len.to_le_bytes());
+        data.extend_from_slice(&self.digest);
+        for hash in &self.chunks {
+            data.extend_from_slice(hash);
+        }
+        Ok(data)
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data = data.strip_prefix(MANIFEST_MAGIC)?;
+        if data.len() < 12 + HASH_SIZE || !(data.len() - 12 - HASH_SIZE).is_multiple_of(HASH_SIZE) {
+            return None
+        }
+        let chunk_size = u32::from_le_bytes(data[0..4].try_into().ok()?) as usize;
+        let len = u64::from_le_bytes(data[4..12].try_into().ok()?);
+        let digest = data[12..12 + HASH_SIZE].try_into().ok()?;
+        let chunks: Vec<[u8; HASH_SIZE]> = data[12 + HASH_SIZE..].chunks(HASH_SIZE).map(|hash| hash.try_into().unwrap()).collect();
+
+        // only last chunk can be shorter than chunk size
+        let max_len = chunks.len() as u64 * chunk_size as u64;
+        if chunk_size == 0 || len > max_len || (!chunks.is_empty() && len <= max_len - chunk_size as u64) {
+            return None
+        }
+        Some(Self { chunk_size, len, digest, chunks })
+    }
+
+}
+
+// Result of adding a file to the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedVersion {
+    pub version: String,
+    pub chunks: usize,
+    pub new_chunks: usize
+}
+
+// Result of removing unreferenced chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectedGarbage {
+    pub removed_chunks: usize,
+    pub removed_bytes: u64
+}
+
+// Problems found by integrity check, chunks are identified by hex hash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub versions: usize,
+    pub chunks: usize,
+    pub invalid_manifests: Vec<String>,
+    pub missing_chunks: BTreeSet<String>,
+    pub corrupted_chunks: BTreeSet<String>
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.invalid_manifests.is_empty() && self.missing_chunks.is_empty() && self.corrupted_chunks.is_empty()
+    }
+}
+
+
+// Store keeping files as lists of chunks, every unique chunk is stored once under its hash
+// store layout:
+// chunks/<first 2 hex digits of hash>/<hex hash> - chunk data
+// versions/<version> - manifest
+// lock - locked by commands adding chunks and exclusively by garbage collection
+pub struct ChunkStore {
+    root: PathBuf
+}
+
+// Lock of the store is released when lock file is closed, also when command is killed
+struct StoreLock {
+    _file: fs::File
+}
+
+impl ChunkStore {
+
+    // Opens store directory, it is created if it does not exist
+    pub fn open(root: &Path) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(root.join(CHUNKS_DIR))?;
+        fs::create_dir_all(root.join(VERSIONS_DIR))?;
+        Ok(Self { root: root.to_path_buf() })
+    }
+
+    // Stores file as a new version, version is named by hex digest of the file if name was not given
+    pub fn add_file(&self, file_path: &Path, version: Option<&str>, chunk_size: usize) -> Result<AddedVersion, Box<dyn Error>> {
+        // chunks which are not referenced by manifest yet must not be removed by garbage collection
+        let _lock = self.lock(false)?;
+        let mut input_file = InputFile::new(file_path, chunk_size)?;
+        let mut hasher = Sha256::new();
+        let mut chunks = Vec::new();
+        let mut new_chunks = 0;
+        let mut new_chunk_dirs = HashSet::new();
+        let mut len = 0;
+
+        loop {
+            let chunk = input_file.get_next_data();
+            if chunk.is_empty() { break } // reached end of file
+            hasher.update(chunk);
+            len += chunk.len() as u64;
+
+            let hash = calculate_chunk_hash(chunk);
+            if self.write_chunk(&hash, chunk)? {
+                new_chunks += 1;
+                new_chunk_dirs.extend(self.chunk_path(&hash).parent().map(Path::to_path_buf));
+            }
+            chunks.push(hash);
+        }
+
+        // renames of new chunks and their directories are made durable before manifest referencing them is written
+        for chunk_dir in &new_chunk_dirs {
+            sync_dir(chunk_dir);
+        }
+        if !new_chunk_dirs.is_empty() {
+            sync_dir(&self.root.join(CHUNKS_DIR));
+        }
+
+        let digest: [u8; HASH_SIZE] = hasher.finalize().into();
+        let manifest = Manifest { chunk_size, len, digest, chunks };
+        let version = match version {
+            Some(version) => version.to_owned(),
+            None => to_hex(&digest)
+        };
+
+        let manifest_path = self.manifest_path(&version)?;
+        if manifest_path.exists() {
+            match self.read_manifest(&version) {
+                Ok(stored) if version == to_hex(&digest) && stored.digest == digest => {}
+                _ => return Err(Box::new(StoreError::VersionExists(version)))
+            }
+        }
+        let mut output_file = OutputFile::new(&manifest_path)?;
+        write_data(&mut output_file, &manifest.to_bytes()?)?;
+        output_file.finish()?;
+
+        Ok(AddedVersion { version, chunks: manifest.chunks.len(), new_chunks })
+    }
+
+    // Rebuilds stored version from its chunks
+    pub fn get_version<S: ProcessorDataOutput>(&self, version: &str, output_file: &mut S) -> Result<(), Box<dyn Error>> {
+        let manifest = self.read_manifest(version)?;
+        let mut hasher = Sha256::new();
+        let mut len = 0;
+
+        for hash in &manifest.chunks {
+            let chunk = self.read_chunk(hash)?;
+            hasher.update(&chunk);
+            len += chunk.len() as u64;
+            write_data(output_file, &chunk)?;
+        }
+
+        let digest: [u8; HASH_SIZE] = hasher.finalize().into();
+        if len != manifest.len || digest != manifest.digest {
+            return Err(Box::new(StoreError::DigestMismatch(version.to_owned())))
+        }
+        Ok(())
+    }
+
+    // Removes manifest of version, its chunks are removed by garbage collection
+    pub fn remove_version(&self, version: &str) -> Result<(), Box<dyn Error>> {
+        match fs::remove_file(self.manifest_path(version)?) {
+            Ok(()) => Ok(()),
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => Err(Box::new(StoreError::UnknownVersion(version.to_owned()))),
+            Err(x) => Err(Box::new(x))
+        }
+    }
+
+    // Names of all stored versions
+    pub fn versions(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(self.root.join(VERSIONS_DIR))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            // temporary files of manifests being written start with a dot
+            if !name.starts_with('.') {
+                versions.push(name);
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    // Mark and sweep: chunks not referenced by any manifest are removed
+    // nothing is removed if any manifest cannot be read, its chunks would be lost
+    // store is locked, so versions cannot be added during garbage collection
+    pub fn collect_garbage(&self) -> Result<CollectedGarbage, Box<dyn Error>> {
+        let _lock = self.lock(true)?;
+        let mut referenced = HashSet::new();
+        for version in self.versions()? {
+            referenced.extend(self.read_manifest(&version)?.chunks);
+        }
+
+        let mut removed_chunks = 0;
+        let mut removed_bytes = 0;
+        for (chunk_path, hash) in self.chunk_files()? {
+            if hash.is_some_and(|hash| referenced.contains(&hash)) {
+                continue
+            }
+            // temporary files left by interrupted commands are removed too
+            removed_bytes += fs::metadata(&chunk_path)?.len();
+            fs::remove_file(&chunk_path)?;
+            if hash.is_some() {
+                removed_chunks += 1;
+            }
+        }
+
+        Ok(CollectedGarbage { removed_chunks, removed_bytes })
+    }
+
+    // Rehashes every stored chunk and checks that all chunks referenced by manifests are stored
+    pub fn check(&self) -> Result<CheckReport, Box<dyn Error>> {
+        let mut report = CheckReport::default();
+
+        let mut stored = HashSet::new();
+        for (chunk_path, hash) in self.chunk_files()? {
+            let hash = match hash {
+                Some(hash) => hash,
+                None => continue // temporary file
+            };
+            report.chunks += 1;
+            if calculate_chunk_hash(&fs::read(&chunk_path)?) == hash {
+                stored.insert(hash);
+            } else {
+                report.corrupted_chunks.insert(to_hex(&hash));
+            }
+        }
+
+        for version in self.versions()? {
+            report.versions += 1;
+            let manifest = match self.read_manifest(&version) {
+                Ok(manifest) => manifest,
+                Err(x) if x.is::<StoreError>() => {
+                    report.invalid_manifests.push(version);
+                    continue
+                }
+                Err(x) => return Err(x)
+            };
+            for hash in manifest.chunks {
+                let name = to_hex(&hash);
+                if !stored.contains(&hash) && !report.corrupted_chunks.contains(&name) {
+                    report.missing_chunks.insert(name);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub fn read_manifest(&self, version: &str) -> Result<Manifest, Box<dyn Error>> {
+        let data = match fs::read(self.manifest_path(version)?) {
+            Ok(data) => data,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Err(Box::new(StoreError::UnknownVersion(version.to_owned()))),
+            Err(x) => return Err(Box::new(x))
+        };
+        match Manifest::from_bytes(&data) {
+            Some(manifest) => Ok(manifest),
+            None => Err(Box::new(StoreError::InvalidManifest(version.to_owned())))
+        }
+    }
+
+    // Reads chunk and checks its hash
+    pub fn read_chunk(&self, hash: &[u8; HASH_SIZE]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let chunk = match fs::read(self.chunk_path(hash)) {
+            Ok(chunk) => chunk,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Err(Box::new(StoreError::MissingChunk(to_hex(hash)))),
+            Err(x) => return Err(Box::new(x))
+        };
+        if calculate_chunk_hash(&chunk) != *hash {
+            return Err(Box::new(StoreError::CorruptedChunk(to_hex(hash))))
+        }
+        Ok(chunk)
+    }
+
+    // Returns false if chunk was already stored
+    // chunk data is synced before rename, so chunk referenced by a manifest is never lost or empty after a crash
+    fn write_chunk(&self, hash: &[u8; HASH_SIZE], chunk: &[u8]) -> Result<bool, Box<dyn Error>> {
+        let chunk_path = self.chunk_path(hash);
+        if chunk_path.exists() {
+            return Ok(false)
+        }
+        if let Some(parent) = chunk_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // chunk is renamed into place, so store never contains partially written chunk
+        let temp_path = chunk_path.with_extension(format!("{}.tmp", std::process::id()));
+        let result = fs::File::create(&temp_path)
+            .and_then(|mut file| file.write_all(chunk).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&temp_path, &chunk_path));
+        if let Err(x) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(Box::new(x))
+        }
+        Ok(true)
+    }
+
+    // All files in chunks directory with hashes parsed from their names, None for other files
+    fn chunk_files(&self) -> Result<Vec<ChunkFile>, Box<dyn Error>> {
+        let mut files = Vec::new();
+        for dir in fs::read_dir(self.root.join(CHUNKS_DIR))? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue
+            }
+            for entry in fs::read_dir(dir.path())? {
+                let entry = entry?;
+                let hash = from_hex(&entry.file_name().to_string_lossy()).and_then(|hash| <[u8; HASH_SIZE]>::try_from(hash).ok());
+                files.push((entry.path(), hash));
+            }
+        }
+        Ok(files)
+    }
+
+    // Shared lock allows concurrent adding of versions, exclusive lock is held by single command
+    // locking is supported on unix only
+    fn lock(&self, exclusive: bool) -> Result<StoreLock, Box<dyn Error>> {
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(self.root.join(LOCK_FILE))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+            if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+                let error = std::io::Error::last_os_error();
+                if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                    return Err(Box::new(StoreError::Locked))
+                }
+                return Err(Box::new(error))
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = exclusive;
+        Ok(StoreLock { _file: file })
+    }
+
+    fn chunk_path(&self, hash: &[u8; HASH_SIZE]) -> PathBuf {
+        let name = to_hex(hash);
+        self.root.join(CHUNKS_DIR).join(&name[..2]).join(name)
+    }
+
+    fn manifest_path(&self, version: &str) -> Result<PathBuf, StoreError> {
+        // version names are file names in versions directory
+        if version.is_empty() || version.starts_with('.') || version.contains(['/', '\\']) {
+            return Err(StoreError::InvalidVersion(version.to_owned()))
+        }
+        Ok(self.root.join(VERSIONS_DIR).join(version))
+    }
+
+}
+
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+fn write_data<S: ProcessorDataOutput>(output_file: &mut S, data: &[u8]) -> Result<(), ProcessorError> {
+    if output_file.write_data(data) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+}
+
+
+// Chunk store tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    #[test]
+    fn test_store_add_get() {
+        // chunk store test
+        // scenario: two versions share chunks, which are stored once, both versions are rebuilt
+
+        let dir = TestDir::new("store-add-get");
+        let store = ChunkStore::open(&dir.path.join("store")).unwrap();
+        let v1 = dir.write("v1", &[1,1,1,1,2,2,2,2,3,3]);
+        let v2 = dir.write("v2", &[1,1,1,1,4,4,4,4,2,2,2,2,3,3]);
+
+        let added = store.add_file(&v1, Some("v1"), 4).unwrap();
+        assert_eq!( added, AddedVersion { version: "v1".to_owned(), chunks: 3, new_chunks: 3 } );
+        let added = store.add_file(&v2, None, 4).unwrap();
+        assert_eq!( added.version, to_hex(&calculate_chunk_hash(&[1,1,1,1,4,4,4,4,2,2,2,2,3,3])) );
+        assert_eq!( (added.chunks, added.new_chunks), (4, 1) );
+
+        let mut output = Vec::new();
+        assert!( store.get_version("v1", &mut output).is_ok() );
+        assert_eq!( output, [1,1,1,1,2,2,2,2,3,3] );
+        let mut output = Vec::new();
+        assert!( store.get_version(&added.version, &mut output).is_ok() );
+        assert_eq!( output, [1,1,1,1,4,4,4,4,2,2,2,2,3,3] );
+
+        // same content can be added again under its digest, name cannot be reused
+        assert_eq!( store.add_file(&v2, None, 4).unwrap().new_chunks, 0 );
+        assert!( store.add_file(&v2, Some("v1"), 4).is_err() );
+    }
+
+    #[test]
+    fn test_store_invalid() {
+        // chunk store test
+        // scenario: unknown versions, invalid names and corrupted chunks are reported
+
+        let dir = TestDir::new("store-invalid");
+        let store = ChunkStore::open(&dir.path.join("store")).unwrap();
+        let v1 = dir.write("v1", &[1,1,1,1,2,2]);
+        store.add_file(&v1, Some("v1"), 4).unwrap();
+
+        let mut output = Vec::new();
+        assert!( store.get_version("v2", &mut output).is_err() );
+        assert!( store.get_version("../v1", &mut output).is_err() );
+        assert!( store.add_file(&v1, Some(".."), 4).is_err() );
+
+        fs::write(store.chunk_path(&calculate_chunk_hash(&[2,2])), [2,3]).unwrap();
+        let error = store.get_version("v1", &mut output).unwrap_err();
+        assert!( matches!( error.downcast_ref::<StoreError>(), Some(StoreError::CorruptedChunk(_)) ) );
+    }
+
+    #[test]
+    fn test_store_gc() {
+        // chunk store test
+        // scenario: chunks of removed version which are not shared with other versions are removed
+
+        let dir = TestDir::new("store-gc");
+        let store = ChunkStore::open(&dir.path.join("store")).unwrap();
+        store.add_file(&dir.write("v1", &[1,1,1,1,2,2,2,2,3,3]), Some("v1"), 4).unwrap();
+        store.add_file(&dir.write("v2", &[1,1,1,1,4,4]), Some("v2"), 4).unwrap();
+
+        assert_eq!( store.collect_garbage().unwrap(), CollectedGarbage { removed_chunks: 0, removed_bytes: 0 } );
+        assert!( store.remove_version("v1").is_ok() );
+        assert!( store.remove_version("v1").is_err() );
+        assert_eq!( store.versions().unwrap(), ["v2"] );
+        assert_eq!( store.collect_garbage().unwrap(), CollectedGarbage { removed_chunks: 2, removed_bytes: 6 } );
+
+        let mut output = Vec::new();
+        assert!( store.get_version("v2", &mut output).is_ok() );
+        assert_eq!( output, [1,1,1,1,4,4] );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_store_lock() {
+        // chunk store test
+        // scenario: garbage collection is refused while version is added and versions cannot be added during garbage collection
+
+        let dir = TestDir::new("store-lock");
+        let store = ChunkStore::open(&dir.path.join("store")).unwrap();
+        let v1 = dir.write("v1", &[1,1,1,1,2,2]);
+
+        let lock = store.lock(false).unwrap();
+        assert!( store.add_file(&v1, Some("v1"), 4).is_ok() );
+        let error = store.collect_garbage().unwrap_err();
+        assert!( matches!( error.downcast_ref::<StoreError>(), Some(StoreError::Locked) ) );
+        drop(lock);
+
+        let lock = store.lock(true).unwrap();
+        let error = store.add_file(&v1, Some("v2"), 4).unwrap_err();
+        assert!( matches!( error.downcast_ref::<StoreError>(), Some(StoreError::Locked) ) );
+        drop(lock);
+        assert!( store.collect_garbage().is_ok() );
+    }
+
+    #[test]
+    fn test_store_check() {
+        // chunk store test
+        // scenario: corrupted and missing chunks and malformed manifests are reported
+
+        let dir = TestDir::new("store-check");
+        let store = ChunkStore::open(&dir.path.join("store")).unwrap();
+        store.add_file(&dir.write("v1", &[1,1,1,1,2,2,2,2,3,3]), Some("v1"), 4).unwrap();
+        let report = store.check().unwrap();
+        assert!( report.is_ok() );
+        assert_eq!( (report.versions, report.chunks), (1, 3) );
+
+        fs::write(store.chunk_path(&calculate_chunk_hash(&[2,2,2,2])), [2,2,2,3]).unwrap();
+        fs::remove_file(store.chunk_path(&calculate_chunk_hash(&[3,3]))).unwrap();
+        fs::write(dir.path.join("store").join(VERSIONS_DIR).join("v2"), b"invalid").unwrap();
+
+        let report = store.check().unwrap();
+        assert!( !report.is_ok() );
+        assert_eq!( report.invalid_manifests, ["v2"] );
+        assert_eq!( report.corrupted_chunks.into_iter().collect::<Vec<_>>(), [to_hex(&calculate_chunk_hash(&[2,2,2,2]))] );
+        assert_eq!( report.missing_chunks.into_iter().collect::<Vec<_>>(), [to_hex(&calculate_chunk_hash(&[3,3]))] );
+
+        // garbage collection does not remove chunks when manifests cannot be read
+        assert!( store.collect_garbage().is_err() );
+    }
+}
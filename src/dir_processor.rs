@@ -0,0 +1,668 @@
+use std::{error::Error, fs, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, collections::{BTreeMap, BTreeSet, HashMap}, convert::TryFrom};
+use super::processor::*;
+use super::input_file::*;
+use super::output_file::*;
+use super::mem_data::*;
+
+// Magic values at the beginning of directory signature and directory delta files
+const SIGNATURE_DIR_MAGIC: &[u8; 8] = b"HDSIGDIR";
+const DELTA_DIR_MAGIC: &[u8; 8] = b"HDDELDIR";
+
+// tags for entries of directory delta file
+const ENTRY_ADDED: u8 = 0;
+const ENTRY_REMOVED: u8 = 1;
+const ENTRY_MODIFIED: u8 = 2;
+const ENTRY_COPIED: u8 = 3;
+
+// tags for records of copied file entry
+const RECORD_COPY_CHUNK: u8 = 0;
+const RECORD_LITERAL: u8 = 1;
+
+// Size of buffer used when copying whole files into or out of delta file
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+// Longest path stored in signature and delta files, limits memory taken by malformed files
+const MAX_PATH_SIZE: usize = 4096;
+
+// Location of a chunk in old directory: file path and chunk index
+type ChunkIndex<'a> = HashMap<[u8; HASH_SIZE], (&'a str, u64)>;
+
+// Source paths and records of copied file entry
+type CopiedFile<'a> = (Vec<&'a str>, Vec<u8>);
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum DirProcessorError {
+    InvalidArchive,
+    InvalidPath(String),
+    FileChanged(String),
+    OverlappingDirs,
+    OutputNotEmpty
+}
+impl std::fmt::Display for DirProcessorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DirProcessorError::InvalidArchive => write!(f, "Directory signature or delta file is malformed"),
+            DirProcessorError::InvalidPath(path) => write!(f, "Invalid path in directory: {}", path),
+            DirProcessorError::FileChanged(path) => write!(f, "File changed while it was processed: {}", path),
+            DirProcessorError::OverlappingDirs => write!(f, "Output directory cannot be inside of old directory or contain it"),
+            DirProcessorError::OutputNotEmpty => write!(f, "Output directory has to be empty")
+        }
+    }
+}
+impl Error for DirProcessorError {}
+
+
+// Signature-dir command logic, returns skipped entries of input directory
+// directory signature file format:
+// magic, u32 chunk size, then for every file: u32 path length, path, u64 signature length, signature
+pub fn process_signature_dir<S>(input_dir: &Path, chunk_size: usize, output_file: &mut S) -> Result<Vec<String>, Box<dyn Error>>
+    where S: ProcessorDataOutput
+{
+    write_header(output_file, SIGNATURE_DIR_MAGIC, chunk_size)?;
+
+    let listing = list_files(input_dir)?;
+    for path in &listing.files {
+        let signature = file_signature(&to_file_path(input_dir, path)?, chunk_size)?;
+        write_path(output_file, path)?;
+        write_block(output_file, &signature)?;
+    }
+
+    Ok(listing.skipped)
+}
+
+// Delta-dir command logic
+// directory delta file format:
+// magic, u32 chunk size, then for every added, removed or modified file: entry tag, u32 path length, path
+// and for added and modified files: u64 data length, data (whole file or its delta respectively)
+// new files which share chunks with old files (renamed, moved or copied) are stored as copied file entries:
+// u32 number of source files, source paths, u64 records length, records
+// every record is either copy of a chunk: record tag, u32 source index, u64 chunk index
+// or a literal: record tag, chunk data
+// skipped entries of input directory are returned, they are missing in patched directory
+pub fn process_delta_dir<R, S>(signature_file: &mut R, input_dir: &Path, output_file: &mut S) -> Result<Vec<String>, Box<dyn Error>>
+    where R: Read, S: ProcessorDataOutput
+{
+    let chunk_size = read_header(signature_file, SIGNATURE_DIR_MAGIC)?;
+    let mut signatures = BTreeMap::new();
+    while let Some(path) = read_path(signature_file)? {
+        let signature = read_block(signature_file)?;
+        signatures.insert(path, signature);
+    }
+
+    write_header(output_file, DELTA_DIR_MAGIC, chunk_size)?;
+
+    let DirListing { files, skipped } = list_files(input_dir)?;
+    let mut chunk_index = None;
+    for path in signatures.keys().filter(|path| !files.contains(*path)) {
+        write_data(output_file, &[ENTRY_REMOVED])?;
+        write_path(output_file, path)?;
+    }
+
+    for path in &files {
+        let file_path = to_file_path(input_dir, path)?;
+        match signatures.get(path) {
+            Some(old_signature) => {
+                // unchanged files are skipped
+                if file_signature(&file_path, chunk_size)? == *old_signature {
+                    continue
+                }
+
+                let mut input_file = InputFile::new(&file_path, chunk_size)?;
+                let mut signature_input = MemInput::new(old_signature, HASH_SIZE);
+                let mut delta = Vec::new();
+                Processor::new(&mut input_file, &mut delta).process_delta(&mut signature_input)?;
+
+                write_data(output_file, &[ENTRY_MODIFIED])?;
+                write_path(output_file, path)?;
+                write_block(output_file, &delta)?;
+            }
+            None => {
+                // new file may contain chunks of old files, index of all old chunks is created only once
+                let chunk_index = chunk_index.get_or_insert_with(|| create_chunk_index(&signatures));
+                match encode_copies(&file_path, chunk_size, chunk_index)? {
+                    Some((sources, records)) => {
+                        write_data(output_file, &[ENTRY_COPIED])?;
+                        write_path(output_file, path)?;
+                        write_data(output_file, &u32::try_from(sources.len())?.to_le_bytes())?;
+                        for source in sources {
+                            write_path(output_file, source)?;
+                        }
+                        write_block(output_file, &records)?;
+                    }
+                    None => {
+                        write_data(output_file, &[ENTRY_ADDED])?;
+                        write_path(output_file, path)?;
+                        write_file(output_file, &file_path, path)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(skipped)
+}
+
+// Patch-dir command logic, output directory receives copy of old directory with delta applied
+// output directory has to be empty, so no stale files or links leading out of it are left there
+// skipped entries of old directory are returned, they are not copied to output directory
+pub fn process_patch_dir<R>(old_dir: &Path, delta_file: &mut R, output_dir: &Path) -> Result<Vec<String>, Box<dyn Error>>
+    where R: Read
+{
+    let chunk_size = read_header(delta_file, DELTA_DIR_MAGIC)?;
+
+    // output is written to old directory while old files are read, so directories cannot overlap
+    let canonical_old_dir = fs::canonicalize(old_dir)?;
+    let canonical_output_dir = canonicalize_new_dir(output_dir)?;
+    if canonical_output_dir.starts_with(&canonical_old_dir) || canonical_old_dir.starts_with(&canonical_output_dir) {
+        return Err(Box::new(DirProcessorError::OverlappingDirs))
+    }
+    fs::create_dir_all(output_dir)?;
+    if fs::read_dir(output_dir)?.next().is_some() {
+        return Err(Box::new(DirProcessorError::OutputNotEmpty))
+    }
+
+    // start with a copy of old directory, files not present in delta are unchanged
+    let listing = list_files(old_dir)?;
+    for path in &listing.files {
+        let output_path = to_file_path(output_dir, path)?;
+        create_parent_dir(&output_path)?;
+        fs::copy(to_file_path(old_dir, path)?, output_path)?;
+    }
+
+    let mut tag = [0u8; 1];
+    while read_exact_or_eof(delta_file, &mut tag)? {
+        let path = read_path(delta_file)?.ok_or(DirProcessorError::InvalidArchive)?;
+        let output_path = to_file_path(output_dir, &path)?;
+
+        match tag[0] {
+            ENTRY_ADDED => {
+                create_parent_dir(&output_path)?;
+                let mut output_file = OutputFile::new(&output_path)?;
+                let len = read_u64(delta_file)?;
+                copy_data(delta_file, len, &mut output_file)?;
+                output_file.finish()?;
+            }
+            ENTRY_REMOVED => {
+                fs::remove_file(output_path)?;
+            }
+            ENTRY_MODIFIED => {
+                let delta = read_block(delta_file)?;
+                let mut old_file = InputFile::new(to_file_path(old_dir, &path)?, chunk_size)?;
+                let mut output_file = OutputFile::new(&output_path)?;
+                let mut delta_input = MemInput::new(&delta, chunk_size);
+                Processor::new(&mut old_file, &mut output_file).process_patch(&mut delta_input, chunk_size)?;
+                output_file.finish()?;
+            }
+            ENTRY_COPIED => {
+                let mut sources = Vec::new();
+                for _ in 0..read_u32(delta_file)? {
+                    let source = read_path(delta_file)?.ok_or(DirProcessorError::InvalidArchive)?;
+                    sources.push(fs::File::open(to_file_path(old_dir, &source)?)?);
+                }
+                let records = read_block(delta_file)?;
+                create_parent_dir(&output_path)?;
+                let mut output_file = OutputFile::new(&output_path)?;
+                apply_copies(&records, &mut sources, chunk_size, &mut output_file)?;
+                output_file.finish()?;
+            }
+            _ => return Err(Box::new(DirProcessorError::InvalidArchive))
+        }
+    }
+
+    Ok(listing.skipped)
+}
+
+
+// Signature of a single file in memory
+pub fn file_signature(file_path: &Path, chunk_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut input_file = InputFile::new(file_path, chunk_size)?;
+    let mut signature = Vec::new();
+    Processor::new(&mut input_file, &mut signature).process_signature()?;
+    Ok(signature)
+}
+
+// Maps hashes of all chunks from old directory signatures to their first location
+fn create_chunk_index(signatures: &BTreeMap<String, Vec<u8>>) -> ChunkIndex<'_> {
+    let mut chunk_index = HashMap::new();
+    for (path, signature) in signatures {
+        for (index, hash) in signature.chunks_exact(HASH_SIZE).enumerate() {
+            if let Ok(hash) = <[u8; HASH_SIZE]>::try_from(hash) {
+                chunk_index.entry(hash).or_insert((path.as_str(), index as u64));
+            }
+        }
+    }
+    chunk_index
+}
+
+// Encodes file as copies of old chunks and literals, returns source paths and records
+// None is returned if none of the chunks exist in old directory
+fn encode_copies<'a>(file_path: &Path, chunk_size: usize, chunk_index: &ChunkIndex<'a>) -> Result<Option<CopiedFile<'a>>, Box<dyn Error>> {
+    let mut input_file = InputFile::new(file_path, chunk_size)?;
+    let mut sources = Vec::new();
+    let mut records = Vec::new();
+
+    loop {
+        let chunk = input_file.get_next_data();
+        if chunk.is_empty() { break } // reached end of file
+
+        match chunk_index.get(&calculate_signature_hash(chunk)) {
+            Some((source, index)) => {
+                let source_index = match sources.iter().position(|path| path == source) {
+                    Some(source_index) => source_index,
+                    None => {
+                        sources.push(*source);
+                        sources.len() - 1
+                    }
+                };
+                records.push(RECORD_COPY_CHUNK);
+                records.extend_from_slice(&(source_index as u32).to_le_bytes());
+                records.extend_from_slice(&index.to_le_bytes());
+            }
+            None => {
+                records.push(RECORD_LITERAL);
+                records.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    if sources.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some((sources, records)))
+    }
+}
+
+// Rebuilds copied file from its records
+fn apply_copies<S: ProcessorDataOutput>(records: &[u8], sources: &mut [fs::File], chunk_size: usize, output_file: &mut S) -> Result<(), Box<dyn Error>> {
+    let mut chunk = vec![0; chunk_size];
+    let mut records = records;
+
+    while let Some((&tag, rest)) = records.split_first() {
+        match tag {
+            RECORD_COPY_CHUNK if rest.len() >= 12 => {
+                let source_index = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                let mut index = [0u8; 8];
+                index.copy_from_slice(&rest[4..12]);
+                let source = sources.get_mut(source_index).ok_or(DirProcessorError::InvalidArchive)?;
+                let offset = u64::from_le_bytes(index).checked_mul(chunk_size as u64).ok_or(DirProcessorError::InvalidArchive)?;
+                source.seek(SeekFrom::Start(offset))?;
+
+                // last chunk of source file can be shorter than chunk size
+                let mut size = 0;
+                loop {
+                    match source.read(&mut chunk[size..])? {
+                        0 => break,
+                        n => size += n
+                    }
+                    if size == chunk.len() { break }
+                }
+                if size == 0 {
+                    return Err(Box::new(DirProcessorError::InvalidArchive))
+                }
+                write_data(output_file, &chunk[..size])?;
+                records = &rest[12..];
+            }
+            RECORD_LITERAL if !rest.is_empty() => {
+                // only last chunk of the file can be shorter than chunk size
+                let size = std::cmp::min(chunk_size, rest.len());
+                write_data(output_file, &rest[..size])?;
+                records = &rest[size..];
+            }
+            _ => return Err(Box::new(DirProcessorError::InvalidArchive))
+        }
+    }
+
+    Ok(())
+}
+
+// Regular files in a directory tree and skipped entries: symbolic links, special files and empty directories
+// paths are relative and separated with '/', paths of skipped directories end with '/'
+struct DirListing {
+    files: BTreeSet<String>,
+    skipped: Vec<String>
+}
+
+// Lists directory tree, symbolic links are not followed
+fn list_files(dir: &Path) -> Result<DirListing, Box<dyn Error>> {
+    let mut listing = DirListing { files: BTreeSet::new(), skipped: Vec::new() };
+    let mut dirs = vec![(dir.to_path_buf(), String::new())];
+
+    while let Some((dir, prefix)) = dirs.pop() {
+        let mut empty = true;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            empty = false;
+            let name = entry.file_name().into_string()
+                .map_err(|name| DirProcessorError::InvalidPath(name.to_string_lossy().into_owned()))?;
+            let path = format!("{}{}", prefix, name);
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push((entry.path(), format!("{}/", path)));
+            } else if file_type.is_file() {
+                listing.files.insert(path);
+            } else {
+                listing.skipped.push(path);
+            }
+        }
+        if empty && !prefix.is_empty() {
+            listing.skipped.push(prefix);
+        }
+    }
+
+    listing.skipped.sort();
+    Ok(listing)
+}
+
+// Canonical path of directory which may not exist yet, its nearest existing ancestor is canonicalized
+fn canonicalize_new_dir(dir: &Path) -> std::io::Result<PathBuf> {
+    for ancestor in dir.ancestors() {
+        let existing = if ancestor.as_os_str().is_empty() { Path::new(".") } else { ancestor };
+        if existing.exists() {
+            // missing part of the path is appended as it is
+            let missing = dir.strip_prefix(ancestor).unwrap_or(dir);
+            return Ok(fs::canonicalize(existing)?.join(missing))
+        }
+    }
+    Ok(dir.to_path_buf())
+}
+
+// Converts relative path stored in signature or delta file to a path inside of a directory
+pub fn to_file_path(dir: &Path, path: &str) -> Result<PathBuf, DirProcessorError> {
+    let mut file_path = dir.to_path_buf();
+    for component in path.split('/') {
+        // do not allow paths leaving the directory
+        if component.is_empty() || component == "." || component == ".." || component.contains('\\') {
+            return Err(DirProcessorError::InvalidPath(path.to_owned()))
+        }
+        file_path.push(component);
+    }
+    Ok(file_path)
+}
+
+fn create_parent_dir(file_path: &Path) -> std::io::Result<()> {
+    match file_path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(())
+    }
+}
+
+
+fn write_data<S: ProcessorDataOutput>(output_file: &mut S, data: &[u8]) -> Result<(), ProcessorError> {
+    if output_file.write_data(data) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+}
+
+fn write_header<S: ProcessorDataOutput>(output_file: &mut S, magic: &[u8], chunk_size: usize) -> Result<(), Box<dyn Error>> {
+    let chunk_size = u32::try_from(chunk_size)?;
+    write_data(output_file, magic)?;
+    write_data(output_file, &chunk_size.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_path<S: ProcessorDataOutput>(output_file: &mut S, path: &str) -> Result<(), Box<dyn Error>> {
+    let len = u32::try_from(path.len())?;
+    write_data(output_file, &len.to_le_bytes())?;
+    write_data(output_file, path.as_bytes())?;
+    Ok(())
+}
+
+fn write_block<S: ProcessorDataOutput>(output_file: &mut S, data: &[u8]) -> Result<(), ProcessorError> {
+    write_data(output_file, &(data.len() as u64).to_le_bytes())?;
+    write_data(output_file, data)
+}
+
+// Writes length and content of a file, file is read in parts to limit memory usage
+fn write_file<S: ProcessorDataOutput>(output_file: &mut S, file_path: &Path, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::open(file_path)?;
+    let len = file.metadata()?.len();
+    write_data(output_file, &len.to_le_bytes())?;
+
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut len_to_write = len;
+    while len_to_write > 0 {
+        let size = std::cmp::min(len_to_write, buffer.len() as u64) as usize;
+        if file.read_exact(&mut buffer[..size]).is_err() {
+            return Err(Box::new(DirProcessorError::FileChanged(path.to_owned())))
+        }
+        write_data(output_file, &buffer[..size])?;
+        len_to_write -= size as u64;
+    }
+
+    Ok(())
+}
+
+
+// Returns false if reader is at its end, partially read buffer is an error
+fn read_exact_or_eof<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<bool, Box<dyn Error>> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(Box::new(DirProcessorError::InvalidArchive)),
+            Ok(n) => read += n,
+            Err(x) if x.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(x) => return Err(Box::new(x))
+        }
+    }
+    Ok(true)
+}
+
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+    if buffer.is_empty() || read_exact_or_eof(reader, buffer)? {
+        Ok(())
+    } else {
+        Err(Box::new(DirProcessorError::InvalidArchive))
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Box<dyn Error>> {
+    let mut buffer = [0u8; 4];
+    read_exact(reader, &mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Box<dyn Error>> {
+    let mut buffer = [0u8; 8];
+    read_exact(reader, &mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+// Returns chunk size stored in header
+fn read_header<R: Read>(reader: &mut R, magic: &[u8]) -> Result<usize, Box<dyn Error>> {
+    let mut buffer = vec![0u8; magic.len()];
+    read_exact(reader, &mut buffer)?;
+    let chunk_size = read_u32(reader)? as usize;
+    if buffer != magic || chunk_size == 0 {
+        return Err(Box::new(DirProcessorError::InvalidArchive))
+    }
+    Ok(chunk_size)
+}
+
+// Returns None if reader is at its end
+fn read_path<R: Read>(reader: &mut R) -> Result<Option<String>, Box<dyn Error>> {
+    let mut buffer = [0u8; 4];
+    if !read_exact_or_eof(reader, &mut buffer)? {
+        return Ok(None)
+    }
+    let len = u32::from_le_bytes(buffer) as usize;
+    if len > MAX_PATH_SIZE {
+        return Err(Box::new(DirProcessorError::InvalidArchive))
+    }
+    let mut path = vec![0u8; len];
+    read_exact(reader, &mut path)?;
+    match String::from_utf8(path) {
+        Ok(path) => Ok(Some(path)),
+        Err(_) => Err(Box::new(DirProcessorError::InvalidArchive))
+    }
+}
+
+fn read_block<R: Read>(reader: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let len = read_u64(reader)?;
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(Box::new(DirProcessorError::InvalidArchive))
+    }
+    Ok(data)
+}
+
+fn copy_data<R: Read, S: ProcessorDataOutput>(reader: &mut R, len: u64, output_file: &mut S) -> Result<(), Box<dyn Error>> {
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut len_to_copy = len;
+    while len_to_copy > 0 {
+        let size = std::cmp::min(len_to_copy, buffer.len() as u64) as usize;
+        read_exact(reader, &mut buffer[..size])?;
+        write_data(output_file, &buffer[..size])?;
+        len_to_copy -= size as u64;
+    }
+    Ok(())
+}
+
+
+// Directory processor tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    fn make_delta(old_dir: &TestDir, new_dir: &TestDir) -> Vec<u8> {
+        let mut signature = Vec::new();
+        assert!( process_signature_dir(&old_dir.path, 4, &mut signature).is_ok() );
+        let mut delta = Vec::new();
+        assert!( process_delta_dir(&mut signature.as_slice(), &new_dir.path, &mut delta).is_ok() );
+        delta
+    }
+
+    #[test]
+    fn test_dir_unchanged() {
+        // directory delta test
+        // scenario: directories are same, delta contains only header
+
+        let old_dir = TestDir::new("unchanged-old");
+        old_dir.write("a", &[1,2,3,4,5,6]);
+        old_dir.write("sub/b", &[1,2,3,4]);
+        let new_dir = TestDir::new("unchanged-new");
+        new_dir.write("a", &[1,2,3,4,5,6]);
+        new_dir.write("sub/b", &[1,2,3,4]);
+
+        assert_eq!( make_delta(&old_dir, &new_dir), [b'H',b'D',b'D',b'E',b'L',b'D',b'I',b'R',4,0,0,0] );
+    }
+
+    #[test]
+    fn test_dir_changes() {
+        // directory delta and patch test
+        // scenario: one file is modified, one removed, one added and one unchanged
+
+        let old_dir = TestDir::new("changes-old");
+        old_dir.write("same", &[9,9,9,9]);
+        old_dir.write("modified", &[1,2,3,4,5,6,7,8]);
+        old_dir.write("sub/removed", &[1,2,3,4]);
+        let new_dir = TestDir::new("changes-new");
+        new_dir.write("same", &[9,9,9,9]);
+        new_dir.write("modified", &[1,2,3,4,0,0,0,0,5,6,7,8]);
+        new_dir.write("sub/dir/added", &[5,6,7,8,9]);
+
+        let delta = make_delta(&old_dir, &new_dir);
+
+        let output_dir = TestDir::new("changes-output");
+        assert!( process_patch_dir(&old_dir.path, &mut delta.as_slice(), &output_dir.path).is_ok() );
+
+        assert_eq!( list_files(&output_dir.path).unwrap().files, list_files(&new_dir.path).unwrap().files );
+        assert_eq!( output_dir.read("same"), [9,9,9,9] );
+        assert_eq!( output_dir.read("modified"), [1,2,3,4,0,0,0,0,5,6,7,8] );
+        assert_eq!( output_dir.read("sub/dir/added"), [5,6,7,8,9] );
+    }
+
+    #[test]
+    fn test_dir_moved() {
+        // directory delta and patch test
+        // scenario: file is moved to other directory and renamed, another file is created from parts of old files
+
+        let old_dir = TestDir::new("moved-old");
+        old_dir.write("a/file", &[1,2,3,4,5,6,7,8,9,0,1,2,3,4,5]);
+        old_dir.write("b", &[7,7,7,7,8,8]);
+        let new_dir = TestDir::new("moved-new");
+        new_dir.write("c/renamed", &[1,2,3,4,5,6,7,8,9,0,1,2,3,4,5]);
+        new_dir.write("mixed", &[8,8,8,8,7,7,7,7,9,0,1,2,8,8]);
+
+        let delta = make_delta(&old_dir, &new_dir);
+        // no literal data is needed for renamed file, 4 chunks are copied
+        let renamed_entry = [ENTRY_COPIED, 9,0,0,0, b'c',b'/',b'r',b'e',b'n',b'a',b'm',b'e',b'd', 1,0,0,0, 6,0,0,0, b'a',b'/',b'f',b'i',b'l',b'e', 52,0,0,0,0,0,0,0];
+        assert!( delta.windows(renamed_entry.len()).any(|entry| entry == renamed_entry) );
+
+        let output_dir = TestDir::new("moved-output");
+        assert!( process_patch_dir(&old_dir.path, &mut delta.as_slice(), &output_dir.path).is_ok() );
+
+        assert_eq!( list_files(&output_dir.path).unwrap().files, list_files(&new_dir.path).unwrap().files );
+        assert_eq!( output_dir.read("c/renamed"), [1,2,3,4,5,6,7,8,9,0,1,2,3,4,5] );
+        assert_eq!( output_dir.read("mixed"), [8,8,8,8,7,7,7,7,9,0,1,2,8,8] );
+    }
+
+    #[test]
+    fn test_dir_invalid_path() {
+        // directory patch test
+        // scenario: delta file tries to write outside of output directory or contains too long path
+
+        let old_dir = TestDir::new("invalid-path-old");
+        let output_dir = TestDir::new("invalid-path-output");
+        let mut delta = Vec::from(&DELTA_DIR_MAGIC[..]);
+        delta.extend_from_slice(&[4,0,0,0, ENTRY_ADDED, 4,0,0,0, b'.',b'.',b'/',b'a', 1,0,0,0,0,0,0,0, 1]);
+
+        assert!( process_patch_dir(&old_dir.path, &mut delta.as_slice(), &output_dir.path).is_err() );
+
+        // path longer than limit is refused before it is read
+        let mut delta = Vec::from(&DELTA_DIR_MAGIC[..]);
+        delta.extend_from_slice(&[4,0,0,0, ENTRY_ADDED, 0xff,0xff,0xff,0xff]);
+        let result = process_patch_dir(&old_dir.path, &mut delta.as_slice(), &output_dir.path);
+        assert!( matches!( result.unwrap_err().downcast_ref::<DirProcessorError>(), Some(DirProcessorError::InvalidArchive) ) );
+    }
+
+    #[test]
+    fn test_dir_output() {
+        // directory patch test
+        // scenario: output directory overlapping old directory or not empty is refused, entries which are not regular files are reported
+
+        let old_dir = TestDir::new("output-old");
+        old_dir.write("a", &[1,2,3,4]);
+        fs::create_dir_all(old_dir.path.join("sub/empty")).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a", old_dir.path.join("link")).unwrap();
+        let delta = make_delta(&old_dir, &old_dir);
+
+        let result = process_patch_dir(&old_dir.path, &mut delta.as_slice(), &old_dir.path.join("sub/output"));
+        assert!( matches!( result.unwrap_err().downcast_ref::<DirProcessorError>(), Some(DirProcessorError::OverlappingDirs) ) );
+        assert!( !old_dir.path.join("sub/output").exists() );
+        let result = process_patch_dir(&old_dir.path.join("sub"), &mut delta.as_slice(), &old_dir.path);
+        assert!( matches!( result.unwrap_err().downcast_ref::<DirProcessorError>(), Some(DirProcessorError::OverlappingDirs) ) );
+
+        let output_dir = TestDir::new("output-output");
+        output_dir.write("stale", &[5]);
+        let result = process_patch_dir(&old_dir.path, &mut delta.as_slice(), &output_dir.path);
+        assert!( matches!( result.unwrap_err().downcast_ref::<DirProcessorError>(), Some(DirProcessorError::OutputNotEmpty) ) );
+
+        let output_path = output_dir.path.join("new");
+        let skipped = process_patch_dir(&old_dir.path, &mut delta.as_slice(), &output_path).unwrap();
+        let expected: &[&str] = if cfg!(unix) { &["link", "sub/empty/"] } else { &["sub/empty/"] };
+        assert_eq!( skipped, expected );
+        assert_eq!( fs::read(output_path.join("a")).unwrap(), [1,2,3,4] );
+    }
+
+    #[test]
+    fn test_dir_copy_invalid() {
+        // directory patch test
+        // scenario: copied chunk index overflowing offset in source file is refused
+
+        let old_dir = TestDir::new("copy-invalid-old");
+        old_dir.write("a", &[1,2,3,4]);
+        let output_dir = TestDir::new("copy-invalid-output");
+        let mut delta = Vec::from(&DELTA_DIR_MAGIC[..]);
+        delta.extend_from_slice(&[4,0,0,0, ENTRY_COPIED, 1,0,0,0, b'b', 1,0,0,0, 1,0,0,0, b'a', 13,0,0,0,0,0,0,0]);
+        delta.extend_from_slice(&[RECORD_COPY_CHUNK, 0,0,0,0]);
+        delta.extend_from_slice(&u64::MAX.to_le_bytes());
+
+        let result = process_patch_dir(&old_dir.path, &mut delta.as_slice(), &output_dir.path);
+        assert!( matches!( result.unwrap_err().downcast_ref::<DirProcessorError>(), Some(DirProcessorError::InvalidArchive) ) );
+        assert!( !output_dir.path.join("b").exists() );
+    }
+}
@@ -0,0 +1,383 @@
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::{Payload, stream::{DecryptorBE32, EncryptorBE32}}};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use super::processor::*;
+
+// Data is encrypted in segments, every segment is followed by authentication tag
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+// Key is derived with PBKDF2-HMAC-SHA256 from passphrase, iterations are stored in encrypted file, so they can be raised later
+// key file contains random data, so key is derived from it with single HMAC-SHA256 of salt, which is marked by 0 iterations
+pub const KEY_ITERATIONS: u32 = 600_000;
+const MAX_KEY_ITERATIONS: u32 = 10_000_000;
+const SALT_SIZE: usize = 16;
+
+// Nonce of STREAM construction without 4 bytes of counter and 1 byte of last segment flag
+const NONCE_PREFIX_SIZE: usize = 7;
+
+const ENVELOPE_SIZE: usize = 4 + SALT_SIZE + NONCE_PREFIX_SIZE;
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum EncryptionError {
+    Random(getrandom::Error),
+    FileWrite,
+    InvalidEnvelope,
+    DecryptionFailed
+}
+impl std::fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EncryptionError::Random(x) => write!(f, "Unable to generate random data: {}", x),
+            EncryptionError::FileWrite => write!(f, "Unable to write to output file"),
+            EncryptionError::InvalidEnvelope => write!(f, "Invalid encryption parameters"),
+            EncryptionError::DecryptionFailed => write!(f, "Decryption failed, wrong key or data was modified")
+        }
+    }
+}
+impl std::error::Error for EncryptionError {}
+
+
+// Secret from which encryption key is derived, with iterations used when data is encrypted
+// decryption uses iterations stored in encrypted file
+pub struct EncryptionKey {
+    secret: Vec<u8>,
+    iterations: u32
+}
+
+impl EncryptionKey {
+
+    pub fn from_passphrase(secret: Vec<u8>, iterations: u32) -> Self {
+        Self { secret, iterations: iterations.clamp(1, MAX_KEY_ITERATIONS) }
+    }
+
+    pub fn from_key_file(secret: Vec<u8>) -> Self {
+        Self { secret, iterations: 0 }
+    }
+
+    fn iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    fn is_passphrase(&self) -> bool {
+        self.iterations != 0
+    }
+
+    fn cipher(&self, salt: &[u8], iterations: u32) -> ChaCha20Poly1305 {
+        let key: [u8; 32] = if iterations == 0 {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+            mac.update(salt);
+            mac.finalize().into_bytes().into()
+        } else {
+            pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(&self.secret, salt, iterations)
+        };
+        ChaCha20Poly1305::new(&key.into())
+    }
+
+}
+
+
+// Output which encrypts all written data, data is passed unchanged without key
+// encrypted data format:
+// u32 key iterations, salt, nonce prefix, encrypted segments
+// segments are encrypted with STREAM construction, so removing or reordering them is detected
+// associated data, i.e. plain file header, is authenticated with every segment, so it cannot be changed either
+pub struct EncryptedOutput<S> {
+    output_file: S,
+    encryptor: Option<EncryptorBE32<ChaCha20Poly1305>>,
+    associated_data: Vec<u8>,
+    buffer: Vec<u8>
+}
+
+impl<S: ProcessorDataOutput> EncryptedOutput<S> {
+
+    pub fn new(mut output_file: S, key: Option<&EncryptionKey>, associated_data: &[u8]) -> Result<Self, EncryptionError> {
+        let encryptor = match key {
+            Some(key) => {
+                let mut salt = [0u8; SALT_SIZE];
+                let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+                getrandom::getrandom(&mut salt).map_err(EncryptionError::Random)?;
+                getrandom::getrandom(&mut nonce_prefix).map_err(EncryptionError::Random)?;
+
+                let mut envelope = Vec::with_capacity(ENVELOPE_SIZE);
+                envelope.extend_from_slice(&key.iterations().to_le_bytes());
+                envelope.extend_from_slice(&salt);
+                envelope.extend_from_slice(&nonce_prefix);
+                if !output_file.write_data(&envelope) {
+                    return Err(EncryptionError::FileWrite)
+                }
+                Some(EncryptorBE32::from_aead(key.cipher(&salt, key.iterations()), &nonce_prefix.into()))
+            }
+            None => None
+        };
+        Ok(Self { output_file, encryptor, associated_data: associated_data.to_vec(), buffer: Vec::new() })
+    }
+
+    // Encrypts remaining data as last segment
+    pub fn finish(mut self) -> Result<S, EncryptionError> {
+        if let Some(encryptor) = self.encryptor.take() {
+            let payload = Payload { msg: self.buffer.as_slice(), aad: &self.associated_data };
+            let segment = encryptor.encrypt_last(payload).map_err(|_| EncryptionError::FileWrite)?;
+            if !self.output_file.write_data(&segment) {
+                return Err(EncryptionError::FileWrite)
+            }
+        }
+        Ok(self.output_file)
+    }
+
+}
+
+impl<S: ProcessorDataOutput> ProcessorDataOutput for EncryptedOutput<S> {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        let encryptor = match &mut self.encryptor {
+            Some(encryptor) => encryptor,
+            None => return self.output_file.write_data(data)
+        };
+
+        // last segment is encrypted differently, so full segment is kept until more data is written
+        self.buffer.extend_from_slice(data);
+        let mut location = 0;
+        while self.buffer.len() - location > SEGMENT_SIZE {
+            match encryptor.encrypt_next(Payload { msg: &self.buffer[location..location + SEGMENT_SIZE], aad: &self.associated_data }) {
+                Ok(segment) if self.output_file.write_data(&segment) => location += SEGMENT_SIZE,
+                _ => return false
+            }
+        }
+        self.buffer.drain(..location);
+        true
+    }
+}
+
+
+// Input which decrypts data of encrypted input, data is passed unchanged without key
+// input is required to report remaining length, so last segment can be detected
+pub struct DecryptedInput<T> {
+    input_file: T,
+    decryptor: Option<DecryptorBE32<ChaCha20Poly1305>>,
+    associated_data: Vec<u8>,
+    encrypted: bool,
+    buffer: Vec<u8>,
+    location: usize,
+    chunk_size: usize,
+    last_read_size: usize,
+    error: Option<EncryptionError>
+}
+
+impl<T: ProcessorDataInput> DecryptedInput<T> {
+
+    pub fn new(mut input_file: T, key: Option<&EncryptionKey>, associated_data: &[u8]) -> Result<Self, EncryptionError> {
+        let decryptor = match key {
+            Some(key) => {
+                input_file.set_chunk_size(ENVELOPE_SIZE);
+                let envelope = input_file.get_next_data();
+                if envelope.len() != ENVELOPE_SIZE {
+                    return Err(EncryptionError::InvalidEnvelope)
+                }
+                let iterations = u32::from_le_bytes([envelope[0], envelope[1], envelope[2], envelope[3]]);
+                // key file and passphrase are not interchangeable
+                if (iterations == 0) == key.is_passphrase() || iterations > MAX_KEY_ITERATIONS {
+                    return Err(EncryptionError::InvalidEnvelope)
+                }
+                let (salt, nonce_prefix) = envelope[4..].split_at(SALT_SIZE);
+                Some(DecryptorBE32::from_aead(key.cipher(salt, iterations), nonce_prefix.into()))
+            }
+            None => None
+        };
+        let encrypted = decryptor.is_some();
+        Ok(Self { input_file, decryptor, associated_data: associated_data.to_vec(), encrypted, buffer: Vec::new(), location: 0, chunk_size: 0, last_read_size: 0, error: None })
+    }
+
+    // Reports decryption error, which is seen by processor as end of data
+    pub fn finish(self) -> Result<T, EncryptionError> {
+        match self.error {
+            Some(x) => Err(x),
+            None => Ok(self.input_file)
+        }
+    }
+
+    fn decrypt_next_segment(&mut self) {
+        let decryptor = match &mut self.decryptor {
+            Some(decryptor) => decryptor,
+            None => return
+        };
+
+        // last segment is the one which reaches end of input
+        let last = self.input_file.remaining_len() <= (SEGMENT_SIZE + TAG_SIZE) as u64;
+        self.input_file.set_chunk_size(SEGMENT_SIZE + TAG_SIZE);
+        let segment = self.input_file.get_next_data();
+        let payload = Payload { msg: segment, aad: &self.associated_data };
+        let result = if last {
+            self.decryptor.take().map(|decryptor| decryptor.decrypt_last(payload))
+        } else {
+            Some(decryptor.decrypt_next(payload))
+        };
+
+        match result {
+            Some(Ok(data)) => self.buffer.extend_from_slice(&data),
+            _ => {
+                self.decryptor = None;
+                self.error = Some(EncryptionError::DecryptionFailed);
+            }
+        }
+    }
+
+}
+
+impl<T: ProcessorDataInput> ProcessorDataInput for DecryptedInput<T> {
+    fn get_next_data(&mut self) -> &[u8] {
+        if !self.encrypted {
+            self.input_file.set_chunk_size(self.chunk_size);
+            return self.input_file.get_next_data()
+        }
+
+        // decrypted data which was already read is dropped from time to time
+        if self.location > SEGMENT_SIZE {
+            self.buffer.drain(..self.location);
+            self.location = 0;
+        }
+        while self.buffer.len() - self.location < self.chunk_size && self.decryptor.is_some() {
+            self.decrypt_next_segment();
+        }
+        if self.error.is_some() {
+            return &[]
+        }
+
+        let end = std::cmp::min(self.location + self.chunk_size, self.buffer.len());
+        let ret = &self.buffer[self.location..end];
+        self.last_read_size = ret.len();
+        self.location = end;
+        ret
+    }
+
+    fn move_back_last_read(&mut self) -> bool {
+        if !self.encrypted {
+            return self.input_file.move_back_last_read()
+        }
+        self.location -= self.last_read_size;
+        self.last_read_size = 0;
+        true
+    }
+
+    fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
+
+    fn total_len(&self) -> u64 {
+        self.input_file.total_len()
+    }
+
+    fn remaining_len(&self) -> u64 {
+        self.input_file.remaining_len() + (self.buffer.len() - self.location) as u64
+    }
+}
+
+
+// Encryption tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mem_data::*;
+
+    fn encrypt(data: &[u8], key: &EncryptionKey) -> Vec<u8> {
+        let mut output = EncryptedOutput::new(Vec::new(), Some(key), b"header").unwrap();
+        for part in data.chunks(1000) {
+            assert!( output.write_data(part) );
+        }
+        output.finish().unwrap()
+    }
+
+    fn decrypt(data: &[u8], key: &EncryptionKey, chunk_size: usize) -> Result<Vec<u8>, EncryptionError> {
+        decrypt_with(data, key, b"header", chunk_size)
+    }
+
+    fn decrypt_with(data: &[u8], key: &EncryptionKey, associated_data: &[u8], chunk_size: usize) -> Result<Vec<u8>, EncryptionError> {
+        let mut input = DecryptedInput::new(MemInput::new(data, 1), Some(key), associated_data)?;
+        input.set_chunk_size(chunk_size);
+        let mut output = Vec::new();
+        loop {
+            let chunk = input.get_next_data();
+            if chunk.is_empty() { break }
+            output.extend_from_slice(chunk);
+        }
+        input.finish()?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_encryption_1() {
+        // encryption test
+        // scenario: data of multiple segments is decrypted with the same key, data without key is unchanged
+
+        let key = EncryptionKey::from_passphrase(b"passphrase".to_vec(), 1_000);
+        let data: Vec<u8> = (0..3 * SEGMENT_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt(&data, &key);
+        assert_eq!( encrypted.len(), ENVELOPE_SIZE + data.len() + 4 * TAG_SIZE );
+        assert_eq!( decrypt(&encrypted, &key, 1000).unwrap(), data );
+
+        let empty = encrypt(&[], &key);
+        assert_eq!( empty.len(), ENVELOPE_SIZE + TAG_SIZE );
+        assert_eq!( decrypt(&empty, &key, 1).unwrap(), [] );
+
+        let mut output = EncryptedOutput::new(Vec::new(), None, &[]).unwrap();
+        assert!( output.write_data(&[1,2,3]) );
+        assert_eq!( output.finish().unwrap(), [1,2,3] );
+    }
+
+    #[test]
+    fn test_encryption_2() {
+        // encryption test
+        // scenario: wrong key, modified data and truncated data are detected
+
+        let key = EncryptionKey::from_passphrase(b"passphrase".to_vec(), 1_000);
+        let data: Vec<u8> = (0..2 * SEGMENT_SIZE).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt(&data, &key);
+
+        assert!( matches!( decrypt(&encrypted, &EncryptionKey::from_passphrase(b"other".to_vec(), 1_000), 1000), Err(EncryptionError::DecryptionFailed) ) );
+
+        let mut modified = encrypted.clone();
+        modified[ENVELOPE_SIZE + 10] ^= 1;
+        assert!( matches!( decrypt(&modified, &key, 1000), Err(EncryptionError::DecryptionFailed) ) );
+
+        let truncated = &encrypted[..ENVELOPE_SIZE + SEGMENT_SIZE + TAG_SIZE];
+        assert!( matches!( decrypt(truncated, &key, 1000), Err(EncryptionError::DecryptionFailed) ) );
+    }
+
+    #[test]
+    fn test_encryption_3() {
+        // encryption test
+        // scenario: modified associated data is detected, key file is used without PBKDF2 and is not interchangeable with passphrase
+
+        let key = EncryptionKey::from_passphrase(b"passphrase".to_vec(), 1_000);
+        let data: Vec<u8> = (0..SEGMENT_SIZE + 10).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt(&data, &key);
+        assert!( matches!( decrypt_with(&encrypted, &key, b"Header", 1000), Err(EncryptionError::DecryptionFailed) ) );
+        assert!( matches!( decrypt_with(&encrypted, &key, b"", 1000), Err(EncryptionError::DecryptionFailed) ) );
+
+        let file_key = EncryptionKey::from_key_file(b"passphrase".to_vec());
+        let encrypted_file_key = encrypt(&data, &file_key);
+        assert_eq!( &encrypted_file_key[..4], &0u32.to_le_bytes() );
+        assert_eq!( decrypt(&encrypted_file_key, &file_key, 1000).unwrap(), data );
+        assert!( matches!( decrypt(&encrypted_file_key, &key, 1000), Err(EncryptionError::InvalidEnvelope) ) );
+        assert!( matches!( decrypt(&encrypted, &file_key, 1000), Err(EncryptionError::InvalidEnvelope) ) );
+    }
+
+    #[test]
+    fn test_encryption_iterations() {
+        // encryption test
+        // scenario: iterations of passphrase key are stored in encrypted data and used by decryption with any iterations of key
+
+        let data: Vec<u8> = (0..100).collect();
+        let encrypted = encrypt(&data, &EncryptionKey::from_passphrase(b"passphrase".to_vec(), 1_500));
+        assert_eq!( &encrypted[..4], &1_500u32.to_le_bytes() );
+        assert_eq!( decrypt(&encrypted, &EncryptionKey::from_passphrase(b"passphrase".to_vec(), 1_000), 10).unwrap(), data );
+
+        let encrypted = encrypt(&data, &EncryptionKey::from_passphrase(b"passphrase".to_vec(), 0));
+        assert_eq!( &encrypted[..4], &1u32.to_le_bytes() );
+
+        let mut modified = encrypted.clone();
+        modified[..4].copy_from_slice(&(MAX_KEY_ITERATIONS + 1).to_le_bytes());
+        assert!( matches!( decrypt(&modified, &EncryptionKey::from_passphrase(b"passphrase".to_vec(), 1_000), 10), Err(EncryptionError::InvalidEnvelope) ) );
+    }
+}
@@ -0,0 +1,399 @@
+use super::processor::*;
+use super::metadata::*;
+
+// Magic value at the beginning of signature and delta files
+const HEADER_MAGIC: &[u8; 4] = b"HDIF";
+// version 2 stores zero chunks in signatures as zero chunk records, so signatures of version 1 cannot be mixed with it
+const HEADER_VERSION: u8 = 2;
+
+// kinds of files
+const KIND_SIGNATURE: u8 = b'S';
+const KIND_DELTA: u8 = b'D';
+const KIND_CONTROL: u8 = b'C';
+const KIND_TREE: u8 = b'T';
+
+// flags of header
+const FLAG_METADATA: u8 = 1;
+const FLAG_MAC: u8 = 2;
+const FLAG_ENCRYPTED: u8 = 4;
+const FLAG_BSDIFF: u8 = 8;
+const FLAG_DIGEST: u8 = 16;
+const FLAG_IDENTICAL: u8 = 32;
+
+// flags of metadata fields which are present
+const METADATA_MODE: u8 = 1;
+const METADATA_OWNER: u8 = 2;
+const METADATA_MTIME: u8 = 4;
+const METADATA_XATTRS: u8 = 8;
+
+// Limits protecting against malformed files
+// chunk size limits buffers allocated for chunks of signature, delta and control files
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+const MAX_XATTR_NAME_SIZE: usize = 255;
+const MAX_XATTR_VALUE_SIZE: usize = 64 * 1024;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Signature,
+    Delta,
+    Control,
+    Tree
+}
+
+// Header of signature, delta, control and signature tree files
+// header format:
+// magic, u8 kind, u8 version, u8 flags, u32 chunk size, optional metadata, optional u64 file length and digest of whole file
+// metadata format:
+// u8 present fields, u32 mode, u32 uid, u32 gid, i64 mtime seconds, u32 mtime nanoseconds,
+// u32 number of extended attributes, for every attribute: u32 name length, name, u32 value length, value
+// fields which are not present are skipped, all numbers are little endian
+// authenticated files end with MAC trailer calculated over all preceding data, including header
+// data following header of encrypted files is encrypted, header itself stays readable
+// delta data of bsdiff files is made of bsdiff records instead of chunk tags
+// identical delta has no data, old file is copied and checked against digest of header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    pub kind: FileKind,
+    pub chunk_size: usize,
+    pub metadata: Option<FileMetadata>,
+    pub authenticated: bool,
+    pub encrypted: bool,
+    pub bsdiff: bool,
+    pub digest: Option<(u64, [u8; HASH_SIZE])>, // length and SHA-256 of whole file
+    pub identical: bool
+}
+
+impl FileHeader {
+
+    pub fn new(kind: FileKind, chunk_size: usize, metadata: Option<FileMetadata>) -> Self {
+        Self { kind, chunk_size, metadata, authenticated: false, encrypted: false, bsdiff: false, digest: None, identical: false }
+    }
+
+    // Header of signature updated from delta, it is the same as header of signature created from patched file
+    // delta header keeps metadata, length and digest of new file
+    pub fn updated_signature(chunk_size: usize, delta_header: Option<&FileHeader>) -> Self {
+        let mut header = Self::new(FileKind::Signature, chunk_size, None);
+        if let Some(delta_header) = delta_header {
+            header.metadata = delta_header.metadata.clone();
+            header.digest = delta_header.digest;
+        }
+        header
+    }
+
+    // Returns written header data, which encrypted files authenticate as associated data
+    pub fn write<S: ProcessorDataOutput>(&self, output_file: &mut S) -> Result<Vec<u8>, ProcessorError> {
+        let mut data = Vec::new();
+        self.write_fields(&mut data)?;
+        write_data(output_file, &data)?;
+        Ok(data)
+    }
+
+    fn write_fields(&self, output_file: &mut Vec<u8>) -> Result<(), ProcessorError> {
+        let kind = match self.kind {
+            FileKind::Signature => KIND_SIGNATURE,
+            FileKind::Delta => KIND_DELTA,
+            FileKind::Control => KIND_CONTROL,
+            FileKind::Tree => KIND_TREE
+        };
+        let mut flags = if self.metadata.is_some() { FLAG_METADATA } else { 0 };
+        if self.authenticated {
+            flags |= FLAG_MAC;
+        }
+        if self.encrypted {
+            flags |= FLAG_ENCRYPTED;
+        }
+        if self.bsdiff {
+            flags |= FLAG_BSDIFF;
+        }
+        if self.digest.is_some() {
+            flags |= FLAG_DIGEST;
+        }
+        if self.identical {
+            flags |= FLAG_IDENTICAL;
+        }
+        // header with chunk size larger than limit could not be read back
+        if self.chunk_size > MAX_CHUNK_SIZE {
+            return Err(ProcessorError::InvalidHeader)
+        }
+        let chunk_size = self.chunk_size as u32;
+
+        write_data(output_file, HEADER_MAGIC)?;
+        write_data(output_file, &[kind, HEADER_VERSION, flags])?;
+        write_data(output_file, &chunk_size.to_le_bytes())?;
+        if let Some(metadata) = &self.metadata {
+            write_metadata(output_file, metadata)?;
+        }
+        if let Some((len, digest)) = &self.digest {
+            write_data(output_file, &len.to_le_bytes())?;
+            write_data(output_file, digest)?;
+        }
+        Ok(())
+    }
+
+    // Reads header from beginning of the file, None is returned for files without header
+    // input is left positioned after header, chunk size of input has to be set by the caller afterwards
+    pub fn read<T: ProcessorDataInput>(input_file: &mut T) -> Result<Option<Self>, ProcessorError> {
+        input_file.set_chunk_size(HEADER_MAGIC.len());
+        if input_file.get_next_data() != HEADER_MAGIC {
+            if !input_file.move_back_last_read() {
+                return Err(ProcessorError::FileSeek)
+            }
+            return Ok(None)
+        }
+
+        let (kind, version, flags) = match read_data(input_file, 3)? {
+            [kind, version, flags] => (*kind, *version, *flags),
+            _ => return Err(ProcessorError::InvalidHeader)
+        };
+        let kind = match kind {
+            KIND_SIGNATURE => FileKind::Signature,
+            KIND_DELTA => FileKind::Delta,
+            KIND_CONTROL => FileKind::Control,
+            KIND_TREE => FileKind::Tree,
+            _ => return Err(ProcessorError::InvalidHeader)
+        };
+        if version != HEADER_VERSION {
+            return Err(ProcessorError::UnsupportedVersion)
+        }
+        if flags & !(FLAG_METADATA | FLAG_MAC | FLAG_ENCRYPTED | FLAG_BSDIFF | FLAG_DIGEST | FLAG_IDENTICAL) != 0 {
+            return Err(ProcessorError::InvalidHeader)
+        }
+        let chunk_size = read_u32(input_file)? as usize;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(ProcessorError::InvalidHeader)
+        }
+        let metadata = if flags & FLAG_METADATA != 0 { Some(read_metadata(input_file)?) } else { None };
+        let digest = if flags & FLAG_DIGEST != 0 { Some(read_digest(input_file)?) } else { None };
+
+        let authenticated = flags & FLAG_MAC != 0;
+        let encrypted = flags & FLAG_ENCRYPTED != 0;
+        let bsdiff = flags & FLAG_BSDIFF != 0;
+        let identical = flags & FLAG_IDENTICAL != 0;
+        if identical && digest.is_none() {
+            return Err(ProcessorError::InvalidHeader)
+        }
+
+        Ok(Some(Self { kind, chunk_size, metadata, authenticated, encrypted, bsdiff, digest, identical }))
+    }
+}
+
+
+fn write_data<S: ProcessorDataOutput>(output_file: &mut S, data: &[u8]) -> Result<(), ProcessorError> {
+    if output_file.write_data(data) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+}
+
+fn write_metadata<S: ProcessorDataOutput>(output_file: &mut S, metadata: &FileMetadata) -> Result<(), ProcessorError> {
+    let mut fields = 0;
+    let mut data = Vec::new();
+
+    if let Some(mode) = metadata.mode {
+        fields |= METADATA_MODE;
+        data.extend_from_slice(&mode.to_le_bytes());
+    }
+    if let Some((uid, gid)) = metadata.owner {
+        fields |= METADATA_OWNER;
+        data.extend_from_slice(&uid.to_le_bytes());
+        data.extend_from_slice(&gid.to_le_bytes());
+    }
+    if let Some((seconds, nanoseconds)) = metadata.mtime {
+        fields |= METADATA_MTIME;
+        data.extend_from_slice(&seconds.to_le_bytes());
+        data.extend_from_slice(&nanoseconds.to_le_bytes());
+    }
+    if let Some(xattrs) = &metadata.xattrs {
+        fields |= METADATA_XATTRS;
+        data.extend_from_slice(&(xattrs.len() as u32).to_le_bytes());
+        for (name, value) in xattrs {
+            if name.len() > MAX_XATTR_NAME_SIZE || value.len() > MAX_XATTR_VALUE_SIZE {
+                return Err(ProcessorError::InvalidHeader)
+            }
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            data.extend_from_slice(name);
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+    }
+
+    write_data(output_file, &[fields])?;
+    write_data(output_file, &data)
+}
+
+fn read_data<T: ProcessorDataInput>(input_file: &mut T, size: usize) -> Result<&[u8], ProcessorError> {
+    input_file.set_chunk_size(size);
+    let data = input_file.get_next_data();
+    if data.len() != size {
+        return Err(ProcessorError::InvalidHeader)
+    }
+    Ok(data)
+}
+
+fn read_u32<T: ProcessorDataInput>(input_file: &mut T) -> Result<u32, ProcessorError> {
+    let mut data = [0u8; 4];
+    data.copy_from_slice(read_data(input_file, 4)?);
+    Ok(u32::from_le_bytes(data))
+}
+
+fn read_i64<T: ProcessorDataInput>(input_file: &mut T) -> Result<i64, ProcessorError> {
+    let mut data = [0u8; 8];
+    data.copy_from_slice(read_data(input_file, 8)?);
+    Ok(i64::from_le_bytes(data))
+}
+
+fn read_digest<T: ProcessorDataInput>(input_file: &mut T) -> Result<(u64, [u8; HASH_SIZE]), ProcessorError> {
+    let len = read_i64(input_file)? as u64;
+    let mut digest = [0u8; HASH_SIZE];
+    digest.copy_from_slice(read_data(input_file, HASH_SIZE)?);
+    Ok((len, digest))
+}
+
+fn read_limited<T: ProcessorDataInput>(input_file: &mut T, max_size: usize) -> Result<Vec<u8>, ProcessorError> {
+    let size = read_u32(input_file)? as usize;
+    if size > max_size {
+        return Err(ProcessorError::InvalidHeader)
+    }
+    Ok(read_data(input_file, size)?.to_owned())
+}
+
+fn read_metadata<T: ProcessorDataInput>(input_file: &mut T) -> Result<FileMetadata, ProcessorError> {
+    let fields = read_data(input_file, 1)?[0];
+    let mut metadata = FileMetadata::default();
+
+    if fields & METADATA_MODE != 0 {
+        metadata.mode = Some(read_u32(input_file)?);
+    }
+    if fields & METADATA_OWNER != 0 {
+        metadata.owner = Some((read_u32(input_file)?, read_u32(input_file)?));
+    }
+    if fields & METADATA_MTIME != 0 {
+        metadata.mtime = Some((read_i64(input_file)?, read_u32(input_file)?));
+    }
+    if fields & METADATA_XATTRS != 0 {
+        let mut xattrs = Vec::new();
+        for _ in 0..read_u32(input_file)? {
+            let name = read_limited(input_file, MAX_XATTR_NAME_SIZE)?;
+            let value = read_limited(input_file, MAX_XATTR_VALUE_SIZE)?;
+            xattrs.push((name, value));
+        }
+        metadata.xattrs = Some(xattrs);
+    }
+
+    Ok(metadata)
+}
+
+
+// File header tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mem_data::*;
+
+    #[test]
+    fn test_header_1() {
+        // header test
+        // scenario: header without metadata, header of other version is refused
+
+        let header = FileHeader::new(FileKind::Signature, 1024, None);
+        let mut output = Vec::new();
+        assert!( header.write(&mut output).is_ok() );
+        assert_eq!( output, [b'H',b'D',b'I',b'F',b'S',2,0,0,4,0,0] );
+
+        let mut input = MemInput::new(&output, 1);
+        assert_eq!( FileHeader::read(&mut input).unwrap(), Some(header) );
+
+        // header of older version is refused explicitly
+        output[5] = 1;
+        let mut input = MemInput::new(&output, 1);
+        assert!( matches!( FileHeader::read(&mut input), Err(ProcessorError::UnsupportedVersion) ) );
+    }
+
+    #[test]
+    fn test_header_2() {
+        // header test
+        // scenario: header with all metadata fields is followed by data
+
+        let metadata = FileMetadata {
+            mode: Some(0o755),
+            owner: Some((1000, 100)),
+            mtime: Some((-5, 10)),
+            xattrs: Some(vec![(b"user.a".to_vec(), b"value".to_vec())])
+        };
+        let header = FileHeader::new(FileKind::Delta, 4, Some(metadata));
+        let mut output = Vec::new();
+        assert!( header.write(&mut output).is_ok() );
+        output.extend_from_slice(&[0,0]);
+
+        let mut input = MemInput::new(&output, 1);
+        assert_eq!( FileHeader::read(&mut input).unwrap(), Some(header) );
+        input.set_chunk_size(4);
+        assert_eq!( input.get_next_data(), [0,0] );
+    }
+
+    #[test]
+    fn test_header_3() {
+        // header test
+        // scenario: file without header is left unchanged
+
+        let data = [0,1,2,3,4,5];
+        let mut input = MemInput::new(&data, 1);
+        assert_eq!( FileHeader::read(&mut input).unwrap(), None );
+        input.set_chunk_size(6);
+        assert_eq!( input.get_next_data(), data );
+    }
+
+    #[test]
+    fn test_header_4() {
+        // header test
+        // scenario: header of bsdiff delta keeps its flag, unknown flags are refused
+
+        let mut header = FileHeader::new(FileKind::Delta, 1024, None);
+        header.bsdiff = true;
+        let mut output = Vec::new();
+        assert!( header.write(&mut output).is_ok() );
+        assert_eq!( output, [b'H',b'D',b'I',b'F',b'D',2,8,0,4,0,0] );
+
+        let mut input = MemInput::new(&output, 1);
+        assert_eq!( FileHeader::read(&mut input).unwrap(), Some(header) );
+
+        output[6] = 64;
+        let mut input = MemInput::new(&output, 1);
+        assert!( FileHeader::read(&mut input).is_err() );
+    }
+
+    #[test]
+    fn test_header_5() {
+        // header test
+        // scenario: digest of whole file follows metadata, identical delta without digest is refused
+
+        let mut header = FileHeader::new(FileKind::Delta, 4, Some(FileMetadata { mode: Some(0o644), owner: None, mtime: None, xattrs: None }));
+        header.digest = Some((10, [7; HASH_SIZE]));
+        header.identical = true;
+        let mut output = Vec::new();
+        assert!( header.write(&mut output).is_ok() );
+        assert_eq!( output.len(), 11 + 5 + 8 + HASH_SIZE );
+        assert_eq!( output[6], 1 | 16 | 32 );
+
+        let mut input = MemInput::new(&output, 1);
+        assert_eq!( FileHeader::read(&mut input).unwrap(), Some(header) );
+
+        output[6] = 1 | 32;
+        output.truncate(16);
+        let mut input = MemInput::new(&output, 1);
+        assert!( FileHeader::read(&mut input).is_err() );
+    }
+
+    #[test]
+    fn test_header_invalid() {
+        // header test
+        // scenario: header is truncated, chunk size is larger than limit
+
+        let data = [b'H',b'D',b'I',b'F',b'D',2,1,4,0,0,0,1];
+        let mut input = MemInput::new(&data, 1);
+        assert!( FileHeader::read(&mut input).is_err() );
+
+        let data = [b'H',b'D',b'I',b'F',b'D',2,0,1,0,0,4];
+        let mut input = MemInput::new(&data, 1);
+        assert!( matches!( FileHeader::read(&mut input), Err(ProcessorError::InvalidHeader) ) );
+        assert!( FileHeader::new(FileKind::Delta, MAX_CHUNK_SIZE + 1, None).write(&mut Vec::new()).is_err() );
+    }
+}
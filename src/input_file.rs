@@ -1,4 +1,4 @@
-use std::{error::Error, io::Read, convert::TryFrom};
+use std::{error::Error, io::{Read, Seek, SeekFrom}, convert::TryFrom, ops::Range, path::Path};
 use super::processor::*;
 
 
@@ -7,18 +7,82 @@ pub struct InputFile {
     reader: std::io::BufReader<std::fs::File>,
     chunk_size: usize,
     chunk: Vec<u8>,
-    len_to_read: u64
+    len: u64,
+    len_to_read: u64,
+    hole: Range<u64>, // known hole and data ranges of sparse file around read position
+    data: Range<u64>
 }
 
 impl InputFile {
     
-    pub fn new(file_name: &str, chunk_size: usize) -> Result<Self, Box<dyn Error>> {
+    pub fn new<P: AsRef<Path>>(file_name: P, chunk_size: usize) -> Result<Self, Box<dyn Error>> {
         let file = std::fs::File::open(file_name)?;
         let metadata = file.metadata()?;
         let reader = std::io::BufReader::new(file);
-        let mut chunk: Vec<u8> = Vec::new();
-        chunk.resize(chunk_size, 0);
-        Ok( Self { reader, chunk_size, chunk, len_to_read: metadata.len() } )
+        let chunk: Vec<u8> = vec![0; chunk_size];
+        Ok( Self { reader, chunk_size, chunk, len: metadata.len(), len_to_read: metadata.len(), hole: 0..0, data: 0..0 } )
+    }
+
+    // Offset of next data read from the file
+    pub fn position(&self) -> u64 {
+        self.len - self.len_to_read
+    }
+
+    // Excludes trailer at the end of the file from reading, e.g. MAC of the file
+    pub fn skip_trailer(&mut self, trailer_len: u64) {
+        let trailer_len = std::cmp::min(trailer_len, self.len_to_read);
+        self.len -= trailer_len;
+        self.len_to_read -= trailer_len;
+    }
+
+    // Reads the file from offset with separate reader, e.g. to verify signature of data which is read later
+    // position of next data read is kept
+    pub fn read_from<T>(&mut self, offset: u64, read: impl FnOnce(&mut dyn Read) -> T) -> std::io::Result<T> {
+        let position = self.position();
+        let file = self.reader.get_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let ret = read(&mut std::io::BufReader::new(file));
+        self.reader.seek(SeekFrom::Start(position))?;
+        Ok(ret)
+    }
+
+    // Checks if data range is in a hole of sparse file, holes are found with SEEK_DATA and SEEK_HOLE
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn is_hole(&mut self, range: Range<u64>) -> bool {
+        if !self.hole.contains(&range.start) && !self.data.contains(&range.start) {
+            self.find_hole(range.start);
+        }
+        self.hole.start <= range.start && range.end <= self.hole.end
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn is_hole(&mut self, _range: Range<u64>) -> bool {
+        false
+    }
+
+    // Finds hole or data range which starts at offset, offset of file is restored afterwards
+    // range of data is assumed if file system does not report holes
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn find_hole(&mut self, offset: u64) {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.reader.get_ref().as_raw_fd();
+        let start = offset as libc::off_t;
+        // SAFETY: lseek only changes offset of the file, which is restored before returning
+        unsafe {
+            let current = libc::lseek(fd, 0, libc::SEEK_CUR);
+            let data_start = libc::lseek(fd, start, libc::SEEK_DATA);
+            if data_start < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                // no data till end of file
+                self.hole = offset..u64::MAX;
+            } else if data_start > start {
+                self.hole = offset..data_start as u64;
+            } else {
+                let hole_start = if data_start == start { libc::lseek(fd, start, libc::SEEK_HOLE) } else { -1 };
+                self.data = offset..if hole_start > start { hole_start as u64 } else { u64::MAX };
+            }
+            libc::lseek(fd, current, libc::SEEK_SET);
+        }
     }
     
 }
@@ -40,6 +104,14 @@ impl ProcessorDataInput for InputFile {
             }
         }
         
+        let position = self.position();
+        if self.is_hole(position..position + self.chunk.len() as u64) && self.reader.seek_relative(self.chunk.len() as i64).is_ok() {
+            // hole of sparse file is not read
+            self.chunk.fill(0);
+            self.len_to_read -= self.chunk.len() as u64;
+            return &self.chunk
+        }
+
         match self.reader.read_exact(&mut self.chunk) {
             Ok(()) => {
                 self.len_to_read -= self.chunk.len() as u64;
@@ -57,5 +129,56 @@ impl ProcessorDataInput for InputFile {
         self.len_to_read += self.chunk.len() as u64;
         self.reader.seek_relative(-(self.chunk.len() as i64)).is_ok()
     }
+
+    fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+        self.chunk.resize(chunk_size, 0);
+    }
+
+    fn total_len(&self) -> u64 {
+        self.len
+    }
+
+    fn remaining_len(&self) -> u64 {
+        self.len_to_read
+    }
 }
 
+
+
+// Input file tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn test_input_sparse() {
+        // input file test
+        // scenario: holes of sparse file are read as zeros, also after moving back to data before the hole
+
+        let file_name = test_file_name("input-sparse");
+        let mut file = std::fs::File::create(&file_name).unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        file.write_all(&[2; 10]).unwrap();
+        file.set_len(2 * 1024 * 1024).unwrap();
+        drop(file);
+
+        let mut input = InputFile::new(&file_name, 4096).unwrap();
+        assert_eq!( input.get_next_data(), [1; 4096] );
+        assert_eq!( input.get_next_data(), [0; 4096] );
+        assert!( input.move_back_last_read() );
+        assert!( input.move_back_last_read() );
+        assert_eq!( input.get_next_data(), [1; 4096] );
+        let mut data = input.get_next_data().to_vec();
+        while input.remaining_len() > 0 {
+            data.extend_from_slice(input.get_next_data());
+        }
+        let mut expected = vec![0; 2 * 1024 * 1024 - 4096];
+        expected[1024 * 1024 - 4096..1024 * 1024 - 4096 + 10].fill(2);
+        assert_eq!( data, expected );
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+use std::io::Read;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use super::processor::*;
+use super::input_file::*;
+use super::file_header::*;
+
+// Using HMAC-SHA256 which gives 32 bytes trailer
+pub const MAC_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+
+// Custom error codes, all of them contain name of the file
+#[derive(Debug)]
+pub enum MacError {
+    KeyRequired(String),
+    NotAuthenticated(String),
+    VerificationFailed(String),
+    FileRead(String, std::io::Error)
+}
+impl std::fmt::Display for MacError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MacError::KeyRequired(name) => write!(f, "File is authenticated, MAC key is required: {}", name),
+            MacError::NotAuthenticated(name) => write!(f, "File is not authenticated: {}", name),
+            MacError::VerificationFailed(name) => write!(f, "MAC verification failed for file: {}", name),
+            MacError::FileRead(name, x) => write!(f, "Unable to read file: {}, error: {}", name, x)
+        }
+    }
+}
+impl std::error::Error for MacError {}
+
+
+// Output which appends MAC of all written data when it is finished, data is passed unchanged without key
+pub struct MacOutput<S> {
+    output_file: S,
+    mac: Option<HmacSha256>
+}
+
+impl<S: ProcessorDataOutput> MacOutput<S> {
+
+    pub fn new(output_file: S, key: Option<&[u8]>) -> Self {
+        let mac = key.map(|key| HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size"));
+        Self { output_file, mac }
+    }
+
+    // Writes MAC trailer
+    pub fn finish(mut self) -> Result<S, ProcessorError> {
+        if let Some(mac) = self.mac.take() {
+            if !self.output_file.write_data(&mac.finalize().into_bytes()) {
+                return Err(ProcessorError::FileWrite)
+            }
+        }
+        Ok(self.output_file)
+    }
+
+}
+
+impl<S: ProcessorDataOutput> ProcessorDataOutput for MacOutput<S> {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        if let Some(mac) = &mut self.mac {
+            mac.update(data);
+        }
+        self.output_file.write_data(data)
+    }
+}
+
+
+// Appends MAC trailer of data of given length which was already written to output, used when written data was replaced afterwards
+pub fn append_mac<S: ProcessorDataOutput>(output_file: &mut S, key: &[u8], len: u64) -> bool {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    let mut buffer = vec![0; 64 * 1024];
+    let mut offset = 0;
+    while offset < len {
+        let size = buffer.len().min((len - offset) as usize);
+        if !output_file.read_back(offset, &mut buffer[..size]) {
+            return false
+        }
+        mac.update(&buffer[..size]);
+        offset += size as u64;
+    }
+    output_file.write_data(&mac.finalize().into_bytes())
+}
+
+
+// Verifies MAC trailer of input file which header was already read, trailer is excluded from further reads
+// MAC covers data from header start to end of the input, files with MAC trailer require key and key requires files with MAC trailer
+pub fn authenticate_input_file(input_file: &mut InputFile, header: Option<&FileHeader>, header_start: u64, file_name: &str, key: Option<&[u8]>) -> Result<(), MacError> {
+    let authenticated = header.map(|header| header.authenticated).unwrap_or(false);
+    match key {
+        Some(key) => {
+            if !authenticated {
+                return Err(MacError::NotAuthenticated(file_name.to_owned()))
+            }
+            // MAC is verified on the same open file which is read afterwards, so file cannot be replaced in between
+            let len = (input_file.position() + input_file.remaining_len()).saturating_sub(header_start);
+            match input_file.read_from(header_start, |reader| verify_mac(reader, key, len)).and_then(|result| result) {
+                Ok(true) => {}
+                Ok(false) => return Err(MacError::VerificationFailed(file_name.to_owned())),
+                Err(x) => return Err(MacError::FileRead(file_name.to_owned(), x))
+            }
+            input_file.skip_trailer(MAC_SIZE as u64);
+            Ok(())
+        }
+        None if authenticated => Err(MacError::KeyRequired(file_name.to_owned())),
+        None => Ok(())
+    }
+}
+
+// Checks MAC trailer of data of given length read from reader, MAC is calculated over all data before trailer
+pub fn verify_mac(reader: &mut dyn Read, key: &[u8], len: u64) -> std::io::Result<bool> {
+    if len < MAC_SIZE as u64 {
+        return Ok(false)
+    }
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    let mut buffer = vec![0; 64 * 1024];
+    let mut data = (&mut *reader).take(len - MAC_SIZE as u64);
+    loop {
+        match data.read(&mut buffer)? {
+            0 => break,
+            n => mac.update(&buffer[..n])
+        }
+    }
+
+    let mut trailer = [0u8; MAC_SIZE];
+    reader.read_exact(&mut trailer)?;
+    Ok(mac.verify_slice(&trailer).is_ok())
+}
+
+
+// MAC tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    #[test]
+    fn test_mac_output() {
+        // MAC test
+        // scenario: output without key is unchanged, output with key gets trailer
+
+        let mut output = MacOutput::new(Vec::new(), None);
+        assert!( output.write_data(&[1,2,3]) );
+        assert_eq!( output.finish().unwrap(), [1,2,3] );
+
+        let mut output = MacOutput::new(Vec::new(), Some(b"key"));
+        assert!( output.write_data(&[1,2,3]) );
+        let data = output.finish().unwrap();
+        assert_eq!( data.len(), 3 + MAC_SIZE );
+        assert_eq!( data[..3], [1,2,3] );
+
+        // MAC appended to already written data is the same
+        let mut appended = vec![1,2,3];
+        assert!( append_mac(&mut appended, b"key", 3) );
+        assert_eq!( appended, data );
+    }
+
+    #[test]
+    fn test_mac_verify() {
+        // MAC test
+        // scenario: MAC is verified with correct key only and fails if data was changed
+
+        let mut output = MacOutput::new(Vec::new(), Some(b"key"));
+        assert!( output.write_data(&[1,2,3,4,5]) );
+        let mut data = output.finish().unwrap();
+        let len = data.len() as u64;
+
+        assert!( verify_mac(&mut &data[..], b"key", len).unwrap() );
+        assert!( !verify_mac(&mut &data[..], b"other key", len).unwrap() );
+        assert!( !verify_mac(&mut &data[..], b"key", len - 1).unwrap() );
+
+        data[2] = 0;
+        assert!( !verify_mac(&mut &data[..], b"key", len).unwrap() );
+    }
+
+    #[test]
+    fn test_mac_input_file() {
+        // MAC test
+        // scenario: authenticated file is read without trailer, key and MAC are required together
+
+        let file_path = test_file_name("mac-input");
+        let file_name = file_path.to_str().unwrap();
+        let mut header = FileHeader::new(FileKind::Delta, 4, None);
+        header.authenticated = true;
+        let mut output = MacOutput::new(Vec::new(), Some(b"key"));
+        header.write(&mut output).unwrap();
+        assert!( output.write_data(&[2,1,2,3,4]) );
+        std::fs::write(file_name, output.finish().unwrap()).unwrap();
+
+        let mut input = InputFile::new(file_name, 1).unwrap();
+        let read_header = FileHeader::read(&mut input).unwrap();
+        assert_eq!( read_header.as_ref(), Some(&header) );
+        assert!( authenticate_input_file(&mut input, read_header.as_ref(), 0, file_name, Some(b"key")).is_ok() );
+        input.set_chunk_size(16);
+        assert_eq!( input.get_next_data(), [2,1,2,3,4] );
+
+        let mut input = InputFile::new(file_name, 1).unwrap();
+        let read_header = FileHeader::read(&mut input).unwrap();
+        assert!( matches!( authenticate_input_file(&mut input, read_header.as_ref(), 0, file_name, None), Err(MacError::KeyRequired(_)) ) );
+        assert!( matches!( authenticate_input_file(&mut input, read_header.as_ref(), 0, file_name, Some(b"other key")), Err(MacError::VerificationFailed(_)) ) );
+        assert!( matches!( authenticate_input_file(&mut input, None, 0, file_name, Some(b"key")), Err(MacError::NotAuthenticated(_)) ) );
+
+        // file replaced after it was opened is not used for verification of the opened file
+        let data = std::fs::read(file_name).unwrap();
+        let mut modified = data.clone();
+        modified[12] = 3;
+        std::fs::write(file_name, &modified).unwrap();
+        let mut input = InputFile::new(file_name, 1).unwrap();
+        let read_header = FileHeader::read(&mut input).unwrap();
+        std::fs::remove_file(file_name).unwrap();
+        std::fs::write(file_name, &data).unwrap();
+        assert!( matches!( authenticate_input_file(&mut input, read_header.as_ref(), 0, file_name, Some(b"key")), Err(MacError::VerificationFailed(_)) ) );
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{env, process};
+use std::{env, process, path::{Path, PathBuf}, io::{IsTerminal, Read}};
 
 mod processor;
 use processor::*;
@@ -6,105 +6,1438 @@ mod input_file;
 use input_file::*;
 mod output_file;
 use output_file::*;
+mod mem_data;
+mod dir_processor;
+use dir_processor::*;
+mod metadata;
+use metadata::*;
+mod file_header;
+use file_header::*;
+mod progress;
+use progress::*;
+mod stream_data;
+mod sync_protocol;
+use sync_protocol::*;
+mod mac;
+use mac::*;
+mod package;
+use package::*;
+mod encryption;
+use encryption::*;
+mod chunk_store;
+use chunk_store::*;
+mod release;
+use release::*;
+mod planner;
+use planner::*;
+mod vcdiff;
+use vcdiff::*;
+mod rdiff;
+use rdiff::*;
+mod bsdiff;
+use bsdiff::*;
+mod zsync;
+use zsync::*;
+mod merkle;
+use merkle::*;
+mod tune;
+use tune::*;
+#[cfg(test)]
+mod test_dir;
 
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    
+    // options are separated from arguments, every command takes options it uses and refuses the others
+    let (args, mut options) = Options::parse(env::args().collect());
+
+    // format of files written by signature and delta commands selects variant of the command
+    let output_format = peek_format(&options);
+
     // handle arguments
-    if ( args.len() == 4 || args.len() == 5 ) && args[1] == "signature" {
-        
+    if ( args.len() == 4 || args.len() == 5 ) && args[1] == "signature" && output_format == OutputFormat::Rdiff {
+
+        take_format(&mut options, &[OutputFormat::Rdiff]);
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 4);
+        options.finish(&args[1]);
+
+        // block length of librsync signature is the chunk size
+        let chunk_size = parse_input_chunk_size(chunk_size_arg.as_ref(), &args[2]);
+        let format = RdiffFormat::new(chunk_size);
+
+        // try to open files
+        let mut input_file = open_input_file(&args[2], chunk_size, "input");
+        let mut output_file = create_output_file(&args[3], atomic);
+
+        // create logic processor
+        let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+        let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+        let mut proc = Processor::new(&mut input_file, &mut output_file).with_progress(&mut show_progress).with_format(&format);
+
+        // start processing input file to generate signature file
+        let result = proc.process_signature();
+        progress_bar.finish();
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+        finish_output_file(output_file, &args[3]);
+    }
+    else if ( args.len() == 4 || args.len() == 5 ) && args[1] == "signature" && output_format == OutputFormat::Merkle {
+
+        // nodes of signature tree are read on demand, so it is not authenticated
+        take_format(&mut options, &[OutputFormat::Merkle]);
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 4);
+        options.finish(&args[1]);
+
         // check if chunk size was specified
-        let chunk_size = if args.len() == 5 {
-            match args[4].parse::<usize>() {
-                Ok(v) => v,
-                Err(_) => {
-                    eprintln!("Wrong value of chunk size: {}", &args[4]);
-                    process::exit(1);
-                }
-            }
-        } else {
-            processor::CHUNK_SIZE
-        };
-        
+        let chunk_size = parse_input_chunk_size(chunk_size_arg.as_ref(), &args[2]);
+
         // try to open files
-        let mut input_file = match InputFile::new(&args[2], chunk_size) {
+        let mut input_file = open_input_file(&args[2], chunk_size, "input");
+        let mut output_file = create_output_file(&args[3], atomic);
+
+        // signature tree starts with header containing chunk size and metadata of input file
+        let header = FileHeader::new(FileKind::Tree, chunk_size, FileMetadata::from_file(&args[2]).ok());
+        write_file_header(&header, &mut output_file);
+        let chunk_count = input_file.total_len().div_ceil(chunk_size as u64);
+        let mut output_file = match MerkleOutput::new(output_file, MERKLE_FANOUT, chunk_count) {
             Ok(f) => f,
             Err(x) => {
-                eprintln!("Unable to open inpug file: {}, error: {}", &args[2], x);
+                eprintln!("Processing error: {}", x);
                 process::exit(1);
             }
         };
-        let mut output_file = match OutputFile::new(&args[3]) {
-            Ok(f) => f,
+
+        // create logic processor
+        let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+        let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+        let mut proc = Processor::new(&mut input_file, &mut output_file).with_progress(&mut show_progress);
+
+        // start processing input file to generate signature tree, inner nodes are written at the end
+        let result = proc.process_signature();
+        progress_bar.finish();
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+        match output_file.finish() {
+            Ok(f) => finish_output_file(f, &args[3]),
             Err(x) => {
-                eprintln!("Unable to create output file: {}, error: {}", &args[3], x);
+                eprintln!("Unable to write output file: {}, error: {}", &args[3], x);
                 process::exit(1);
             }
-        };
-        
+        }
+    }
+    else if ( args.len() == 4 || args.len() == 5 ) && args[1] == "signature" {
+
+        take_format(&mut options, &[OutputFormat::Hdiff]);
+        let mac_key = take_mac_key(&mut options);
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 4);
+        options.finish(&args[1]);
+
+        // check if chunk size was specified
+        let chunk_size = parse_input_chunk_size(chunk_size_arg.as_ref(), &args[2]);
+
+        // try to open files
+        let mut input_file = open_input_file(&args[2], chunk_size, "input");
+        let mut output_file = create_output_file(&args[3], atomic);
+
+        // signature file starts with header containing chunk size, metadata, length and digest of input file
+        // digest is calculated while signature is written, so header is written again when it is known
+        let mut header = FileHeader::new(FileKind::Signature, chunk_size, FileMetadata::from_file(&args[2]).ok());
+        header.authenticated = mac_key.is_some();
+        header.digest = Some((0, [0; processor::HASH_SIZE]));
+        write_file_header(&header, &mut output_file);
+
         // create logic processor
-        let mut proc = Processor::new(&mut input_file, &mut output_file);
+        let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+        let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+        let mut proc = Processor::new(&mut input_file, &mut output_file).with_progress(&mut show_progress);
 
         // start processing input file to generate signature file
-        if let Err(x) = proc.process_signature() {
-            eprintln!("Processing error: {}", x);
+        let result = proc.process_signature_digest();
+        progress_bar.finish();
+        match result {
+            Ok(digest) => header.digest = Some(digest),
+            Err(x) => abort_output_file(output_file, x)
+        }
+        let header_data = exit_on_error(header.write(&mut Vec::new()));
+        if !output_file.write_back(0, &header_data) {
+            abort_output_file(output_file, ProcessorError::FileWrite);
         }
+
+        // MAC covers rewritten header, so it is calculated from written data
+        if let Some(key) = mac_key.as_deref() {
+            let len = exit_on_error(output_file.written_len());
+            if !append_mac(&mut output_file, key, len) {
+                abort_output_file(output_file, ProcessorError::FileWrite);
+            }
+        }
+        finish_output_file(output_file, &args[3]);
     }
-    else if ( args.len() == 5 || args.len() == 6 ) && args[1] == "delta" {
-        
-        // check if chunk size was specified
-        let chunk_size = if args.len() == 6 {            
-            match args[5].parse::<usize>() {
-                Ok(v) => v,
-                Err(_) => {
-                    eprintln!("Wrong value of chunk size: {}", &args[5]);
-                    process::exit(1);
-                }
+    else if ( args.len() == 5 || args.len() == 6 ) && args[1] == "delta" && ( output_format == OutputFormat::Rdiff || is_input_file_of(&args[2], is_rdiff_signature) ) {
+
+        // librsync delta has no header, so it cannot be authenticated or encrypted
+        take_format(&mut options, &[OutputFormat::Rdiff]);
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 5);
+        options.finish(&args[1]);
+
+        // block length is taken from signature file header
+        let mut signature_file = open_input_file(&args[2], 1, "signature");
+        let format = match RdiffFormat::read_signature_header(&mut signature_file) {
+            Ok(format) => format,
+            Err(x) => {
+                eprintln!("Unable to read file: {}, error: {}", &args[2], x);
+                process::exit(1);
             }
-        } else {
-            processor::CHUNK_SIZE
         };
-                
+        if chunk_size_arg.is_some() && parse_chunk_size(chunk_size_arg.as_ref()) != format.block_len() {
+            eprintln!("Chunk size does not match chunk size stored in file: {}", format.block_len());
+            process::exit(1);
+        }
+
+        // try to open files
+        let mut input_file = open_input_file(&args[3], format.block_len(), "input");
+        let mut output_file = create_output_file(&args[4], atomic);
+
+        // create logic processor
+        let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+        let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+        let mut proc = Processor::new(&mut input_file, &mut output_file).with_progress(&mut show_progress).with_format(&format);
+
+        // start processing input files to generate delta file
+        let result = proc.process_delta(&mut signature_file);
+        progress_bar.finish();
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+        finish_output_file(output_file, &args[4]);
+    }
+    else if ( args.len() == 5 || args.len() == 6 ) && args[1] == "delta" && output_format == OutputFormat::Vcdiff {
+
+        // VCDIFF has no header, so it cannot be authenticated or encrypted
+        take_format(&mut options, &[OutputFormat::Vcdiff]);
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 5);
+        options.finish(&args[1]);
+
+        // chunk size is taken from signature file header
+        let mut signature_file = open_input_file(&args[2], processor::HASH_SIZE, "signature");
+        let signature_header = read_file_header(&mut signature_file, FileKind::Signature, &args[2]);
+        authenticate_file(&mut signature_file, signature_header.as_ref(), 0, &args[2], None);
+        let chunk_size = select_chunk_size(signature_header.as_ref(), chunk_size_arg.as_ref());
+        signature_file.set_chunk_size(processor::HASH_SIZE);
+
         // try to open files
-        let mut input_file = match InputFile::new(&args[3], chunk_size) {
+        let mut input_file = open_input_file(&args[3], chunk_size, "input");
+        let target_len = input_file.total_len();
+        let mut output_file = match VcdiffOutput::new(create_output_file(&args[4], atomic), chunk_size, target_len) {
             Ok(f) => f,
             Err(x) => {
-                eprintln!("Unable to open input file: {}, error: {}", &args[3], x);
+                eprintln!("Unable to write output file: {}, error: {}", &args[4], x);
+                process::exit(1);
+            }
+        };
+
+        // create logic processor
+        let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+        let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+        let mut proc = Processor::new(&mut input_file, &mut output_file).with_progress(&mut show_progress);
+
+        // delta is converted to VCDIFF while it is generated
+        let result = proc.process_delta(&mut signature_file);
+        progress_bar.finish();
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+        match output_file.finish() {
+            Ok(f) => finish_output_file(f, &args[4]),
+            Err(x) => {
+                eprintln!("Unable to write output file: {}, error: {}", &args[4], x);
                 process::exit(1);
             }
+        }
+    }
+    else if ( args.len() == 5 || args.len() == 6 ) && args[1] == "delta" {
+
+        take_format(&mut options, &[OutputFormat::Hdiff]);
+        let mac_key = take_mac_key(&mut options);
+        let encryption_key = take_encryption_key(&mut options);
+        let atomic = take_atomic(&mut options);
+        // file with the same length and modification time as in signature is treated as identical, without reading it
+        let quick_check = options.flag("--quick-check");
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 5);
+        options.finish(&args[1]);
+
+        // chunk size is taken from signature file header
+        let mut signature_file = open_input_file(&args[2], processor::HASH_SIZE, "signature");
+        let signature_header = read_file_header(&mut signature_file, FileKind::Signature, &args[2]);
+        authenticate_file(&mut signature_file, signature_header.as_ref(), 0, &args[2], mac_key.as_deref());
+        let chunk_size = select_chunk_size(signature_header.as_ref(), chunk_size_arg.as_ref());
+        signature_file.set_chunk_size(processor::HASH_SIZE);
+
+        // try to open files
+        let mut input_file = open_input_file(&args[3], chunk_size, "input");
+        let mut output_file = create_output_file(&args[4], atomic);
+
+        // delta file starts with header containing chunk size, metadata, length and digest of new input file
+        // delta of identical file has no data, only digest of old file which patch checks
+        // digest is calculated while delta is written, so header is written again when it is known,
+        // encrypted data authenticates header, so digest of encrypted delta is calculated before
+        let mut header = FileHeader::new(FileKind::Delta, chunk_size, FileMetadata::from_file(&args[3]).ok());
+        header.authenticated = mac_key.is_some();
+        header.encrypted = encryption_key.is_some();
+        header.identical = is_identical_file(&args[3], signature_header.as_ref(), quick_check);
+        let rewrite_header = !header.identical && !header.encrypted;
+        header.digest = if header.identical {
+            signature_header.as_ref().and_then(|header| header.digest)
+        } else if header.encrypted {
+            Some(read_file_digest(&args[3]))
+        } else {
+            Some((0, [0; processor::HASH_SIZE]))
         };
-        let mut signature_file = match InputFile::new(&args[2], processor::HASH_SIZE) {
+        let header_data = write_file_header(&header, &mut output_file);
+
+        // data following header is encrypted before it is authenticated, header is authenticated by encryption too
+        let mut output_file = match EncryptedOutput::new(output_file, encryption_key.as_ref(), &header_data) {
             Ok(f) => f,
             Err(x) => {
-                eprintln!("Unable to open signature file: {}, error: {}", &args[2], x);
+                eprintln!("Processing error: {}", x);
                 process::exit(1);
             }
         };
-        let mut output_file = match OutputFile::new(&args[4]) {
+
+        let result = if header.identical {
+            Ok(())
+        } else {
+            // create logic processor
+            let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+            let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+            let mut proc = Processor::new(&mut input_file, &mut output_file).with_progress(&mut show_progress);
+
+            // start processing input files to generate delta file
+            let result = proc.process_delta_digest(&mut signature_file);
+            progress_bar.finish();
+            result.map(|digest| if rewrite_header { header.digest = Some(digest) })
+        };
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+        let mut output_file = match output_file.finish() {
             Ok(f) => f,
             Err(x) => {
-                eprintln!("Unable to create output file: {}, error: {}", &args[4], x);
+                eprintln!("Unable to write output file: {}, error: {}", &args[4], x);
                 process::exit(1);
             }
         };
+        if rewrite_header {
+            let header_data = exit_on_error(header.write(&mut Vec::new()));
+            if !output_file.write_back(0, &header_data) {
+                abort_output_file(output_file, ProcessorError::FileWrite);
+            }
+        }
 
-        // create logic processor
-        let mut proc = Processor::new(&mut input_file, &mut output_file);
+        // MAC covers rewritten header, so it is calculated from written data
+        if let Some(key) = mac_key.as_deref() {
+            let len = exit_on_error(output_file.written_len());
+            if !append_mac(&mut output_file, key, len) {
+                abort_output_file(output_file, ProcessorError::FileWrite);
+            }
+        }
+        finish_output_file(output_file, &args[4]);
 
-        // start processing input files to generate delta file
-        if let Err(x) = proc.process_delta(&mut signature_file) {
-            eprintln!("Processing error: {}", x);
-        }        
-        
         // delta file format:
         // 0 - current chank is same as in old file
         // 1 - apply new chunk which is added after this tag
         // 2 - chunk was inserted, value of the chunk is added after this tag
         // 3 - chunk was removed
+        // 4 - chunk is copied from already written part of new file, u64 offset is added after this tag
+        // 5 - chunk of zeros, u64 length of the chunk is added after this tag
+    }
+    else if ( args.len() == 5 || args.len() == 6 ) && args[1] == "diff" {
+
+        let diff_algo = take_diff_algo(&mut options);
+        let mac_key = take_mac_key(&mut options);
+        let encryption_key = take_encryption_key(&mut options);
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 5);
+        options.finish(&args[1]);
+
+        // both files are available, so old file is read directly instead of its signature
+        let chunk_size = parse_input_chunk_size(chunk_size_arg.as_ref(), &args[2]);
+        let mut input_file = open_input_file(&args[3], chunk_size, "input");
+        let mut output_file = MacOutput::new(create_output_file(&args[4], atomic), mac_key.as_deref());
+
+        // delta file starts with header containing chunk size and metadata of new input file
+        let mut header = FileHeader::new(FileKind::Delta, chunk_size, FileMetadata::from_file(&args[3]).ok());
+        header.authenticated = mac_key.is_some();
+        header.encrypted = encryption_key.is_some();
+        header.bsdiff = diff_algo == DiffAlgo::Bsdiff;
+        let header_data = write_file_header(&header, &mut output_file);
+
+        // data following header is encrypted before it is authenticated, header is authenticated by encryption too
+        let mut output_file = match EncryptedOutput::new(output_file, encryption_key.as_ref(), &header_data) {
+            Ok(f) => f,
+            Err(x) => {
+                eprintln!("Processing error: {}", x);
+                process::exit(1);
+            }
+        };
+
+        let result = match diff_algo {
+            DiffAlgo::Hdiff => {
+                // old file is read in chunks, only its signature is kept in memory
+                let mut signature = Vec::new();
+                if let Err(x) = Processor::new(&mut open_input_file(&args[2], chunk_size, "old"), &mut signature).process_signature() {
+                    abort_output_file(output_file, x);
+                }
+
+                let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+                let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+                let mut proc = Processor::new(&mut input_file, &mut output_file).with_progress(&mut show_progress);
+                let result = proc.process_delta(&mut mem_data::MemInput::new(&signature, processor::HASH_SIZE));
+                progress_bar.finish();
+                result
+            }
+            // suffix array of old file needs both files in memory, so their size is limited
+            DiffAlgo::Bsdiff => {
+                let old_data = read_input_data(&args[2], "old", MAX_BSDIFF_INPUT_SIZE);
+                let new_data = read_input_data(&args[3], "input", MAX_BSDIFF_INPUT_SIZE);
+                process_bsdiff(&old_data, &new_data, &mut output_file)
+            }
+        };
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+        let output_file = match output_file.finish() {
+            Ok(f) => f,
+            Err(x) => {
+                eprintln!("Unable to write output file: {}, error: {}", &args[4], x);
+                process::exit(1);
+            }
+        };
+        finish_output_file(finish_mac_output(output_file, &args[4]), &args[4]);
+    }
+    else if args.len() == 5 && args[1] == "patch" && is_input_file_of(&args[3], is_rdiff_delta) {
+
+        // librsync delta has no header, so it cannot be authenticated or encrypted and metadata is not restored
+        let signer_key = take_signer_key(&mut options);
+        let atomic = take_atomic(&mut options);
+        options.finish(&args[1]);
+
+        // delta file can be wrapped in signed package
+        let mut delta_file = open_input_file(&args[3], 1, "delta");
+        exit_on_error(open_input_package(&mut delta_file, &args[3], signer_key.as_ref()));
+
+        // old file is read at positions given by delta
+        let mut old_file = open_reader(&args[2], "old");
+        let mut output_file = create_output_file(&args[4], atomic);
+        if let Err(x) = process_rdiff_patch(&mut old_file, &mut delta_file, &mut output_file) {
+            abort_output_file(output_file, format!("{}: {}", x, &args[3]));
+        }
+        finish_output_file(output_file, &args[4]);
+    }
+    else if args.len() == 5 && args[1] == "patch" && is_input_file_of(&args[3], is_vcdiff) {
+
+        // VCDIFF has no header, so it cannot be authenticated or encrypted and metadata is not restored
+        let signer_key = take_signer_key(&mut options);
+        let atomic = take_atomic(&mut options);
+        options.finish(&args[1]);
+
+        // delta file can be wrapped in signed package
+        let mut delta_file = open_input_file(&args[3], 1, "delta");
+        exit_on_error(open_input_package(&mut delta_file, &args[3], signer_key.as_ref()));
+
+        // old file is read at positions given by delta
+        let mut old_file = open_reader(&args[2], "old");
+        let mut output_file = create_output_file(&args[4], atomic);
+        if let Err(x) = process_vcdiff_patch(&mut old_file, &mut delta_file, &mut output_file) {
+            abort_output_file(output_file, format!("{}: {}", x, &args[3]));
+        }
+        finish_output_file(output_file, &args[4]);
+    }
+    else if ( args.len() == 5 || args.len() == 6 ) && args[1] == "patch" {
+
+        let metadata_options = take_metadata_options(&mut options);
+        let signer_key = take_signer_key(&mut options);
+        let mac_key = take_mac_key(&mut options);
+        let encryption_key = take_encryption_key(&mut options);
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 5);
+        options.finish(&args[1]);
+
+        // delta file can be wrapped in signed package
+        let mut delta_file = open_input_file(&args[3], 1, "delta");
+        exit_on_error(open_input_package(&mut delta_file, &args[3], signer_key.as_ref()));
+
+        // chunk size is taken from delta file header
+        let header_start = delta_file.position();
+        let header = read_file_header(&mut delta_file, FileKind::Delta, &args[3]);
+        authenticate_file(&mut delta_file, header.as_ref(), header_start, &args[3], mac_key.as_deref());
+        let chunk_size = select_chunk_size(header.as_ref(), chunk_size_arg.as_ref());
+        let mut delta_file = open_decrypted_input(delta_file, header.as_ref(), &args[3], encryption_key.as_ref());
+
+        let (output_file, result) = if header.as_ref().is_some_and(|header| header.bsdiff) {
+            // bsdiff delta reads old file at positions given by its records
+            let mut old_file = open_reader(&args[2], "old");
+            let mut output_file = create_output_file(&args[4], atomic);
+            let result = process_bsdiff_patch(&mut old_file, &mut delta_file, &mut output_file);
+            (output_file, result)
+        } else if let Some(digest) = header.as_ref().filter(|header| header.identical).and_then(|header| header.digest) {
+            // identical delta copies old file
+            let mut old_file = open_input_file(&args[2], chunk_size, "old");
+            let mut output_file = create_output_file(&args[4], atomic);
+            let result = Processor::new(&mut old_file, &mut output_file).process_identical_patch(digest, chunk_size);
+            (output_file, result)
+        } else {
+            // try to open files
+            let mut old_file = open_input_file(&args[2], chunk_size, "old");
+            let mut output_file = create_output_file(&args[4], atomic);
+
+            // create logic processor
+            let mut progress_bar = ProgressBar::new(std::io::stderr().is_terminal());
+            let mut show_progress = |progress: &Progress| progress_bar.update(progress);
+            let mut proc = Processor::new(&mut old_file, &mut output_file).with_progress(&mut show_progress);
+
+            // start applying delta file to old file
+            let result = proc.process_patch(&mut delta_file, chunk_size);
+            progress_bar.finish();
+            (output_file, result)
+        };
+        if let Err(x) = delta_file.finish() {
+            abort_output_file(output_file, format!("{}: {}", x, &args[3]));
+        }
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+
+        // metadata of new file is restored before output file replaces destination
+        let metadata = header.and_then(|header| header.metadata);
+        if let Err(x) = output_file.finish_with_metadata(metadata.as_ref(), &metadata_options) {
+            eprintln!("Unable to write output file: {}, error: {}", &args[4], x);
+            process::exit(1);
+        }
+    }
+    else if args.len() == 4 && args[1] == "keygen" {
+
+        let atomic = take_atomic(&mut options);
+        options.finish(&args[1]);
+
+        let (secret_key, public_key) = exit_on_error(generate_keys());
+
+        // secret key file is readable by owner only from its creation
+        let mut secret_key_file = match OutputFile::new_private(&args[2], atomic) {
+            Ok(f) => f,
+            Err(x) => {
+                eprintln!("Unable to create output file: {}, error: {}", &args[2], x);
+                process::exit(1);
+            }
+        };
+        if !secret_key_file.write_data(&secret_key) {
+            abort_output_file(secret_key_file, ProcessorError::FileWrite);
+        }
+        finish_output_file(secret_key_file, &args[2]);
+
+        let mut public_key_file = create_output_file(&args[3], atomic);
+        if !public_key_file.write_data(&public_key) {
+            abort_output_file(public_key_file, ProcessorError::FileWrite);
+        }
+        finish_output_file(public_key_file, &args[3]);
+    }
+    else if args.len() == 5 && args[1] == "sign" {
+
+        let atomic = take_atomic(&mut options);
+        options.finish(&args[1]);
+
+        // try to open files
+        let secret_key = exit_on_error(read_secret_key(&args[2]));
+        let mut delta_file = open_reader(&args[3], "delta");
+        let mut output_file = create_output_file(&args[4], atomic);
+
+        // delta file is wrapped in package with signature
+        if let Err(x) = sign_package(&secret_key, &mut delta_file, &mut output_file) {
+            abort_output_file(output_file, x);
+        }
+        finish_output_file(output_file, &args[4]);
+    }
+    else if args.len() == 4 && args[1] == "verify-package" {
+
+        options.finish(&args[1]);
+
+        let public_key = exit_on_error(read_public_key(&args[2]));
+        exit_on_error(verify_package_file(&args[3], &public_key));
+        println!("Package signature is valid: {}", &args[3]);
+    }
+    else if ( args.len() == 4 || args.len() == 5 ) && args[1] == "store" && args[2] == "add" {
+
+        let store = open_chunk_store(&mut options);
+        options.finish(&args[1]);
+
+        // file is stored as a version named by its digest unless name was given
+        match store.add_file(Path::new(&args[3]), args.get(4).map(String::as_str), processor::CHUNK_SIZE) {
+            Ok(added) => println!("Stored version: {}, new chunks: {} of {}", added.version, added.new_chunks, added.chunks),
+            Err(x) => {
+                eprintln!("Processing error: {}", x);
+                process::exit(1);
+            }
+        }
+    }
+    else if args.len() == 5 && args[1] == "store" && args[2] == "get" {
+
+        let store = open_chunk_store(&mut options);
+        let atomic = take_atomic(&mut options);
+        options.finish(&args[1]);
+        let mut output_file = create_output_file(&args[4], atomic);
+
+        // start rebuilding version from stored chunks
+        if let Err(x) = store.get_version(&args[3], &mut output_file) {
+            abort_output_file(output_file, x);
+        }
+        finish_output_file(output_file, &args[4]);
+    }
+    else if args.len() == 3 && args[1] == "store" && args[2] == "list" {
+
+        let store = open_chunk_store(&mut options);
+        options.finish(&args[1]);
+        for version in exit_on_error(store.versions()) {
+            println!("{}", version);
+        }
+    }
+    else if args.len() == 4 && args[1] == "store" && args[2] == "remove" {
+
+        // chunks of removed version stay in store until garbage collection
+        let store = open_chunk_store(&mut options);
+        options.finish(&args[1]);
+        exit_on_error(store.remove_version(&args[3]));
+    }
+    else if args.len() == 3 && args[1] == "store" && args[2] == "gc" {
+
+        let store = open_chunk_store(&mut options);
+        options.finish(&args[1]);
+        let collected = exit_on_error(store.collect_garbage());
+        println!("Removed {} chunks, {} bytes", collected.removed_chunks, collected.removed_bytes);
+    }
+    else if args.len() == 3 && args[1] == "store" && args[2] == "fsck" {
+
+        let store = open_chunk_store(&mut options);
+        options.finish(&args[1]);
+        let report = exit_on_error(store.check());
+        for version in &report.invalid_manifests {
+            println!("Invalid manifest: {}", version);
+        }
+        for chunk in &report.missing_chunks {
+            println!("Missing chunk: {}", chunk);
+        }
+        for chunk in &report.corrupted_chunks {
+            println!("Corrupted chunk: {}", chunk);
+        }
+        println!("Checked {} versions, {} chunks", report.versions, report.chunks);
+        if !report.is_ok() {
+            process::exit(1);
+        }
+    }
+    else if ( args.len() == 2 || args.len() == 3 ) && args[1] == "release" {
+
+        // all options of release command are required, base option can be repeated
+        // deltas are published for any client, so options authenticating or encrypting them are refused by options.finish
+        let release_bases = options.values("--base");
+        let release_target = options.value("--target");
+        let release_output = options.value("--out");
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 2);
+        options.finish(&args[1]);
+
+        let chunk_size = parse_chunk_size(chunk_size_arg.as_ref());
+        let (target_file, output_dir) = match (&release_target, &release_output) {
+            (Some(target_file), Some(output_dir)) if !release_bases.is_empty() => (target_file, output_dir),
+            _ => {
+                eprintln!("Release requires --base, --target and --out options");
+                process::exit(1);
+            }
+        };
+        let base_files: Vec<PathBuf> = release_bases.iter().map(PathBuf::from).collect();
+
+        // start generating deltas from all base files to target file
+        let entries = exit_on_error(process_release(&base_files, Path::new(target_file), Path::new(output_dir), chunk_size));
+        for entry in entries {
+            println!("{} {} bytes", entry.delta_path, entry.delta_size);
+        }
+    }
+    else if args.len() == 4 && args[1] == "tune" {
+
+        options.finish(&args[1]);
+
+        // chunk size selected automatically is tried along with common chunk sizes
+        let auto_size = parse_input_chunk_size(Some(&"auto".to_owned()), &args[2]);
+        let mut chunk_sizes = TUNE_CHUNK_SIZES.to_vec();
+        chunk_sizes.push(auto_size);
+        chunk_sizes.sort_unstable();
+        chunk_sizes.dedup();
+
+        let results = exit_on_error(tune_chunk_size(Path::new(&args[2]), Path::new(&args[3]), &chunk_sizes));
+        println!("{:>10} {:>12} {:>12} {:>12}", "Chunk size", "Signature", "Delta", "Total");
+        for result in results {
+            let auto = if result.chunk_size == auto_size { " (auto)" } else { "" };
+            println!("{:>10} {:>12} {:>12} {:>12}{}", result.chunk_size, result.signature_size, result.delta_size,
+                     result.signature_size + result.delta_size, auto);
+        }
+    }
+    else if args.len() == 5 && args[1] == "plan" {
+
+        options.finish(&args[1]);
+
+        let index = exit_on_error(read_index(Path::new(&args[2])));
+        let (current_digest, target_digest) = (parse_digest_arg(&args[3]), parse_digest_arg(&args[4]));
+
+        // find downloads with the smallest total size
+        let plan = exit_on_error(plan_update(&index, &current_digest, &target_digest));
+        if plan.steps.is_empty() {
+            println!("Current version is up to date");
+        }
+        if plan.is_full_download() {
+            println!("Full download is cheaper than deltas");
+        }
+        for step in &plan.steps {
+            match step {
+                UpdateStep::Full(entry) => println!("full {} {} bytes", entry.path, step.size()),
+                UpdateStep::Delta(entry) => println!("delta {} {} bytes", entry.delta_path, step.size())
+            }
+        }
+        println!("Total: {} bytes", plan.total_size);
+    }
+    else if ( args.len() == 4 || args.len() == 5 ) && args[1] == "signature-dir" {
+
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 4);
+        options.finish(&args[1]);
+
+        // check if chunk size was specified
+        let chunk_size = parse_chunk_size(chunk_size_arg.as_ref());
+        let mut output_file = create_output_file(&args[3], atomic);
+
+        // start processing all files from input directory
+        match process_signature_dir(Path::new(&args[2]), chunk_size, &mut output_file) {
+            Ok(skipped) => report_skipped_entries(&skipped),
+            Err(x) => abort_output_file(output_file, x)
+        }
+        finish_output_file(output_file, &args[3]);
+    }
+    else if args.len() == 5 && args[1] == "delta-dir" {
+
+        let atomic = take_atomic(&mut options);
+        options.finish(&args[1]);
+
+        // chunk size is stored in signature file
+        let mut signature_file = open_reader(&args[2], "signature");
+        let mut output_file = create_output_file(&args[4], atomic);
+
+        // start processing all files from input directory to generate delta file
+        match process_delta_dir(&mut signature_file, Path::new(&args[3]), &mut output_file) {
+            Ok(skipped) => report_skipped_entries(&skipped),
+            Err(x) => abort_output_file(output_file, x)
+        }
+        finish_output_file(output_file, &args[4]);
+    }
+    else if args.len() == 5 && args[1] == "patch-dir" {
+
+        // directory delta does not store metadata of files, so metadata flags are refused by options.finish
+        options.finish(&args[1]);
+
+        // chunk size is stored in delta file
+        let mut delta_file = open_reader(&args[3], "delta");
+
+        // start applying delta file to old directory
+        match process_patch_dir(Path::new(&args[2]), &mut delta_file, Path::new(&args[4])) {
+            Ok(skipped) => report_skipped_entries(&skipped),
+            Err(x) => {
+                eprintln!("Processing error: {}", x);
+                process::exit(1);
+            }
+        }
+    }
+    else if args.len() == 4 && args[1] == "serve" {
+
+        options.finish(&args[1]);
+
+        let listener = match std::net::TcpListener::bind(&args[2]) {
+            Ok(l) => l,
+            Err(x) => {
+                eprintln!("Unable to listen on address: {}, error: {}", &args[2], x);
+                process::exit(1);
+            }
+        };
+
+        // start serving files from root directory
+        if let Err(x) = serve(listener, Path::new(&args[3])) {
+            eprintln!("Processing error: {}", x);
+            process::exit(1);
+        }
+    }
+    else if ( args.len() == 2 || args.len() == 3 ) && args[1] == "--server" {
+
+        options.finish(&args[1]);
+
+        // sync protocol is spoken over stdin and stdout, root directory defaults to current directory
+        let root_dir = args.get(2).map(String::as_str).unwrap_or(".");
+        let mut stdin = std::io::stdin().lock();
+        let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+        if let Err(x) = serve_stream(&mut stdin, &mut stdout, Path::new(root_dir)) {
+            eprintln!("Processing error: {}", x);
+            process::exit(1);
+        }
+    }
+    else if ( args.len() == 3 || args.len() == 4 ) && args[1] == "pull" && options.peek("-e").is_some() {
+
+        // command used to start remote side of sync protocol, e.g. "ssh host hdiff --server"
+        let command = options.value("-e").unwrap_or_default();
+        options.finish(&args[1]);
+
+        // remote file has same name as local file if it was not specified
+        let local_file = Path::new(&args[2]);
+        let remote_name = remote_file_name(local_file, args.get(3));
+
+        // start replacing local file with remote file
+        match pull_command(&command, local_file, &remote_name, processor::CHUNK_SIZE) {
+            Ok(received) => println!("Received {} bytes of delta", received),
+            Err(x) => {
+                eprintln!("Processing error: {}", x);
+                process::exit(1);
+            }
+        }
+    }
+    else if ( args.len() == 4 || args.len() == 5 ) && args[1] == "pull" {
+
+        options.finish(&args[1]);
+
+        // remote file has same name as local file if it was not specified
+        let local_file = Path::new(&args[3]);
+        let remote_name = remote_file_name(local_file, args.get(4));
+
+        // start replacing local file with remote file
+        match pull(&args[2], local_file, &remote_name, processor::CHUNK_SIZE) {
+            Ok(received) => println!("Received {} bytes of delta", received),
+            Err(x) => {
+                eprintln!("Processing error: {}", x);
+                process::exit(1);
+            }
+        }
+    }
+    else if args.len() == 5 && args[1] == "update-signature" {
+
+        let signer_key = take_signer_key(&mut options);
+        let mac_key = take_mac_key(&mut options);
+        let encryption_key = take_encryption_key(&mut options);
+        let atomic = take_atomic(&mut options);
+        options.finish(&args[1]);
+
+        // chunk size is taken from old signature file header
+        let mut old_signature = open_input_file(&args[2], processor::HASH_SIZE, "signature");
+        let old_header = read_file_header(&mut old_signature, FileKind::Signature, &args[2]);
+        authenticate_file(&mut old_signature, old_header.as_ref(), 0, &args[2], mac_key.as_deref());
+        let chunk_size = select_chunk_size(old_header.as_ref(), None);
+
+        // delta file can be wrapped in signed package
+        let mut delta_file = open_input_file(&args[3], 1, "delta");
+        exit_on_error(open_input_package(&mut delta_file, &args[3], signer_key.as_ref()));
+        let header_start = delta_file.position();
+        let header = read_file_header(&mut delta_file, FileKind::Delta, &args[3]);
+        authenticate_file(&mut delta_file, header.as_ref(), header_start, &args[3], mac_key.as_deref());
+        if select_chunk_size(header.as_ref(), None) != chunk_size {
+            eprintln!("Chunk size of delta does not match chunk size of signature: {}", chunk_size);
+            process::exit(1);
+        }
+        // bsdiff delta does not keep chunks of patched file aligned
+        if header.as_ref().is_some_and(|header| header.bsdiff) {
+            eprintln!("Signature cannot be updated from bsdiff delta: {}", &args[3]);
+            process::exit(1);
+        }
+        let mut delta_file = open_decrypted_input(delta_file, header.as_ref(), &args[3], encryption_key.as_ref());
+
+        // new signature has the same header as signature created from patched file, except digest of patched file
+        let identical = header.as_ref().is_some_and(|header| header.identical);
+        let mut output_file = MacOutput::new(create_output_file(&args[4], atomic), mac_key.as_deref());
+        let mut new_header = FileHeader::updated_signature(chunk_size, header.as_ref());
+        new_header.authenticated = mac_key.is_some();
+        write_file_header(&new_header, &mut output_file);
+
+        let result = if identical {
+            copy_signature(&mut old_signature, &mut output_file)
+        } else {
+            process_signature_update(&mut old_signature, &mut delta_file, &mut output_file, chunk_size)
+        };
+        if let Err(x) = delta_file.finish() {
+            abort_output_file(output_file, format!("{}: {}", x, &args[3]));
+        }
+        if let Err(x) = result {
+            abort_output_file(output_file, x);
+        }
+        finish_output_file(finish_mac_output(output_file, &args[4]), &args[4]);
+    }
+    else if args.len() == 4 && args[1] == "sigdiff" {
+
+        let mac_key = take_mac_key(&mut options);
+        options.finish(&args[1]);
+
+        // both signatures have to be created with the same chunk size
+        let mut old_signature = open_input_file(&args[2], processor::HASH_SIZE, "signature");
+        let old_header = read_file_header(&mut old_signature, FileKind::Signature, &args[2]);
+        authenticate_file(&mut old_signature, old_header.as_ref(), 0, &args[2], mac_key.as_deref());
+        let mut new_signature = open_input_file(&args[3], processor::HASH_SIZE, "signature");
+        let new_header = read_file_header(&mut new_signature, FileKind::Signature, &args[3]);
+        authenticate_file(&mut new_signature, new_header.as_ref(), 0, &args[3], mac_key.as_deref());
+        let chunk_size = select_chunk_size(old_header.as_ref(), None);
+        if select_chunk_size(new_header.as_ref(), None) != chunk_size {
+            eprintln!("Signatures have different chunk size: {}, {}", &args[2], &args[3]);
+            process::exit(1);
+        }
+
+        // ranges are reported as chunk indexes of old and new file
+        let diff = exit_on_error(process_sigdiff(&mut old_signature, &mut new_signature, chunk_size));
+        for (change, old, new) in &diff.ranges {
+            println!("{} old chunks {}-{} new chunks {}-{}", change, old.start, old.end, new.start, new.end);
+        }
+        println!("Estimated delta size: {} bytes", diff.delta_size);
+    }
+    else if args.len() == 4 && args[1] == "tree-diff" {
+
+        options.finish(&args[1]);
+
+        let mut tree_a = exit_on_error(MerkleReader::new(open_reader(&args[2], "signature tree")));
+        let mut tree_b = exit_on_error(MerkleReader::new(open_reader(&args[3], "signature tree")));
+
+        // changed chunks are reported as byte ranges
+        let diff = exit_on_error(diff_trees(&mut tree_a, &mut tree_b));
+        let chunk_size = tree_a.chunk_size as u64;
+        for range in &diff.changed {
+            println!("Changed bytes {}-{}", range.start * chunk_size, range.end * chunk_size);
+        }
+        let changed: u64 = diff.changed.iter().map(|range| range.end - range.start).sum();
+        println!("Changed {} of {} chunks, compared {} nodes in {} rounds", changed, std::cmp::max(tree_a.chunk_count, tree_b.chunk_count), diff.compared, diff.rounds);
+    }
+    else if ( args.len() == 4 || args.len() == 5 ) && args[1] == "make-control" {
+
+        let atomic = take_atomic(&mut options);
+        let chunk_size_arg = take_chunk_size_arg(&mut options, &args, 4);
+        options.finish(&args[1]);
+
+        // control file is published in the same directory as the file, so it refers to the file by its name
+        let input_file = Path::new(&args[2]);
+        let url = remote_file_name(input_file, None);
+        let mut output_file = create_output_file(&args[3], atomic);
+        if let Err(x) = make_control(input_file, &url, parse_input_chunk_size(chunk_size_arg.as_ref(), &args[2]), &mut output_file) {
+            abort_output_file(output_file, x);
+        }
+        finish_output_file(output_file, &args[3]);
+    }
+    else if ( args.len() == 3 || args.len() == 4 ) && args[1] == "zsync-fetch" {
+
+        options.finish(&args[1]);
+
+        // local file has same name as remote file if it was not specified
+        match zsync_fetch(&args[2], args.get(3).map(Path::new)) {
+            Ok(stats) => println!("Fetched {} bytes, reused {} bytes of local file", stats.fetched, stats.reused),
+            Err(x) => {
+                eprintln!("Processing error: {}", x);
+                process::exit(1);
+            }
+        }
     } else {
         // provide application usage info
-        println!("Application usage:\nhdiff signature <input-file> <output-signature-file> [optional-chunk-size]\nhdiff delta <signature-file> <new-input-file> <output-delta-file> [optional-chunk-size]\n");
+        println!("Application usage:\n\
+                  commands writing files accept --no-atomic, <key> is --mac-key <key-file>, <enc> is --encryption-key <key-file> | --encryption-passphrase\n\
+                  hdiff signature [--format hdiff|rdiff|merkle] [<key>] [--chunk-size <size>|auto] <input-file> <output-signature-file> [optional-chunk-size|auto]\n\
+                  hdiff delta [--format hdiff|vcdiff|rdiff] [<key>] [<enc>] [--quick-check] <signature-file> <new-input-file> <output-delta-file> [optional-chunk-size]\n\
+                  hdiff update-signature [<key>] [<enc>] [--require-signature <public-key-file>] <old-signature-file> <delta-file> <output-signature-file>\n\
+                  hdiff sigdiff [<key>] <old-signature-file> <new-signature-file>\n\
+                  hdiff tree-diff <signature-tree-file> <signature-tree-file>\n\
+                  hdiff diff [--algo hdiff|bsdiff] [<key>] [<enc>] <old-input-file> <new-input-file> <output-delta-file> [optional-chunk-size|auto]\n\
+                  hdiff tune <old-input-file> <new-input-file>\n\
+                  hdiff patch [--no-mode] [--no-owner] [--no-mtime] [--no-xattrs] [<key>] [<enc>] [--require-signature <public-key-file>] <old-input-file> <delta-file> <output-file> [optional-chunk-size]\n\
+                  hdiff keygen <output-secret-key-file> <output-public-key-file>\n\
+                  hdiff sign <secret-key-file> <delta-file> <output-package-file>\n\
+                  hdiff verify-package <public-key-file> <package-file>\n\
+                  hdiff signature-dir <input-dir> <output-signature-file> [optional-chunk-size]\n\
+                  hdiff delta-dir <signature-file> <new-input-dir> <output-delta-file>\n\
+                  hdiff patch-dir <old-input-dir> <delta-file> <output-dir>\n\
+                  hdiff [--store <store-dir>] store add <input-file> [version]\n\
+                  hdiff [--store <store-dir>] store get <version> <output-file>\n\
+                  hdiff [--store <store-dir>] store list\n\
+                  hdiff [--store <store-dir>] store remove <version>\n\
+                  hdiff [--store <store-dir>] store gc\n\
+                  hdiff [--store <store-dir>] store fsck\n\
+                  hdiff release --base <base-file> [--base <base-file> ...] --target <target-file> --out <output-dir> [optional-chunk-size]\n\
+                  hdiff plan <index-file> <current-digest> <target-digest>\n\
+                  hdiff serve <listen-address> <root-dir>\n\
+                  hdiff pull <host:port> <local-file> [remote-file]\n\
+                  hdiff pull -e <remote-command> <local-file> [remote-file]\n\
+                  hdiff --server [root-dir]\n\
+                  hdiff make-control <input-file> <output-control-file> [optional-chunk-size|auto]\n\
+                  hdiff zsync-fetch <control-url> [local-file]\n");
+        process::exit(1);
+    }
+}
+
+
+// Format of files written by signature and delta commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Hdiff,
+    Vcdiff,
+    Rdiff,
+    Merkle
+}
+
+// Algorithm of diff command, which has both old and new file available
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffAlgo {
+    Hdiff,
+    Bsdiff
+}
+
+// Options which are followed by value, other known options are flags
+const VALUE_OPTIONS: [&str; 11] = ["-e", "--mac-key", "--encryption-key", "--store", "--base", "--target", "--out", "--format", "--algo", "--require-signature", "--chunk-size"];
+const FLAG_OPTIONS: [&str; 7] = ["--no-mode", "--no-owner", "--no-mtime", "--no-xattrs", "--no-atomic", "--quick-check", "--encryption-passphrase"];
+
+// Options given on command line, command takes options it uses and options left are refused
+struct Options {
+    given: Vec<(String, Option<String>)>
+}
+
+impl Options {
+
+    // Separates options from arguments, options can be given anywhere after program name
+    fn parse(args: Vec<String>) -> (Vec<String>, Self) {
+        let mut arguments = Vec::new();
+        let mut given = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if VALUE_OPTIONS.contains(&arg.as_str()) {
+                match args.next() {
+                    Some(value) => given.push((arg, Some(value))),
+                    None => {
+                        eprintln!("Missing value of option: {}", arg);
+                        process::exit(1);
+                    }
+                }
+            } else if FLAG_OPTIONS.contains(&arg.as_str()) {
+                given.push((arg, None));
+            } else if arg.starts_with("--") && !(arguments.len() == 1 && arg == "--server") {
+                eprintln!("Unknown option: {}", arg);
+                process::exit(1);
+            } else {
+                arguments.push(arg);
+            }
+        }
+        (arguments, Self { given })
+    }
+
+    // Value of option without taking it
+    fn peek(&self, name: &str) -> Option<&str> {
+        self.given.iter().find(|(option, _)| option == name).and_then(|(_, value)| value.as_deref())
+    }
+
+    // Takes flag, returns true if it was given
+    fn flag(&mut self, name: &str) -> bool {
+        let len = self.given.len();
+        self.given.retain(|(option, _)| option != name);
+        self.given.len() != len
+    }
+
+    // Takes option, it can be given only once
+    fn value(&mut self, name: &str) -> Option<String> {
+        let mut values = self.values(name);
+        if values.len() > 1 {
+            eprintln!("Option can be given only once: {}", name);
+            process::exit(1);
+        }
+        values.pop()
+    }
+
+    // Takes all values of repeated option
+    fn values(&mut self, name: &str) -> Vec<String> {
+        let (taken, left): (Vec<_>, Vec<_>) = std::mem::take(&mut self.given).into_iter().partition(|(option, _)| option == name);
+        self.given = left;
+        taken.into_iter().filter_map(|(_, value)| value).collect()
+    }
+
+    // Refuses options which were not taken by command
+    fn finish(self, command: &str) {
+        if let Some((option, _)) = self.given.first() {
+            eprintln!("Option {} cannot be used with {} command", option, command);
+            process::exit(1);
+        }
+    }
+
+}
+
+// Output files are replaced atomically unless they are written directly
+fn take_atomic(options: &mut Options) -> bool {
+    !options.flag("--no-atomic")
+}
+
+// Metadata restored by patch command
+fn take_metadata_options(options: &mut Options) -> MetadataOptions {
+    MetadataOptions {
+        mode: !options.flag("--no-mode"),
+        owner: !options.flag("--no-owner"),
+        mtime: !options.flag("--no-mtime"),
+        xattrs: !options.flag("--no-xattrs")
+    }
+}
+
+// Shared secret used to authenticate signature and delta files
+fn take_mac_key(options: &mut Options) -> Option<Vec<u8>> {
+    options.value("--mac-key").map(|file_name| read_key_file(&file_name))
+}
+
+// Secret from which key encrypting delta files is derived, passphrase is taken from environment variable
+fn take_encryption_key(options: &mut Options) -> Option<EncryptionKey> {
+    match options.value("--encryption-key") {
+        Some(file_name) => Some(EncryptionKey::from_key_file(read_key_file(&file_name))),
+        None if options.flag("--encryption-passphrase") => Some(EncryptionKey::from_passphrase(read_passphrase(), encryption::KEY_ITERATIONS)),
+        None => None
+    }
+}
+
+// Public key of signer which delta packages are required to be signed with
+fn take_signer_key(options: &mut Options) -> Option<ed25519_dalek::VerifyingKey> {
+    options.value("--require-signature").map(|file_name| exit_on_error(read_public_key(file_name)))
+}
+
+// Chunk size can be given as option instead of optional argument at given index, but not as both
+fn take_chunk_size_arg(options: &mut Options, args: &[String], index: usize) -> Option<String> {
+    match (options.value("--chunk-size"), args.get(index)) {
+        (Some(_), Some(_)) => {
+            eprintln!("Chunk size is given both as option and as argument");
+            process::exit(1);
+        }
+        (option, arg) => option.or_else(|| arg.cloned())
+    }
+}
+
+// Format of files written by signature and delta commands, VCDIFF and librsync formats are understood by other tools
+fn parse_format(format: Option<&str>) -> OutputFormat {
+    match format {
+        None | Some("hdiff") => OutputFormat::Hdiff,
+        Some("vcdiff") => OutputFormat::Vcdiff,
+        Some("rdiff") => OutputFormat::Rdiff,
+        Some("merkle") => OutputFormat::Merkle,
+        Some(format) => {
+            eprintln!("Unknown delta format: {}", format);
+            process::exit(1);
+        }
+    }
+}
+
+fn peek_format(options: &Options) -> OutputFormat {
+    parse_format(options.peek("--format"))
+}
+
+// Takes format option, formats which command cannot write are refused
+fn take_format(options: &mut Options, allowed: &[OutputFormat]) {
+    if let Some(format) = options.value("--format") {
+        if !allowed.contains(&parse_format(Some(&format))) {
+            eprintln!("Format {} cannot be used with this command", format);
+            process::exit(1);
+        }
+    }
+}
+
+// Algorithm used by diff command, bsdiff suits executable binaries with shifted addresses
+fn take_diff_algo(options: &mut Options) -> DiffAlgo {
+    match options.value("--algo").as_deref() {
+        None | Some("hdiff") => DiffAlgo::Hdiff,
+        Some("bsdiff") => DiffAlgo::Bsdiff,
+        Some(algo) => {
+            eprintln!("Unknown diff algorithm: {}", algo);
+            process::exit(1);
+        }
+    }
+}
+
+// Parses optional chunk size argument, default chunk size is used if it was not specified
+fn parse_chunk_size(arg: Option<&String>) -> usize {
+    match arg {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(v) if v > 0 && v <= MAX_CHUNK_SIZE => v,
+            _ => {
+                eprintln!("Wrong value of chunk size: {}, it has to be between 1 and {}", arg, MAX_CHUNK_SIZE);
+                process::exit(1);
+            }
+        },
+        None => processor::CHUNK_SIZE
+    }
+}
+
+// Chunk size of input file, auto selects it from length of the file
+fn parse_input_chunk_size(arg: Option<&String>, file_name: &str) -> usize {
+    if arg.is_none_or(|arg| arg != "auto") {
+        return parse_chunk_size(arg)
+    }
+    match std::fs::metadata(file_name) {
+        Ok(metadata) => auto_chunk_size(metadata.len()),
+        Err(x) => {
+            eprintln!("Unable to open input file: {}, error: {}", file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+// Chunk size stored in file header is used, argument is required to match it
+// files created without header use chunk size from argument
+fn select_chunk_size(header: Option<&FileHeader>, arg: Option<&String>) -> usize {
+    match header {
+        Some(header) => {
+            if arg.is_some() && parse_chunk_size(arg) != header.chunk_size {
+                eprintln!("Chunk size does not match chunk size stored in file: {}", header.chunk_size);
+                process::exit(1);
+            }
+            header.chunk_size
+        }
+        None => parse_chunk_size(arg)
+    }
+}
+
+fn read_file_header(input_file: &mut InputFile, kind: FileKind, file_name: &str) -> Option<FileHeader> {
+    match FileHeader::read(input_file) {
+        Ok(Some(header)) if header.kind != kind => {
+            eprintln!("Wrong type of file: {}", file_name);
+            process::exit(1);
+        }
+        Ok(header) => header,
+        Err(x) => {
+            eprintln!("Unable to read file: {}, error: {}", file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+// Input file has to be authenticated if MAC key was given, authenticated file requires MAC key
+fn authenticate_file(input_file: &mut InputFile, header: Option<&FileHeader>, header_start: u64, file_name: &str, key: Option<&[u8]>) {
+    exit_on_error(authenticate_input_file(input_file, header, header_start, file_name, key));
+}
+
+// Errors which already describe failing file are printed as they are
+fn exit_on_error<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(x) => {
+            eprintln!("{}", x);
+            process::exit(1);
+        }
+    }
+}
+
+// Encrypted input file requires encryption key and encryption key requires encrypted file
+fn open_decrypted_input(input_file: InputFile, header: Option<&FileHeader>, file_name: &str, key: Option<&EncryptionKey>) -> DecryptedInput<InputFile> {
+    let encrypted = header.map(|header| header.encrypted).unwrap_or(false);
+    if encrypted && key.is_none() {
+        eprintln!("File is encrypted, encryption key is required: {}", file_name);
+        process::exit(1);
+    }
+    if !encrypted && key.is_some() {
+        eprintln!("File is not encrypted: {}", file_name);
+        process::exit(1);
+    }
+    // header is parsed from canonical form, so written again it gives the same associated data
+    let associated_data = match header.map(|header| header.write(&mut Vec::new())) {
+        Some(Ok(data)) => data,
+        Some(Err(x)) => {
+            eprintln!("Unable to read file: {}, error: {}", file_name, x);
+            process::exit(1);
+        }
+        None => Vec::new()
+    };
+    match DecryptedInput::new(input_file, key, &associated_data) {
+        Ok(f) => f,
+        Err(x) => {
+            eprintln!("Unable to read file: {}, error: {}", file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+fn read_key_file(file_name: &str) -> Vec<u8> {
+    match std::fs::read(file_name) {
+        Ok(key) if !key.is_empty() => key,
+        Ok(_) => {
+            eprintln!("Key file is empty: {}", file_name);
+            process::exit(1);
+        }
+        Err(x) => {
+            eprintln!("Unable to read key file: {}, error: {}", file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+fn read_passphrase() -> Vec<u8> {
+    match env::var("HDIFF_PASSPHRASE") {
+        Ok(passphrase) if !passphrase.is_empty() => passphrase.into_bytes(),
+        _ => {
+            eprintln!("Passphrase has to be set in HDIFF_PASSPHRASE environment variable");
+            process::exit(1);
+        }
+    }
+}
+
+fn write_file_header<S: ProcessorDataOutput>(header: &FileHeader, output_file: &mut S) -> Vec<u8> {
+    match header.write(output_file) {
+        Ok(data) => data,
+        Err(x) => {
+            eprintln!("Processing error: {}", x);
+            process::exit(1);
+        }
+    }
+}
+
+fn remote_file_name(local_file: &Path, arg: Option<&String>) -> String {
+    match arg {
+        Some(name) => name.clone(),
+        None => local_file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+}
+
+fn open_input_file(file_name: &str, chunk_size: usize, kind: &str) -> InputFile {
+    match InputFile::new(file_name, chunk_size) {
+        Ok(f) => f,
+        Err(x) => {
+            eprintln!("Unable to open {} file: {}, error: {}", kind, file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+// Files of other tools are recognized by their beginning, delta can be wrapped in signed package too
+fn is_input_file_of(file_name: &str, check: fn(&mut InputFile) -> bool) -> bool {
+    match InputFile::new(file_name, 1) {
+        Ok(mut input_file) => open_input_package(&mut input_file, file_name, None).is_ok() && check(&mut input_file),
+        Err(_) => false
+    }
+}
+
+fn open_reader(file_name: &str, kind: &str) -> std::io::BufReader<std::fs::File> {
+    match std::fs::File::open(file_name) {
+        Ok(f) => std::io::BufReader::new(f),
+        Err(x) => {
+            eprintln!("Unable to open {} file: {}, error: {}", kind, file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+// Length and digest of whole input file
+fn read_file_digest(file_name: &str) -> (u64, [u8; processor::HASH_SIZE]) {
+    let mut input_file = open_input_file(file_name, 64 * 1024, "input");
+    let digest = calculate_data_digest(&mut input_file);
+    if input_file.remaining_len() != 0 {
+        eprintln!("Unable to read input file: {}", file_name);
+        process::exit(1);
+    }
+    (input_file.total_len(), digest)
+}
+
+// New file is identical to old file if its length and digest are the same as in signature
+// with quick check the same length and modification time are enough
+fn is_identical_file(file_name: &str, signature_header: Option<&FileHeader>, quick_check: bool) -> bool {
+    let (len, digest) = match signature_header.and_then(|header| header.digest) {
+        Some(digest) => digest,
+        None => return false
+    };
+    if std::fs::metadata(file_name).map(|metadata| metadata.len()).ok() != Some(len) {
+        return false
+    }
+    let mtime = signature_header.and_then(|header| header.metadata.as_ref()).and_then(|metadata| metadata.mtime);
+    if quick_check && mtime.is_some() && FileMetadata::from_file(file_name).ok().and_then(|metadata| metadata.mtime) == mtime {
+        return true
+    }
+    read_file_digest(file_name) == (len, digest)
+}
+
+// Only regular files are stored in directory signature and delta files, other entries are reported
+fn report_skipped_entries(skipped: &[String]) {
+    for path in skipped {
+        eprintln!("Skipped {}: only regular files are processed", path);
+    }
+}
+
+// Reads whole file to memory, file larger than max_len is refused before it is read
+fn read_input_data(file_name: &str, kind: &str, max_len: u64) -> Vec<u8> {
+    let result = std::fs::File::open(file_name).and_then(|file| {
+        if file.metadata()?.len() > max_len {
+            return Err(std::io::Error::other(ProcessorError::InputTooLarge))
+        }
+        let mut data = Vec::new();
+        file.take(max_len + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > max_len {
+            return Err(std::io::Error::other(ProcessorError::InputTooLarge))
+        }
+        Ok(data)
+    });
+    match result {
+        Ok(data) => data,
+        Err(x) => {
+            eprintln!("Unable to open {} file: {}, error: {}", kind, file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_digest_arg(arg: &str) -> [u8; processor::HASH_SIZE] {
+    match parse_digest(arg) {
+        Some(digest) => digest,
+        None => {
+            eprintln!("Wrong value of digest: {}", arg);
+            process::exit(1);
+        }
+    }
+}
+
+// Directory of chunk store is given by option
+fn open_chunk_store(options: &mut Options) -> ChunkStore {
+    let store_dir = options.value("--store").unwrap_or_else(|| ".hdiff-store".to_owned());
+    match ChunkStore::open(Path::new(&store_dir)) {
+        Ok(s) => s,
+        Err(x) => {
+            eprintln!("Unable to open chunk store: {}, error: {}", store_dir, x);
+            process::exit(1);
+        }
+    }
+}
+
+fn create_output_file(file_name: &str, atomic: bool) -> OutputFile {
+    let output_file = if atomic { OutputFile::new(file_name) } else { OutputFile::new_direct(file_name) };
+    match output_file {
+        Ok(f) => f,
+        Err(x) => {
+            eprintln!("Unable to create output file: {}, error: {}", file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+// Output file is dropped before exiting, so its destination stays unchanged
+fn abort_output_file<S>(output_file: S, error: impl std::fmt::Display) -> ! {
+    drop(output_file);
+    eprintln!("Processing error: {}", error);
+    process::exit(1);
+}
+
+// MAC trailer is written after all data of output file
+fn finish_mac_output(output_file: MacOutput<OutputFile>, file_name: &str) -> OutputFile {
+    match output_file.finish() {
+        Ok(f) => f,
+        Err(x) => {
+            eprintln!("Unable to write output file: {}, error: {}", file_name, x);
+            process::exit(1);
+        }
+    }
+}
+
+fn finish_output_file(output_file: OutputFile, file_name: &str) {
+    if let Err(x) = output_file.finish() {
+        eprintln!("Unable to write output file: {}, error: {}", file_name, x);
         process::exit(1);
     }
 }
@@ -0,0 +1,63 @@
+use super::processor::*;
+
+
+
+// Input data kept in memory, used for signatures and deltas embedded in other files
+pub struct MemInput<'a> {
+    data: &'a [u8],
+    location: usize,
+    chunk_size: usize,
+    last_read_size: usize
+}
+
+impl<'a> MemInput<'a> {
+
+    pub fn new(data: &'a [u8], chunk_size: usize) -> Self {
+        Self { data, location: 0, chunk_size, last_read_size: 0 }
+    }
+
+}
+
+impl ProcessorDataInput for MemInput<'_> {
+    fn get_next_data(&mut self) -> &[u8] {
+        let end = std::cmp::min(self.location + self.chunk_size, self.data.len());
+        let ret = &self.data[self.location..end];
+        self.last_read_size = ret.len();
+        self.location = end;
+        ret
+    }
+
+    fn move_back_last_read(&mut self) -> bool {
+        self.location -= self.last_read_size;
+        self.last_read_size = 0;
+        true
+    }
+
+    fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
+
+    fn total_len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn remaining_len(&self) -> u64 {
+        (self.data.len() - self.location) as u64
+    }
+}
+
+// Output data collected in memory
+impl ProcessorDataOutput for Vec<u8> {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        self.extend_from_slice(data);
+        true
+    }
+
+    fn read_back(&mut self, offset: u64, data: &mut [u8]) -> bool {
+        let start = offset as usize;
+        match start.checked_add(data.len()).and_then(|end| self.get(start..end)) {
+            Some(written) => { data.copy_from_slice(written); true }
+            None => false
+        }
+    }
+}
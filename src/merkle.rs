@@ -0,0 +1,325 @@
+use std::{error::Error, io::{Read, Seek, SeekFrom}, ops::Range};
+use sha2::{Sha256, Digest};
+use super::processor::*;
+use super::stream_data::*;
+use super::file_header::*;
+
+// Number of children of every inner node of the tree
+pub const MERKLE_FANOUT: usize = 16;
+
+// Inner nodes are hashed with prefix, so they differ from hashes of chunks with the same data
+const NODE_PREFIX: u8 = 1;
+
+// Fanout is stored in one byte, reader checks it against the same limits
+const MIN_FANOUT: usize = 2;
+const MAX_FANOUT: usize = u8::MAX as usize;
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum MerkleError {
+    InvalidTree,
+    TreeMismatch
+}
+impl std::fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MerkleError::InvalidTree => write!(f, "Signature tree file is malformed"),
+            MerkleError::TreeMismatch => write!(f, "Signature trees have different chunk size or fanout")
+        }
+    }
+}
+impl Error for MerkleError {}
+
+// Output building Merkle tree from chunk hashes written by signature processing
+// tree signature format, written after header:
+// u8 fanout, u64 number of chunks, chunk hashes, followed by levels of inner nodes from the lowest one up to the root
+// every inner node is SHA-256 of prefix and hashes of its children, last node of a level can have less children
+// chunk hashes are written as they come, inner nodes are kept in memory until the output is finished
+pub struct MerkleOutput<S> {
+    output_file: S,
+    fanout: usize,
+    chunk_count: u64,
+    written_chunks: u64,
+    hash: Vec<u8>,
+    children: Vec<[u8; HASH_SIZE]>,
+    nodes: Vec<[u8; HASH_SIZE]>
+}
+
+impl<S: ProcessorDataOutput> MerkleOutput<S> {
+
+    // Number of chunks has to be known before chunk hashes are written
+    pub fn new(mut output_file: S, fanout: usize, chunk_count: u64) -> Result<Self, ProcessorError> {
+        if !(MIN_FANOUT..=MAX_FANOUT).contains(&fanout) {
+            return Err(ProcessorError::InvalidSignature)
+        }
+        if !output_file.write_data(&[fanout as u8]) || !output_file.write_data(&chunk_count.to_le_bytes()) {
+            return Err(ProcessorError::FileWrite)
+        }
+        Ok(Self { output_file, fanout, chunk_count, written_chunks: 0, hash: Vec::new(), children: Vec::new(), nodes: Vec::new() })
+    }
+
+    // Writes inner nodes of the tree
+    pub fn finish(mut self) -> Result<S, ProcessorError> {
+        if !self.hash.is_empty() || self.written_chunks != self.chunk_count {
+            return Err(ProcessorError::InvalidSignature)
+        }
+        // tree with single chunk has no inner nodes
+        if self.chunk_count <= 1 {
+            return Ok(self.output_file)
+        }
+        if !self.children.is_empty() {
+            self.nodes.push(node_hash(&self.children));
+        }
+
+        let mut level = self.nodes;
+        loop {
+            if !level.iter().all(|node| self.output_file.write_data(node)) {
+                return Err(ProcessorError::FileWrite)
+            }
+            if level.len() <= 1 {
+                return Ok(self.output_file)
+            }
+            level = level.chunks(self.fanout).map(node_hash).collect();
+        }
+    }
+}
+
+impl<S: ProcessorDataOutput> ProcessorDataOutput for MerkleOutput<S> {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        if !self.output_file.write_data(data) {
+            return false
+        }
+        // hashes can be written in parts
+        for byte in data {
+            self.hash.push(*byte);
+            if let Ok(hash) = <[u8; HASH_SIZE]>::try_from(self.hash.as_slice()) {
+                self.hash.clear();
+                self.written_chunks += 1;
+                self.children.push(hash);
+                if self.children.len() == self.fanout {
+                    self.nodes.push(node_hash(&self.children));
+                    self.children.clear();
+                }
+            }
+        }
+        true
+    }
+}
+
+// Tree signature file, nodes are read on demand, so only compared parts of the tree are read
+pub struct MerkleReader<R> {
+    reader: R,
+    pub chunk_size: usize,
+    pub fanout: usize,
+    pub chunk_count: u64,
+    // offset in file and number of nodes of every level, first level are chunk hashes
+    levels: Vec<(u64, u64)>
+}
+
+impl<R: Read + Seek> MerkleReader<R> {
+
+    pub fn new(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let chunk_size = match FileHeader::read(&mut StreamInput::new(&mut reader, 1)) {
+            Ok(Some(header)) if header.kind == FileKind::Tree && !header.authenticated && !header.encrypted => header.chunk_size,
+            _ => return Err(Box::new(MerkleError::InvalidTree))
+        };
+        let mut data = [0u8; 9];
+        reader.read_exact(&mut data).map_err(|_| MerkleError::InvalidTree)?;
+        let fanout = data[0] as usize;
+        let chunk_count = u64::from_le_bytes(data[1..9].try_into().unwrap());
+        if !(MIN_FANOUT..=MAX_FANOUT).contains(&fanout) {
+            return Err(Box::new(MerkleError::InvalidTree))
+        }
+
+        let mut offset = reader.stream_position()?;
+        let mut levels = vec![(offset, chunk_count)];
+        let mut len = chunk_count;
+        while len > 1 {
+            offset = len.checked_mul(HASH_SIZE as u64).and_then(|size| offset.checked_add(size)).ok_or(MerkleError::InvalidTree)?;
+            len = len.div_ceil(fanout as u64);
+            levels.push((offset, len));
+        }
+
+        // file has to contain all levels
+        let end = offset + len * HASH_SIZE as u64;
+        if reader.seek(SeekFrom::End(0))? != end {
+            return Err(Box::new(MerkleError::InvalidTree))
+        }
+        Ok(Self { reader, chunk_size, fanout, chunk_count, levels })
+    }
+
+    // Number of levels including chunk hashes
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    // Node of given level, level 0 are chunk hashes, None is returned for nodes outside of the tree
+    pub fn node(&mut self, level: usize, index: u64) -> Result<Option<[u8; HASH_SIZE]>, Box<dyn Error>> {
+        let (offset, len) = match self.levels.get(level) {
+            Some(level) => *level,
+            None => return Ok(None)
+        };
+        if index >= len {
+            return Ok(None)
+        }
+        let mut node = [0u8; HASH_SIZE];
+        self.reader.seek(SeekFrom::Start(offset + index * HASH_SIZE as u64))?;
+        self.reader.read_exact(&mut node)?;
+        Ok(Some(node))
+    }
+}
+
+// Result of tree comparison, ranges are chunk indexes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeDiff {
+    pub changed: Vec<Range<u64>>,
+    pub rounds: usize,
+    pub compared: u64
+}
+
+// Compares trees top-down, children are compared only for nodes which differ
+// every round corresponds to one exchange of nodes between two parties, so changes are found in logarithmic rounds
+// comparison starts at the highest level present in both trees
+pub fn diff_trees<R1, R2>(a: &mut MerkleReader<R1>, b: &mut MerkleReader<R2>) -> Result<TreeDiff, Box<dyn Error>>
+    where R1: Read + Seek, R2: Read + Seek
+{
+    if a.chunk_size != b.chunk_size || a.fanout != b.fanout {
+        return Err(Box::new(MerkleError::TreeMismatch))
+    }
+    let fanout = a.fanout as u64;
+
+    let level_lens: Vec<u64> = a.levels.iter().zip(&b.levels).map(|(a, b)| std::cmp::max(a.1, b.1)).collect();
+    let mut level = std::cmp::min(a.depth(), b.depth()) - 1;
+    let mut pending: Vec<u64> = (0..level_lens[level]).collect();
+    let mut diff = TreeDiff { changed: Vec::new(), rounds: 0, compared: 0 };
+    loop {
+        diff.rounds += 1;
+        let mut changed = Vec::new();
+        for index in pending {
+            diff.compared += 1;
+            if a.node(level, index)? != b.node(level, index)? {
+                changed.push(index);
+            }
+        }
+
+        if level == 0 || changed.is_empty() {
+            for index in changed {
+                match diff.changed.last_mut() {
+                    Some(range) if range.end == index => range.end += 1,
+                    _ => diff.changed.push(index..index + 1)
+                }
+            }
+            return Ok(diff)
+        }
+
+        level -= 1;
+        let child_len = level_lens[level];
+        pending = changed.iter().flat_map(|index| index * fanout..std::cmp::min((index + 1) * fanout, child_len)).collect();
+    }
+}
+
+fn node_hash(children: &[[u8; HASH_SIZE]]) -> [u8; HASH_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    for child in children {
+        hasher.update(child);
+    }
+    hasher.finalize().into()
+}
+
+
+// Merkle tree tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mem_data::*;
+
+    fn tree(data: &[u8], chunk_size: usize, fanout: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        FileHeader::new(FileKind::Tree, chunk_size, None).write(&mut output).unwrap();
+        let mut tree_output = MerkleOutput::new(output, fanout, data.len().div_ceil(chunk_size) as u64).unwrap();
+        Processor::new(&mut MemInput::new(data, chunk_size), &mut tree_output).process_signature().unwrap();
+        tree_output.finish().unwrap()
+    }
+
+    fn tree_bytes(data: &[u8], chunk_size: usize) -> Vec<u8> {
+        tree(data, chunk_size, MERKLE_FANOUT)
+    }
+
+    fn reader(tree: &[u8]) -> MerkleReader<std::io::Cursor<&[u8]>> {
+        MerkleReader::new(std::io::Cursor::new(tree)).unwrap()
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        // merkle tree test
+        // scenario: inner nodes are hashes of their children, last node of level has less children
+
+        let data = [1,2,3,4,5,6,7,8,9,10];
+        let output = tree(&data, 2, 2);
+        let mut tree = reader(&output);
+        assert_eq!( (tree.chunk_size, tree.fanout, tree.chunk_count, tree.depth()), (2, 2, 5, 4) );
+
+        let chunks: Vec<_> = data.chunks(2).map(calculate_chunk_hash).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            assert_eq!( tree.node(0, index as u64).unwrap(), Some(*chunk) );
+        }
+        let level_1 = [node_hash(&chunks[0..2]), node_hash(&chunks[2..4]), node_hash(&chunks[4..5])];
+        let level_2 = [node_hash(&level_1[0..2]), node_hash(&level_1[2..3])];
+        assert_eq!( tree.node(1, 2).unwrap(), Some(level_1[2]) );
+        assert_eq!( tree.node(2, 1).unwrap(), Some(level_2[1]) );
+        assert_eq!( tree.node(3, 0).unwrap(), Some(node_hash(&level_2)) );
+        assert_eq!( tree.node(1, 3).unwrap(), None );
+
+        assert_eq!( reader(&tree_bytes(&[1], 2)).node(0, 0).unwrap(), Some(calculate_chunk_hash(&[1])) );
+        assert_eq!( reader(&tree_bytes(&[1], 2)).depth(), 1 );
+        assert_eq!( reader(&tree_bytes(&[], 2)).node(0, 0).unwrap(), None );
+    }
+
+    #[test]
+    fn test_merkle_diff() {
+        // merkle tree test
+        // scenario: changed chunks are found by comparing only nodes on paths to them
+
+        let old: Vec<u8> = (0..65536u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut new = old.clone();
+        new[1000] ^= 1;
+        new[40000] ^= 1;
+        new[40100] ^= 1;
+
+        let old_tree = tree_bytes(&old, 64);
+        let new_tree = tree_bytes(&new, 64);
+        let diff = diff_trees(&mut reader(&old_tree), &mut reader(&new_tree)).unwrap();
+        assert_eq!( diff.changed, [15..16, 625..627] );
+        assert_eq!( diff.rounds, 4 );
+        assert!( diff.compared < 100 );
+
+        let diff = diff_trees(&mut reader(&old_tree), &mut reader(&old_tree)).unwrap();
+        assert_eq!( diff, TreeDiff { changed: Vec::new(), rounds: 1, compared: 1 } );
+
+        // trees of different depth are compared from the highest common level
+        let diff = diff_trees(&mut reader(&old_tree), &mut reader(&tree_bytes(&old[..640], 64))).unwrap();
+        assert_eq!( diff.changed, vec![Range { start: 10, end: 1024 }] );
+    }
+
+    #[test]
+    fn test_merkle_invalid() {
+        // merkle tree test
+        // scenario: truncated tree, wrong number of chunks, fanout which does not fit in one byte and trees with different chunk size are refused
+
+        let output = tree_bytes(&[1,2,3,4,5,6,7,8,9], 2);
+        assert!( MerkleReader::new(std::io::Cursor::new(&output[..output.len() - 1])).is_err() );
+        assert!( MerkleReader::new(std::io::Cursor::new(&output[..11])).is_err() );
+
+        let mut tree_output = MerkleOutput::new(Vec::new(), 2, 3).unwrap();
+        assert!( tree_output.write_data(&[0; HASH_SIZE]) );
+        assert!( tree_output.finish().is_err() );
+        assert!( MerkleOutput::new(Vec::new(), 1, 3).is_err() );
+        assert!( MerkleOutput::new(Vec::new(), MAX_FANOUT + 1, 3).is_err() );
+        assert_eq!( reader(&tree(&[1,2,3,4,5,6,7,8,9], 2, MAX_FANOUT)).fanout, MAX_FANOUT );
+
+        let other = tree_bytes(&[1,2,3,4,5,6,7,8,9], 4);
+        assert!( diff_trees(&mut reader(&output), &mut reader(&other)).is_err() );
+    }
+}
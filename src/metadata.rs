@@ -0,0 +1,137 @@
+use std::{error::Error, path::Path};
+
+
+
+// POSIX metadata of a file, fields which are not available are empty
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub mode: Option<u32>,
+    pub owner: Option<(u32, u32)>, // uid, gid
+    pub mtime: Option<(i64, u32)>, // seconds since epoch, nanoseconds
+    pub xattrs: Option<Vec<(Vec<u8>, Vec<u8>)>> // names and values of extended attributes
+}
+
+// Kinds of metadata which are restored, all are restored by default
+// ownership is restored only by root, other users cannot give files away
+pub struct MetadataOptions {
+    pub mode: bool,
+    pub owner: bool,
+    pub mtime: bool,
+    pub xattrs: bool
+}
+
+impl Default for MetadataOptions {
+    fn default() -> Self {
+        Self { mode: true, owner: true, mtime: true, xattrs: true }
+    }
+}
+
+// Custom error codes
+#[derive(Debug)]
+pub enum MetadataError {
+    Mode(std::io::Error),
+    Owner(std::io::Error),
+    Mtime(std::io::Error),
+    Xattr(String, std::io::Error)
+}
+impl std::fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MetadataError::Mode(x) => write!(f, "Unable to restore file mode: {}", x),
+            MetadataError::Owner(x) => write!(f, "Unable to restore file ownership: {} (use --no-owner to skip it)", x),
+            MetadataError::Mtime(x) => write!(f, "Unable to restore modification time: {}", x),
+            MetadataError::Xattr(name, x) => write!(f, "Unable to restore extended attribute {}: {} (use --no-xattrs to skip it)", name, x)
+        }
+    }
+}
+impl Error for MetadataError {}
+
+
+impl FileMetadata {
+
+    // Reads metadata of a file
+    #[cfg(unix)]
+    pub fn from_file<P: AsRef<Path>>(file_name: P) -> Result<Self, Box<dyn Error>> {
+        use std::os::unix::{fs::MetadataExt, ffi::OsStringExt};
+
+        let metadata = std::fs::metadata(&file_name)?;
+
+        // extended attributes are skipped if file system does not support them
+        let mut xattrs = Vec::new();
+        if let Ok(names) = xattr::list(&file_name) {
+            for name in names {
+                if let Ok(Some(value)) = xattr::get(&file_name, &name) {
+                    xattrs.push((name.into_vec(), value));
+                }
+            }
+        }
+
+        Ok( Self {
+            mode: Some(metadata.mode() & 0o7777),
+            owner: Some((metadata.uid(), metadata.gid())),
+            mtime: Some((metadata.mtime(), metadata.mtime_nsec() as u32)),
+            xattrs: Some(xattrs)
+        } )
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_file<P: AsRef<Path>>(_file_name: P) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::default())
+    }
+
+    // Restores metadata of a file, fields not selected in options are skipped
+    #[cfg(unix)]
+    pub fn apply<P: AsRef<Path>>(&self, file_name: P, options: &MetadataOptions) -> Result<(), MetadataError> {
+        use std::os::unix::{fs::{MetadataExt, PermissionsExt}, ffi::OsStrExt};
+
+        let is_root = unsafe { libc::geteuid() } == 0;
+
+        // ownership is changed first as it can clear setuid and setgid bits
+        // it is skipped if it already matches or if command does not run as root
+        if let (Some((uid, gid)), true) = (self.owner, options.owner) {
+            let current = std::fs::metadata(&file_name).map_err(MetadataError::Owner)?;
+            if is_root && (current.uid(), current.gid()) != (uid, gid) {
+                std::os::unix::fs::chown(&file_name, Some(uid), Some(gid)).map_err(MetadataError::Owner)?;
+            }
+        }
+        // attributes of security and trusted namespaces can be set only by root, e.g. SELinux labels, other users skip them
+        if let (Some(xattrs), true) = (&self.xattrs, options.xattrs) {
+            for (name, value) in xattrs.iter().filter(|(name, _)| is_root || !is_privileged_xattr(name)) {
+                let name = std::ffi::OsStr::from_bytes(name);
+                xattr::set(&file_name, name, value)
+                    .map_err(|x| MetadataError::Xattr(name.to_string_lossy().into_owned(), x))?;
+            }
+        }
+        if let (Some((seconds, nanoseconds)), true) = (self.mtime, options.mtime) {
+            let time = to_system_time(seconds, nanoseconds);
+            std::fs::File::open(&file_name).and_then(|file| file.set_modified(time)).map_err(MetadataError::Mtime)?;
+        }
+        if let (Some(mode), true) = (self.mode, options.mode) {
+            std::fs::set_permissions(&file_name, std::fs::Permissions::from_mode(mode)).map_err(MetadataError::Mode)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply<P: AsRef<Path>>(&self, file_name: P, options: &MetadataOptions) -> Result<(), MetadataError> {
+        if let (Some((seconds, nanoseconds)), true) = (self.mtime, options.mtime) {
+            let time = to_system_time(seconds, nanoseconds);
+            std::fs::File::options().write(true).open(&file_name).and_then(|file| file.set_modified(time)).map_err(MetadataError::Mtime)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn is_privileged_xattr(name: &[u8]) -> bool {
+    name.starts_with(b"security.") || name.starts_with(b"trusted.")
+}
+
+fn to_system_time(seconds: i64, nanoseconds: u32) -> std::time::SystemTime {
+    let nanoseconds = std::time::Duration::from_nanos(nanoseconds as u64);
+    if seconds >= 0 {
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds as u64) + nanoseconds
+    } else {
+        std::time::UNIX_EPOCH - std::time::Duration::from_secs(seconds.unsigned_abs()) + nanoseconds
+    }
+}
@@ -1,25 +1,334 @@
-use std::{error::Error, io::Write};
+use std::{error::Error, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
 use super::processor::*;
+use super::metadata::*;
 
 
 
 pub struct OutputFile {
-    writer: std::io::BufWriter<std::fs::File>
+    writer: std::io::BufWriter<std::fs::File>,
+    file_name: PathBuf,
+    temp_file_name: Option<PathBuf>, // file renamed over destination when output is finished
+    private: bool, // permissions of replaced destination are not taken by private output
+    hole_len: u64 // zeros not written yet, they are left as a hole when next data is written
 }
 
 impl OutputFile {
-    
-    pub fn new(file_name: &str) -> Result<Self, Box<dyn Error>> {
-        let file = std::fs::File::create(file_name)?;
+
+    // Output is written to a temporary file, destination is replaced only when output is finished
+    pub fn new<P: AsRef<Path>>(file_name: P) -> Result<Self, Box<dyn Error>> {
+        let file_name = file_name.as_ref().to_path_buf();
+        let (file, temp_file_name) = create_temp_file(&file_name, false)?;
+        let writer = std::io::BufWriter::new(file);
+        Ok( Self { writer, file_name, temp_file_name: Some(temp_file_name), private: false, hole_len: 0 } )
+    }
+
+    // Output readable by owner only, e.g. secret key, file is created with restricted permissions before any data is written
+    // output written directly requires new file, so data never goes to existing file with other permissions
+    pub fn new_private<P: AsRef<Path>>(file_name: P, atomic: bool) -> Result<Self, Box<dyn Error>> {
+        let file_name = file_name.as_ref().to_path_buf();
+        let (file, temp_file_name) = if atomic {
+            let (file, temp_file_name) = create_temp_file(&file_name, true)?;
+            (file, Some(temp_file_name))
+        } else {
+            (open_options(true).create_new(true).open(&file_name)?, None)
+        };
+        let writer = std::io::BufWriter::new(file);
+        Ok( Self { writer, file_name, temp_file_name, private: true, hole_len: 0 } )
+    }
+
+    // Output is written directly to destination, used for special files which cannot be renamed over
+    pub fn new_direct<P: AsRef<Path>>(file_name: P) -> Result<Self, Box<dyn Error>> {
+        // opened for reading too, so delta can copy data already written to output
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&file_name)?;
         let writer = std::io::BufWriter::new(file);
-        Ok( Self { writer } )
+        Ok( Self { writer, file_name: file_name.as_ref().to_path_buf(), temp_file_name: None, private: false, hole_len: 0 } )
+    }
+
+    // Writes all data to disk and replaces destination file
+    // dropping output file without finishing it leaves destination file unchanged
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        self.finish_with_metadata(None, &MetadataOptions::default())
+    }
+
+    // Same as finish, metadata is restored before destination file is replaced
+    pub fn finish_with_metadata(mut self, metadata: Option<&FileMetadata>, options: &MetadataOptions) -> Result<(), Box<dyn Error>> {
+        self.write_hole()?;
+        self.writer.flush()?;
+
+        let written_file_name = self.temp_file_name.as_ref().unwrap_or(&self.file_name);
+        if let (Some(temp_file_name), false) = (&self.temp_file_name, self.private) {
+            keep_permissions(&self.file_name, temp_file_name)?;
+        }
+        if let Some(metadata) = metadata {
+            metadata.apply(written_file_name, options)?;
+        }
+
+        if let Some(temp_file_name) = &self.temp_file_name {
+            self.writer.get_ref().sync_all()?;
+            std::fs::rename(temp_file_name, &self.file_name)?;
+            self.temp_file_name = None;
+            sync_parent_dir(&self.file_name);
+        }
+        Ok(())
+    }
+
+    // Replaces already written data, used for header which is known only after all data was written
+    pub fn write_back(&mut self, offset: u64, data: &[u8]) -> bool {
+        if self.write_hole().is_err() || self.writer.flush().is_err() {
+            return false
+        }
+        let file = self.writer.get_mut();
+        file.seek(SeekFrom::Start(offset)).is_ok() && file.write_all(data).is_ok() && file.seek(SeekFrom::End(0)).is_ok()
+    }
+
+    // Length of all written data
+    pub fn written_len(&mut self) -> std::io::Result<u64> {
+        Ok(self.writer.stream_position()? + self.hole_len)
+    }
+
+    // Moves past zeros which were not written, file is extended in case hole is at the end of file
+    fn write_hole(&mut self) -> std::io::Result<()> {
+        if self.hole_len > 0 {
+            let end = self.writer.seek(SeekFrom::Current(self.hole_len as i64))?;
+            self.writer.get_ref().set_len(end)?;
+            self.hole_len = 0;
+        }
+        Ok(())
+    }
+
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        // output was not finished, remove incomplete data
+        if let Some(temp_file_name) = &self.temp_file_name {
+            let _ = std::fs::remove_file(temp_file_name);
+        }
     }
-    
 }
 
 impl ProcessorDataOutput for OutputFile {
     fn write_data(&mut self, data: &[u8]) -> bool {
-        self.writer.write(data).is_ok()
+        self.write_hole().is_ok() && self.writer.write_all(data).is_ok()
+    }
+
+    fn read_back(&mut self, offset: u64, data: &mut [u8]) -> bool {
+        if self.write_hole().is_err() || self.writer.flush().is_err() {
+            return false
+        }
+        let file = self.writer.get_mut();
+        file.seek(SeekFrom::Start(offset)).is_ok() && file.read_exact(data).is_ok() && file.seek(SeekFrom::End(0)).is_ok()
+    }
+
+    fn write_zeros(&mut self, len: u64) -> bool {
+        if self.temp_file_name.is_none() {
+            // destination written directly can be a device, which has old data in place of a hole
+            return write_zero_data(self, len)
+        }
+        self.hole_len += len;
+        true
+    }
+}
+
+
+// Creates temporary file next to destination file, so it can be renamed over it
+fn create_temp_file(file_name: &Path, private: bool) -> std::io::Result<(std::fs::File, PathBuf)> {
+    let name = file_name.file_name().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "output path is not a file"))?;
+    let dir = file_name.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut index = 0;
+    loop {
+        let temp_file_name = dir.join(format!(".{}.{}-{}.tmp", name.to_string_lossy(), std::process::id(), index));
+        match open_options(private).create_new(true).open(&temp_file_name) {
+            Ok(file) => return Ok((file, temp_file_name)),
+            Err(x) if x.kind() == std::io::ErrorKind::AlreadyExists => index += 1,
+            Err(x) => return Err(x)
+        }
     }
 }
 
+// Replaced destination keeps its permissions, and its owner when running as root, metadata of output is applied after it
+fn keep_permissions(file_name: &Path, temp_file_name: &Path) -> std::io::Result<()> {
+    let existing = match std::fs::metadata(file_name) {
+        Ok(existing) => existing,
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(x) => return Err(x)
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let is_root = unsafe { libc::geteuid() } == 0;
+        if is_root {
+            std::os::unix::fs::chown(temp_file_name, Some(existing.uid()), Some(existing.gid()))?;
+        }
+    }
+    std::fs::set_permissions(temp_file_name, existing.permissions())
+}
+
+// Options of new output file, private file is readable and writable by owner only
+fn open_options(private: bool) -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options
+}
+
+// Makes rename durable, errors are ignored as not all platforms allow syncing directories
+fn sync_parent_dir(file_name: &Path) {
+    match file_name.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => sync_dir(Path::new("."))
+    }
+}
+
+// Makes entries created in directory durable, errors are ignored as in sync_parent_dir
+pub fn sync_dir(dir: &Path) {
+    #[cfg(unix)]
+    if let Ok(dir) = std::fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+}
+
+
+// Output file tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    #[test]
+    fn test_output_finished() {
+        // output file test
+        // scenario: destination is replaced when output is finished
+
+        let file_name = test_file_name("output-finished");
+        std::fs::write(&file_name, [1,2,3]).unwrap();
+
+        let mut output = OutputFile::new(&file_name).unwrap();
+        assert!( output.write_data(&[4,5]) );
+        assert_eq!( std::fs::read(&file_name).unwrap(), [1,2,3] );
+        assert!( output.finish().is_ok() );
+
+        assert_eq!( std::fs::read(&file_name).unwrap(), [4,5] );
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_output_read_back() {
+        // output file test
+        // scenario: data already written is read back and writing continues at the end
+
+        let file_name = test_file_name("output-read-back");
+        let mut output = OutputFile::new(&file_name).unwrap();
+        assert!( output.write_data(&[1,2,3,4]) );
+        let mut data = [0; 2];
+        assert!( output.read_back(1, &mut data) );
+        assert_eq!( data, [2,3] );
+        assert!( !output.read_back(3, &mut data) );
+        assert!( output.write_data(&[5]) );
+        assert!( output.finish().is_ok() );
+
+        assert_eq!( std::fs::read(&file_name).unwrap(), [1,2,3,4,5] );
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_output_sparse() {
+        // output file test
+        // scenario: zeros are left as holes in the middle and at the end of file, hole is read back as zeros
+
+        let file_name = test_file_name("output-sparse");
+        let mut output = OutputFile::new(&file_name).unwrap();
+        assert!( output.write_data(&[1,2]) );
+        assert!( output.write_zeros(3) );
+        let mut data = [1; 2];
+        assert!( output.read_back(2, &mut data) );
+        assert_eq!( data, [0,0] );
+        assert!( output.write_zeros(2) );
+        assert!( output.write_data(&[3]) );
+        assert!( output.write_zeros(1024 * 1024) );
+        assert!( output.finish().is_ok() );
+
+        let mut expected = vec![1,2,0,0,0,0,0,3];
+        expected.resize(8 + 1024 * 1024, 0);
+        assert_eq!( std::fs::read(&file_name).unwrap(), expected );
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_private() {
+        // output file test
+        // scenario: private output is readable by owner only while it is written, existing file is not written directly
+        use std::os::unix::fs::PermissionsExt;
+
+        let file_name = test_file_name("output-private");
+        let _ = std::fs::remove_file(&file_name);
+        let mut output = OutputFile::new_private(&file_name, false).unwrap();
+        assert_eq!( std::fs::metadata(&file_name).unwrap().permissions().mode() & 0o077, 0 );
+        assert!( output.write_data(&[1,2]) );
+        assert!( output.finish().is_ok() );
+        assert!( OutputFile::new_private(&file_name, false).is_err() );
+
+        let mut output = OutputFile::new_private(&file_name, true).unwrap();
+        let temp_file_name = output.temp_file_name.clone().unwrap();
+        assert_eq!( std::fs::metadata(&temp_file_name).unwrap().permissions().mode() & 0o077, 0 );
+        assert!( output.write_data(&[3]) );
+        assert!( output.finish().is_ok() );
+        assert_eq!( std::fs::read(&file_name).unwrap(), [3] );
+        assert_eq!( std::fs::metadata(&file_name).unwrap().permissions().mode() & 0o077, 0 );
+        std::fs::remove_file(&file_name).unwrap();
+    }
+
+    #[test]
+    fn test_output_dropped() {
+        // output file test
+        // scenario: destination and its directory are unchanged if output is not finished
+
+        let dir = test_file_name("output-dropped");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_name = dir.join("file");
+        std::fs::write(&file_name, [1,2,3]).unwrap();
+
+        let mut output = OutputFile::new(&file_name).unwrap();
+        assert!( output.write_data(&[4,5]) );
+        drop(output);
+
+        assert_eq!( std::fs::read(&file_name).unwrap(), [1,2,3] );
+        assert_eq!( std::fs::read_dir(&dir).unwrap().count(), 1 );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_permissions() {
+        // output file test
+        // scenario: replaced destination keeps its mode, new destination gets default mode
+        use std::os::unix::fs::PermissionsExt;
+
+        let file_name = test_file_name("output-permissions");
+        std::fs::write(&file_name, [1,2,3]).unwrap();
+        std::fs::set_permissions(&file_name, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut output = OutputFile::new(&file_name).unwrap();
+        assert!( output.write_data(&[4,5]) );
+        assert!( output.finish().is_ok() );
+        assert_eq!( std::fs::read(&file_name).unwrap(), [4,5] );
+        assert_eq!( std::fs::metadata(&file_name).unwrap().permissions().mode() & 0o7777, 0o755 );
+        std::fs::remove_file(&file_name).unwrap();
+
+        let mut output = OutputFile::new(&file_name).unwrap();
+        assert!( output.write_data(&[6]) );
+        assert!( output.finish().is_ok() );
+        assert_eq!( std::fs::metadata(&file_name).unwrap().permissions().mode() & 0o111, 0 );
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
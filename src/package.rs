@@ -0,0 +1,255 @@
+use std::{io::Read, path::Path};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+use super::processor::*;
+use super::input_file::*;
+
+// Magic value at the beginning of signed packages
+const PACKAGE_MAGIC: &[u8; 4] = b"HDPK";
+const PACKAGE_VERSION: u8 = 1;
+
+// Signature is calculated with Ed25519ph, context separates it from signatures of other data
+const SIGNATURE_CONTEXT: &[u8] = b"hdiff package";
+
+pub const SIGNATURE_SIZE: usize = 64;
+pub const KEY_SIZE: usize = 32;
+
+
+// Custom error codes, all of them contain name of the file
+#[derive(Debug)]
+pub enum PackageError {
+    InvalidKey(String),
+    NotSigned(String),
+    VerificationFailed(String),
+    FileRead(String, std::io::Error)
+}
+impl std::fmt::Display for PackageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PackageError::InvalidKey(name) => write!(f, "Invalid key file: {}", name),
+            PackageError::NotSigned(name) => write!(f, "File is not a signed package: {}", name),
+            PackageError::VerificationFailed(name) => write!(f, "Signature verification failed for package: {}", name),
+            PackageError::FileRead(name, x) => write!(f, "Unable to read file: {}, error: {}", name, x)
+        }
+    }
+}
+impl std::error::Error for PackageError {}
+
+
+// Generates new key pair, returns secret key and public key
+pub fn generate_keys() -> Result<([u8; KEY_SIZE], [u8; KEY_SIZE]), getrandom::Error> {
+    let mut secret_key = [0u8; KEY_SIZE];
+    getrandom::getrandom(&mut secret_key)?;
+    let public_key = SigningKey::from_bytes(&secret_key).verifying_key().to_bytes();
+    Ok((secret_key, public_key))
+}
+
+// Key files contain raw key bytes
+pub fn read_secret_key<P: AsRef<Path>>(file_name: P) -> Result<SigningKey, PackageError> {
+    let key = read_key(file_name.as_ref())?;
+    Ok(SigningKey::from_bytes(&key))
+}
+
+pub fn read_public_key<P: AsRef<Path>>(file_name: P) -> Result<VerifyingKey, PackageError> {
+    let key = read_key(file_name.as_ref())?;
+    VerifyingKey::from_bytes(&key).map_err(|_| PackageError::InvalidKey(file_name.as_ref().display().to_string()))
+}
+
+fn read_key(file_name: &Path) -> Result<[u8; KEY_SIZE], PackageError> {
+    let key = std::fs::read(file_name).map_err(|x| PackageError::FileRead(file_name.display().to_string(), x))?;
+    <[u8; KEY_SIZE]>::try_from(key.as_slice()).map_err(|_| PackageError::InvalidKey(file_name.display().to_string()))
+}
+
+
+// Wraps delta into signed package
+// package format:
+// magic, u8 version, delta file with its header, Ed25519ph signature of all preceding data
+pub fn sign_package<R: Read, S: ProcessorDataOutput>(key: &SigningKey, delta_file: &mut R, output_file: &mut S) -> Result<(), ProcessorError> {
+    let mut hasher = Sha512::new();
+    let mut write_data = |data: &[u8]| {
+        hasher.update(data);
+        if output_file.write_data(data) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+    };
+
+    write_data(PACKAGE_MAGIC)?;
+    write_data(&[PACKAGE_VERSION])?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match delta_file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => write_data(&buffer[..n])?,
+            Err(x) if x.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => return Err(ProcessorError::InvalidDelta)
+        }
+    }
+
+    let signature = key.sign_prehashed(hasher, Some(SIGNATURE_CONTEXT)).map_err(|_| ProcessorError::FileWrite)?;
+    if output_file.write_data(&signature.to_bytes()) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+}
+
+// Checks signature of package file
+pub fn verify_package_file<P: AsRef<Path>>(file_name: P, key: &VerifyingKey) -> Result<(), PackageError> {
+    let name = file_name.as_ref().display().to_string();
+    let read_error = |x| PackageError::FileRead(name.clone(), x);
+
+    let file = std::fs::File::open(file_name.as_ref()).map_err(read_error)?;
+    let len = file.metadata().map_err(read_error)?.len();
+    verify_package(&mut std::io::BufReader::new(file), len, &name, key)
+}
+
+// Checks signature of package read from its beginning, len is length of the whole package
+fn verify_package(reader: &mut dyn Read, len: u64, name: &str, key: &VerifyingKey) -> Result<(), PackageError> {
+    let read_error = |x| PackageError::FileRead(name.to_owned(), x);
+    let prefix_len = (PACKAGE_MAGIC.len() + 1) as u64;
+    if len < prefix_len + SIGNATURE_SIZE as u64 {
+        return Err(PackageError::NotSigned(name.to_owned()))
+    }
+
+    let mut prefix = [0u8; PACKAGE_MAGIC.len() + 1];
+    reader.read_exact(&mut prefix).map_err(read_error)?;
+    if &prefix[..PACKAGE_MAGIC.len()] != PACKAGE_MAGIC || prefix[PACKAGE_MAGIC.len()] != PACKAGE_VERSION {
+        return Err(PackageError::NotSigned(name.to_owned()))
+    }
+
+    let mut hasher = Sha512::new();
+    hasher.update(prefix);
+    let mut data = (&mut *reader).take(len - prefix_len - SIGNATURE_SIZE as u64);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match data.read(&mut buffer).map_err(read_error)? {
+            0 => break,
+            n => hasher.update(&buffer[..n])
+        }
+    }
+
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    reader.read_exact(&mut signature).map_err(read_error)?;
+    match key.verify_prehashed(hasher, Some(SIGNATURE_CONTEXT), &Signature::from_bytes(&signature)) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(PackageError::VerificationFailed(name.to_owned()))
+    }
+}
+
+// Reads package prefix from beginning of the input, so it is left positioned at the delta header
+// signature is checked if key was given, in this case input has to be a package
+// signature trailer is excluded from further reads
+pub fn open_input_package(input_file: &mut InputFile, file_name: &str, key: Option<&VerifyingKey>) -> Result<(), PackageError> {
+    let read_error = || PackageError::FileRead(file_name.to_owned(), std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+
+    input_file.set_chunk_size(PACKAGE_MAGIC.len());
+    if input_file.get_next_data() != PACKAGE_MAGIC {
+        if !input_file.move_back_last_read() {
+            return Err(read_error())
+        }
+        return match key {
+            Some(_) => Err(PackageError::NotSigned(file_name.to_owned())),
+            None => Ok(())
+        }
+    }
+    input_file.set_chunk_size(1);
+    if input_file.get_next_data() != [PACKAGE_VERSION] {
+        return Err(PackageError::NotSigned(file_name.to_owned()))
+    }
+
+    // signature is verified on the same open file which is read afterwards, so file cannot be replaced in between
+    if let Some(key) = key {
+        let len = input_file.total_len();
+        input_file.read_from(0, |reader| verify_package(reader, len, file_name, key))
+            .map_err(|x| PackageError::FileRead(file_name.to_owned(), x))??;
+    }
+    input_file.skip_trailer(SIGNATURE_SIZE as u64);
+    Ok(())
+}
+
+
+// Signed package tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    #[test]
+    fn test_package_sign() {
+        // package test
+        // scenario: package is verified with public key of signer only and fails if data was changed
+
+        let file_path = test_file_name("package-sign");
+        let file_name = file_path.to_str().unwrap();
+        let (secret_key, public_key) = generate_keys().unwrap();
+        let (_, other_public_key) = generate_keys().unwrap();
+        let public_key = VerifyingKey::from_bytes(&public_key).unwrap();
+        let other_public_key = VerifyingKey::from_bytes(&other_public_key).unwrap();
+
+        let mut output = Vec::new();
+        assert!( sign_package(&SigningKey::from_bytes(&secret_key), &mut &[2,1,2,3,4][..], &mut output).is_ok() );
+        assert_eq!( output.len(), 5 + 5 + SIGNATURE_SIZE );
+        assert_eq!( output[..10], [b'H',b'D',b'P',b'K',1,2,1,2,3,4] );
+        std::fs::write(file_name, &output).unwrap();
+
+        assert!( verify_package_file(file_name, &public_key).is_ok() );
+        assert!( matches!( verify_package_file(file_name, &other_public_key), Err(PackageError::VerificationFailed(_)) ) );
+
+        output[7] = 0;
+        std::fs::write(file_name, &output).unwrap();
+        assert!( matches!( verify_package_file(file_name, &public_key), Err(PackageError::VerificationFailed(_)) ) );
+
+        std::fs::write(file_name, [2,1,2,3,4]).unwrap();
+        assert!( matches!( verify_package_file(file_name, &public_key), Err(PackageError::NotSigned(_)) ) );
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_package_input_file() {
+        // package test
+        // scenario: delta is read from package without prefix and signature, plain delta requires no key
+
+        let file_path = test_file_name("package-input");
+        let file_name = file_path.to_str().unwrap();
+        let (secret_key, public_key) = generate_keys().unwrap();
+        let public_key = VerifyingKey::from_bytes(&public_key).unwrap();
+
+        let mut output = Vec::new();
+        assert!( sign_package(&SigningKey::from_bytes(&secret_key), &mut &[2,1,2,3,4][..], &mut output).is_ok() );
+        std::fs::write(file_name, &output).unwrap();
+
+        let mut input = InputFile::new(file_name, 1).unwrap();
+        assert!( open_input_package(&mut input, file_name, Some(&public_key)).is_ok() );
+        input.set_chunk_size(16);
+        assert_eq!( input.get_next_data(), [2,1,2,3,4] );
+
+        std::fs::write(file_name, [2,1,2,3,4]).unwrap();
+        let mut input = InputFile::new(file_name, 1).unwrap();
+        assert!( matches!( open_input_package(&mut input, file_name, Some(&public_key)), Err(PackageError::NotSigned(_)) ) );
+        let mut input = InputFile::new(file_name, 1).unwrap();
+        assert!( open_input_package(&mut input, file_name, None).is_ok() );
+        input.set_chunk_size(16);
+        assert_eq!( input.get_next_data(), [2,1,2,3,4] );
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_package_replaced() {
+        // package test
+        // scenario: package file replaced after it was opened is not used for verification of the opened file
+
+        let file_path = test_file_name("package-replaced");
+        let file_name = file_path.to_str().unwrap();
+        let (secret_key, public_key) = generate_keys().unwrap();
+        let public_key = VerifyingKey::from_bytes(&public_key).unwrap();
+
+        let mut output = Vec::new();
+        assert!( sign_package(&SigningKey::from_bytes(&secret_key), &mut &[2,1,2,3,4][..], &mut output).is_ok() );
+        let mut modified = output.clone();
+        modified[7] = 0;
+        std::fs::write(file_name, &modified).unwrap();
+
+        let mut input = InputFile::new(file_name, 1).unwrap();
+        std::fs::remove_file(file_name).unwrap();
+        std::fs::write(file_name, &output).unwrap();
+        assert!( matches!( open_input_package(&mut input, file_name, Some(&public_key)), Err(PackageError::VerificationFailed(_)) ) );
+
+        std::fs::remove_file(file_name).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}};
+use super::processor::*;
+use super::release::*;
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum PlannerError {
+    NoPath
+}
+impl std::fmt::Display for PlannerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlannerError::NoPath => write!(f, "Target version cannot be reached from current version")
+        }
+    }
+}
+impl std::error::Error for PlannerError {}
+
+
+// Download needed to update a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateStep {
+    Full(FullEntry),
+    Delta(IndexEntry)
+}
+
+impl UpdateStep {
+    pub fn size(&self) -> u64 {
+        match self {
+            UpdateStep::Full(entry) => entry.size,
+            UpdateStep::Delta(entry) => entry.delta_size
+        }
+    }
+}
+
+// Downloads in the order they are applied, empty plan means file is up to date
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpdatePlan {
+    pub steps: Vec<UpdateStep>,
+    pub total_size: u64
+}
+
+impl UpdatePlan {
+    // True if whole file is downloaded instead of applying deltas to current file
+    pub fn is_full_download(&self) -> bool {
+        matches!(self.steps.first(), Some(UpdateStep::Full(_)))
+    }
+}
+
+
+// Finds downloads with the smallest total size, which turn current file into target file
+// deltas form a graph of versions identified by digests, full download of any version starts a new path
+// shortest path is found with Dijkstra's algorithm
+pub fn plan_update(index: &Index, current_digest: &[u8; HASH_SIZE], target_digest: &[u8; HASH_SIZE]) -> Result<UpdatePlan, PlannerError> {
+    let mut edges: HashMap<[u8; HASH_SIZE], Vec<&IndexEntry>> = HashMap::new();
+    for entry in &index.deltas {
+        edges.entry(entry.base_digest).or_default().push(entry);
+    }
+
+    // cheapest known download size of every version and the step which reaches it
+    let mut costs: HashMap<[u8; HASH_SIZE], (u64, Option<UpdateStep>)> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    costs.insert(*current_digest, (0, None));
+    queue.push(Reverse((0, *current_digest)));
+    for entry in &index.full {
+        if costs.get(&entry.digest).is_none_or(|(cost, _)| entry.size < *cost) {
+            costs.insert(entry.digest, (entry.size, Some(UpdateStep::Full(entry.clone()))));
+            queue.push(Reverse((entry.size, entry.digest)));
+        }
+    }
+
+    while let Some(Reverse((cost, digest))) = queue.pop() {
+        if costs.get(&digest).is_some_and(|(known_cost, _)| cost > *known_cost) {
+            continue // version was already reached with smaller cost
+        }
+        if digest == *target_digest {
+            break
+        }
+        for entry in edges.get(&digest).into_iter().flatten() {
+            let next_cost = cost.saturating_add(entry.delta_size);
+            if costs.get(&entry.target_digest).is_none_or(|(known_cost, _)| next_cost < *known_cost) {
+                costs.insert(entry.target_digest, (next_cost, Some(UpdateStep::Delta((*entry).clone()))));
+                queue.push(Reverse((next_cost, entry.target_digest)));
+            }
+        }
+    }
+
+    // steps are collected from target back to start of the path
+    let total_size = match costs.get(target_digest) {
+        Some((cost, _)) => *cost,
+        None => return Err(PlannerError::NoPath)
+    };
+    let mut steps = Vec::new();
+    let mut digest = *target_digest;
+    while let Some((_, Some(step))) = costs.get(&digest) {
+        digest = match step {
+            UpdateStep::Full(_) => {
+                steps.push(step.clone());
+                break
+            }
+            UpdateStep::Delta(entry) => entry.base_digest
+        };
+        steps.push(step.clone());
+    }
+    steps.reverse();
+
+    Ok(UpdatePlan { steps, total_size })
+}
+
+
+// Planner tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(base: u8, target: u8, size: u64) -> IndexEntry {
+        IndexEntry { base_digest: [base; HASH_SIZE], delta_path: format!("{}-{}.delta", base, target), delta_size: size, target_digest: [target; HASH_SIZE] }
+    }
+
+    fn full(digest: u8, size: u64) -> FullEntry {
+        FullEntry { digest: [digest; HASH_SIZE], path: format!("{}.full", digest), size }
+    }
+
+    #[test]
+    fn test_plan_1() {
+        // planner test
+        // scenario: chain of deltas is cheaper than direct delta
+
+        let index = Index { deltas: vec![delta(1, 3, 100), delta(1, 2, 30), delta(2, 3, 40), delta(3, 4, 10)], full: vec![full(3, 1000)] };
+        let plan = plan_update(&index, &[1; HASH_SIZE], &[3; HASH_SIZE]).unwrap();
+        assert_eq!( plan, UpdatePlan { steps: vec![UpdateStep::Delta(delta(1, 2, 30)), UpdateStep::Delta(delta(2, 3, 40))], total_size: 70 } );
+        assert!( !plan.is_full_download() );
+
+        let plan = plan_update(&index, &[3; HASH_SIZE], &[3; HASH_SIZE]).unwrap();
+        assert_eq!( plan, UpdatePlan::default() );
+    }
+
+    #[test]
+    fn test_plan_2() {
+        // planner test
+        // scenario: full download is cheaper than deltas or current version is unknown
+
+        let index = Index { deltas: vec![delta(1, 2, 80), delta(2, 3, 40)], full: vec![full(3, 100)] };
+        let plan = plan_update(&index, &[1; HASH_SIZE], &[3; HASH_SIZE]).unwrap();
+        assert_eq!( plan, UpdatePlan { steps: vec![UpdateStep::Full(full(3, 100))], total_size: 100 } );
+        assert!( plan.is_full_download() );
+
+        let plan = plan_update(&index, &[9; HASH_SIZE], &[3; HASH_SIZE]).unwrap();
+        assert!( plan.is_full_download() );
+
+        assert!( plan_update(&index, &[9; HASH_SIZE], &[8; HASH_SIZE]).is_err() );
+    }
+
+    #[test]
+    fn test_plan_3() {
+        // planner test
+        // scenario: full download of older version followed by delta is cheapest
+
+        let index = Index { deltas: vec![delta(2, 3, 10)], full: vec![full(2, 50), full(3, 100)] };
+        let plan = plan_update(&index, &[1; HASH_SIZE], &[3; HASH_SIZE]).unwrap();
+        assert_eq!( plan, UpdatePlan { steps: vec![UpdateStep::Full(full(2, 50)), UpdateStep::Delta(delta(2, 3, 10))], total_size: 60 } );
+    }
+}
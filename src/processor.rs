@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use sha2::{Sha256, Digest};
 
 // Default 1024 bytes chunk size
@@ -7,41 +8,382 @@ pub const CHUNK_SIZE: usize = 1024;
 pub const HASH_SIZE: usize = 32;
 
 // tags for delta file
-const TAG_SAME_HASH: [u8; 1] = [0]; 
-const TAG_DIFFERENT_HASH: [u8; 1] = [1];
-const TAG_INSERTED_CHUNK: [u8; 1] = [2];
-const TAG_REMOVED_CHUNK: [u8; 1] = [3];
+pub const TAG_SAME_HASH: [u8; 1] = [0]; 
+pub const TAG_DIFFERENT_HASH: [u8; 1] = [1];
+pub const TAG_INSERTED_CHUNK: [u8; 1] = [2];
+pub const TAG_REMOVED_CHUNK: [u8; 1] = [3];
+pub const TAG_COPY_FROM_OUTPUT: [u8; 1] = [4];
+pub const TAG_ZERO_CHUNK: [u8; 1] = [5];
+pub const TAG_SIZE: usize = 1;
+
+// Limits of chunk size selected from file length
+pub const MIN_AUTO_CHUNK_SIZE: usize = 512;
+pub const MAX_AUTO_CHUNK_SIZE: usize = 128 * 1024;
+
+// Size of output offset following copy from output tag
+pub const COPY_OFFSET_SIZE: usize = 8;
+
+// Size of chunk length following zero chunk tag
+pub const ZERO_LEN_SIZE: usize = 8;
+
+// Size of zero bytes prefix of zero chunk record in signature, chunk length follows it
+const ZERO_RECORD_PREFIX_SIZE: usize = HASH_SIZE - ZERO_LEN_SIZE;
+
+// Progress is reported after processing this many bytes
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
 
 
 // Trait for input data
 pub trait ProcessorDataInput {
     fn get_next_data(&mut self) -> &[u8];
     fn move_back_last_read(&mut self) -> bool; // true if success
+    fn set_chunk_size(&mut self, chunk_size: usize); // size of data returned by next reads
+    fn total_len(&self) -> u64 { 0 } // 0 if size of data is unknown
+    fn remaining_len(&self) -> u64 { 0 }
 }
 
 // Trait for output data
 pub trait ProcessorDataOutput {
     fn write_data(&mut self, data: &[u8]) -> bool; // true if success
+    fn read_back(&mut self, _offset: u64, _data: &mut [u8]) -> bool { false } // reads already written data, true if success
+
+    fn write_zeros(&mut self, len: u64) -> bool { write_zero_data(self, len) } // outputs supporting sparse files can leave a hole
+}
+
+// Writes zero bytes as data
+pub fn write_zero_data<S: ProcessorDataOutput + ?Sized>(output_file: &mut S, len: u64) -> bool {
+    let zeros = [0; 4096];
+    let mut len_to_write = len;
+    while len_to_write > 0 {
+        let len = std::cmp::min(len_to_write, zeros.len() as u64) as usize;
+        if !output_file.write_data(&zeros[..len]) {
+            return false
+        }
+        len_to_write -= len as u64;
+    }
+    true
 }
 
 // Custom error codes
+#[derive(Debug)]
 pub enum ProcessorError {
     FileWrite,
-    FileSeek
+    FileSeek,
+    InvalidDelta,
+    InvalidHeader,
+    InvalidSignature,
+    OldFileMismatch,
+    UnsupportedVersion,
+    InputTooLarge
 }
 impl std::fmt::Display for ProcessorError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             ProcessorError::FileWrite => write!(f, "File write error"),
-            ProcessorError::FileSeek => write!(f, "Unable to change position in a file")
+            ProcessorError::FileSeek => write!(f, "Unable to change position in a file"),
+            ProcessorError::InvalidDelta => write!(f, "Delta file is malformed"),
+            ProcessorError::InvalidHeader => write!(f, "File header is malformed"),
+            ProcessorError::InvalidSignature => write!(f, "Signature file is malformed"),
+            ProcessorError::OldFileMismatch => write!(f, "Old file is different than file which delta was created for"),
+            ProcessorError::UnsupportedVersion => write!(f, "File was created by incompatible version of hdiff, it has to be created again"),
+            ProcessorError::InputTooLarge => write!(f, "Input file is too large for selected diff algorithm")
         }
     }
 }
+impl std::error::Error for ProcessorError {}
+
+// Phases of processing reported by progress callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressPhase {
+    Signature,
+    Delta,
+    Patch
+}
+
+// Progress of processing, bytes are counted in the file which drives the processing:
+// input file for signature and delta, delta file for patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: ProgressPhase,
+    pub processed_bytes: u64,
+    pub total_bytes: u64
+}
+
+// Calls progress callback when enough data was processed
+pub struct ProgressReporter<'a> {
+    callback: Option<&'a mut dyn FnMut(&Progress)>,
+    reported_bytes: u64
+}
+
+impl ProgressReporter<'_> {
+    pub fn report<U: ProcessorDataInput + ?Sized>(&mut self, phase: ProgressPhase, input: &U) {
+        if let Some(callback) = &mut self.callback {
+            let total_bytes = input.total_len();
+            let processed_bytes = total_bytes.saturating_sub(input.remaining_len());
+            if processed_bytes >= self.reported_bytes + PROGRESS_INTERVAL || ( processed_bytes == total_bytes && processed_bytes != self.reported_bytes ) {
+                self.reported_bytes = processed_bytes;
+                callback(&Progress { phase, processed_bytes, total_bytes });
+            }
+        }
+    }
+}
+
+// Full chunks already written to output of patch, used to reference data repeated in new file
+struct OutputChunks {
+    offsets: HashMap<[u8; HASH_SIZE], u64>,
+    len: u64,
+    chunk_size: usize
+}
+
+impl OutputChunks {
+    fn new() -> Self {
+        Self { offsets: HashMap::new(), len: 0, chunk_size: 0 }
+    }
+
+    // Offset of the same chunk written earlier, only full chunks are referenced
+    fn find(&self, hash: &[u8; HASH_SIZE], chunk_len: usize) -> Option<u64> {
+        if chunk_len == self.chunk_size { self.offsets.get(hash).copied() } else { None }
+    }
+
+    fn add(&mut self, hash: [u8; HASH_SIZE], chunk_len: usize) {
+        if self.chunk_size == 0 {
+            // first chunk is full, unless whole file is smaller than a chunk
+            self.chunk_size = chunk_len;
+        }
+        if chunk_len == self.chunk_size {
+            self.offsets.entry(hash).or_insert(self.len);
+        }
+        self.len += chunk_len as u64;
+    }
+}
+
+// Writes chunk of new file which is not in old file, chunk repeated in new file is copied from output of patch
+// tag is either different or inserted chunk, different chunk copied from output is written as removed chunk and copy
+// chunk of zeros is written only as its length, in the same way as copy
+fn write_new_chunk<S: ProcessorDataOutput>(output_file: &mut S, output_chunks: &mut OutputChunks, tag: [u8; 1], chunk: &[u8], hash: [u8; HASH_SIZE]) -> Result<(), ProcessorError> {
+    let reference = is_reference_smaller(tag, chunk.len());
+    let written = match output_chunks.find(&hash, chunk.len()) {
+        _ if reference && is_zero_chunk(chunk) => {
+            (tag != TAG_DIFFERENT_HASH || output_file.write_data(&TAG_REMOVED_CHUNK)) &&
+            output_file.write_data(&TAG_ZERO_CHUNK) && output_file.write_data(&(chunk.len() as u64).to_le_bytes())
+        }
+        Some(offset) if reference => {
+            (tag != TAG_DIFFERENT_HASH || output_file.write_data(&TAG_REMOVED_CHUNK)) &&
+            output_file.write_data(&TAG_COPY_FROM_OUTPUT) && output_file.write_data(&offset.to_le_bytes())
+        }
+        _ => output_file.write_data(&tag) && output_file.write_data(chunk)
+    };
+    if !written {
+        return Err(ProcessorError::FileWrite)
+    }
+    output_chunks.add(hash, chunk.len());
+    Ok(())
+}
+
+// Copy and zero chunk records are written only when they are smaller than chunk data
+fn is_reference_smaller(tag: [u8; 1], chunk_len: usize) -> bool {
+    let tags_size = if tag == TAG_DIFFERENT_HASH { 2 * TAG_SIZE } else { TAG_SIZE };
+    chunk_len > tags_size + COPY_OFFSET_SIZE.max(ZERO_LEN_SIZE)
+}
+
+// Hash of chunk of new file with its data, None at the end of new file
+type NewChunk<C> = Option<([u8; HASH_SIZE], C)>;
+
+// Chunks of new file and hashes of old file aligned by delta and sigdiff processing
+// chunk is data of new file in delta, or nothing in sigdiff where only hashes of new file are known
+trait ChunkAlignment {
+    type Chunk;
+    fn next_new_chunk(&mut self) -> Result<NewChunk<Self::Chunk>, ProcessorError>;
+    fn next_old_hash(&mut self) -> Result<Option<[u8; HASH_SIZE]>, ProcessorError>;
+    fn move_back_new_chunk(&mut self) -> bool;
+    fn move_back_old_hash(&mut self) -> bool;
+    fn same_chunk(&mut self, hash: [u8; HASH_SIZE], chunk: Self::Chunk) -> Result<(), ProcessorError>;
+    fn new_chunk(&mut self, change: ChunkChange, tag: [u8; 1], hash: [u8; HASH_SIZE], chunk: Self::Chunk) -> Result<(), ProcessorError>;
+    fn removed_chunk(&mut self) -> Result<(), ProcessorError>;
+}
+
+// Aligns chunks of new file with old file, chunk is looked up only one position ahead in both files
+fn align_chunks<A: ChunkAlignment>(alignment: &mut A) -> Result<(), ProcessorError> {
+    while let Some((hash, chunk)) = alignment.next_new_chunk()? {
+        let sig_hash = match alignment.next_old_hash()? {
+            Some(sig_hash) => sig_hash,
+            None => {
+                // end of old file -> all chunks of new file are added to delta
+                alignment.new_chunk(ChunkChange::Inserted, TAG_DIFFERENT_HASH, hash, chunk)?;
+                while let Some((hash, chunk)) = alignment.next_new_chunk()? {
+                    alignment.new_chunk(ChunkChange::Inserted, TAG_DIFFERENT_HASH, hash, chunk)?;
+                }
+                break
+            }
+        };
+
+        if hash == sig_hash {
+            // chunks are the same
+            alignment.same_chunk(hash, chunk)?;
+            continue
+        }
+
+        let next = alignment.next_new_chunk()?;
+        let sig_hash_next = alignment.next_old_hash()?;
+        match next {
+            Some((hash_next, chunk_next)) if hash_next == sig_hash => {
+                // current old hash is same as next new hash -> previous chunk in new file was inserted
+                alignment.new_chunk(ChunkChange::Inserted, TAG_INSERTED_CHUNK, hash, chunk)?;
+                alignment.same_chunk(hash_next, chunk_next)?;
+                if !alignment.move_back_old_hash() {
+                    return Err(ProcessorError::FileSeek)
+                }
+            }
+            _ if sig_hash_next == Some(hash) => {
+                // current new hash is same as next old hash -> previous chunk in old file was removed
+                alignment.removed_chunk()?;
+                alignment.same_chunk(hash, chunk)?;
+                if !alignment.move_back_new_chunk() {
+                    return Err(ProcessorError::FileSeek)
+                }
+            }
+            _ => {
+                // chunks are different
+                alignment.new_chunk(ChunkChange::Changed, TAG_DIFFERENT_HASH, hash, chunk)?;
+                if !alignment.move_back_new_chunk() || !alignment.move_back_old_hash() {
+                    return Err(ProcessorError::FileSeek)
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Alignment of input file with signature, writes delta
+struct DeltaAlignment<'b, 'a, T, U, S> {
+    input_file: &'b mut T,
+    signature_file: &'b mut U,
+    output_file: &'b mut S,
+    output_chunks: OutputChunks,
+    progress: &'b mut ProgressReporter<'a>,
+    hasher: Option<&'b mut Sha256>,
+    len: u64
+}
+
+impl<T, U, S> DeltaAlignment<'_, '_, T, U, S> {
+    // Chunks of new file can be read again after moving back, so digest is updated only when chunk is written to delta
+    fn add_to_digest(&mut self, chunk: &[u8]) {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(chunk);
+        }
+        self.len += chunk.len() as u64;
+    }
+}
+
+impl<T, U, S> ChunkAlignment for DeltaAlignment<'_, '_, T, U, S>
+    where T: ProcessorDataInput, U: ProcessorDataInput, S: ProcessorDataOutput
+{
+    type Chunk = Vec<u8>;
+
+    fn next_new_chunk(&mut self) -> Result<NewChunk<Vec<u8>>, ProcessorError> {
+        self.progress.report(ProgressPhase::Delta, self.input_file);
+        match self.input_file.get_next_data() {
+            [] => Ok(None), // reached end of file
+            chunk => Ok(Some((calculate_signature_hash(chunk), chunk.to_owned())))
+        }
+    }
+
+    fn next_old_hash(&mut self) -> Result<Option<[u8; HASH_SIZE]>, ProcessorError> {
+        read_hash(self.signature_file)
+    }
+
+    fn move_back_new_chunk(&mut self) -> bool {
+        self.input_file.move_back_last_read()
+    }
+
+    fn move_back_old_hash(&mut self) -> bool {
+        self.signature_file.move_back_last_read()
+    }
+
+    fn same_chunk(&mut self, hash: [u8; HASH_SIZE], chunk: Vec<u8>) -> Result<(), ProcessorError> {
+        self.add_to_digest(&chunk);
+        if !self.output_file.write_data(&TAG_SAME_HASH) {
+            return Err(ProcessorError::FileWrite)
+        }
+        self.output_chunks.add(hash, chunk.len());
+        Ok(())
+    }
+
+    fn new_chunk(&mut self, _change: ChunkChange, tag: [u8; 1], hash: [u8; HASH_SIZE], chunk: Vec<u8>) -> Result<(), ProcessorError> {
+        self.add_to_digest(&chunk);
+        write_new_chunk(self.output_file, &mut self.output_chunks, tag, &chunk, hash)
+    }
+
+    fn removed_chunk(&mut self) -> Result<(), ProcessorError> {
+        if !self.output_file.write_data(&TAG_REMOVED_CHUNK) {
+            return Err(ProcessorError::FileWrite)
+        }
+        Ok(())
+    }
+}
+
+// Alignment of two signatures, estimates delta and collects ranges of changed chunks
+struct SigDiffAlignment<'b, U, V> {
+    old_signature: &'b mut U,
+    new_signature: &'b mut V,
+    chunk_size: usize,
+    diff: SignatureDiff,
+    output_chunks: OutputChunks
+}
+
+impl<U, V> ChunkAlignment for SigDiffAlignment<'_, U, V>
+    where U: ProcessorDataInput, V: ProcessorDataInput
+{
+    type Chunk = ();
+
+    fn next_new_chunk(&mut self) -> Result<NewChunk<()>, ProcessorError> {
+        Ok(read_hash(self.new_signature)?.map(|hash| (hash, ())))
+    }
+
+    fn next_old_hash(&mut self) -> Result<Option<[u8; HASH_SIZE]>, ProcessorError> {
+        read_hash(self.old_signature)
+    }
+
+    fn move_back_new_chunk(&mut self) -> bool {
+        self.new_signature.move_back_last_read()
+    }
+
+    fn move_back_old_hash(&mut self) -> bool {
+        self.old_signature.move_back_last_read()
+    }
+
+    fn same_chunk(&mut self, hash: [u8; HASH_SIZE], _chunk: ()) -> Result<(), ProcessorError> {
+        self.diff.add_same_chunk(&mut self.output_chunks, hash, self.chunk_size);
+        Ok(())
+    }
+
+    fn new_chunk(&mut self, change: ChunkChange, tag: [u8; 1], hash: [u8; HASH_SIZE], _chunk: ()) -> Result<(), ProcessorError> {
+        self.diff.add_new_chunk(&mut self.output_chunks, change, tag, hash, self.chunk_size);
+        Ok(())
+    }
+
+    fn removed_chunk(&mut self) -> Result<(), ProcessorError> {
+        self.diff.delta_size += TAG_SIZE as u64;
+        self.diff.add(ChunkChange::Removed, 1, 0);
+        Ok(())
+    }
+}
+
+// Format of signature and delta files of other tools, e.g. librsync
+// processor writes hdiff format unless other format is set
+pub trait DeltaFormat {
+    fn process_signature(&self, input_file: &mut dyn ProcessorDataInput, output_file: &mut dyn ProcessorDataOutput,
+                         progress: &mut ProgressReporter) -> Result<(), ProcessorError>;
+    fn process_delta(&self, input_file: &mut dyn ProcessorDataInput, signature_file: &mut dyn ProcessorDataInput,
+                     output_file: &mut dyn ProcessorDataOutput, progress: &mut ProgressReporter) -> Result<(), ProcessorError>;
+}
 
 // Processor object
 pub struct Processor<'a, T, S> {
     input_file: &'a mut T,
-    output_file: &'a mut S
+    output_file: &'a mut S,
+    progress: ProgressReporter<'a>,
+    format: Option<&'a dyn DeltaFormat>
 }
 
 
@@ -52,110 +394,448 @@ impl<'a, T, S> Processor<'a, T, S> {
     pub fn new(input_file: &'a mut T, output_file: &'a mut S) -> Self
         where T: ProcessorDataInput, S: ProcessorDataOutput
     {
-        Self { input_file, output_file }
+        Self { input_file, output_file, progress: ProgressReporter { callback: None, reported_bytes: 0 }, format: None }
+    }
+
+    // Sets callback which receives progress of processing
+    pub fn with_progress(mut self, callback: &'a mut dyn FnMut(&Progress)) -> Self {
+        self.progress.callback = Some(callback);
+        self
+    }
+
+    // Sets format of signature and delta files written instead of hdiff format
+    pub fn with_format(mut self, format: &'a dyn DeltaFormat) -> Self {
+        self.format = Some(format);
+        self
     }
     
     // Delta command logic
-    pub fn process_delta(&mut self, signature_file: &mut T) -> Result<(), ProcessorError>
+    pub fn process_delta<U>(&mut self, signature_file: &mut U) -> Result<(), ProcessorError>
+        where T: ProcessorDataInput, S: ProcessorDataOutput, U: ProcessorDataInput
+    {
+        if let Some(format) = self.format {
+            return format.process_delta(self.input_file, signature_file, self.output_file, &mut self.progress)
+        }
+
+        self.write_delta(signature_file, None)?;
+        Ok(())
+    }    
+
+    // Delta command logic which returns length and digest of whole input file, which delta header keeps
+    pub fn process_delta_digest<U>(&mut self, signature_file: &mut U) -> Result<(u64, [u8; HASH_SIZE]), ProcessorError>
+        where T: ProcessorDataInput, S: ProcessorDataOutput, U: ProcessorDataInput
+    {
+        let mut hasher = Sha256::new();
+        let len = self.write_delta(signature_file, Some(&mut hasher))?;
+        Ok((len, hasher.finalize().into()))
+    }
+
+    // Returns length of input file
+    fn write_delta<U>(&mut self, signature_file: &mut U, hasher: Option<&mut Sha256>) -> Result<u64, ProcessorError>
+        where T: ProcessorDataInput, S: ProcessorDataOutput, U: ProcessorDataInput
+    {
+        let mut alignment = DeltaAlignment { input_file: &mut *self.input_file, signature_file, output_file: &mut *self.output_file,
+                                             output_chunks: OutputChunks::new(), progress: &mut self.progress, hasher, len: 0 };
+        align_chunks(&mut alignment)?;
+        let len = alignment.len;
+        self.progress.report(ProgressPhase::Delta, self.input_file);
+
+        Ok(len)
+    }
+    
+    // Signature command logic
+    pub fn process_signature(&mut self) -> Result<(), ProcessorError>
         where T: ProcessorDataInput, S: ProcessorDataOutput
     {
-        loop {
-            let mut input_file_chunk = self.input_file.get_next_data();
+        if let Some(format) = self.format {
+            return format.process_signature(self.input_file, self.output_file, &mut self.progress)
+        }
+
+        self.write_signature_hashes(None).map(|_| ())
+    }
+
+    // Signature command logic which returns length and digest of whole input file too, so input is read once
+    pub fn process_signature_digest(&mut self) -> Result<(u64, [u8; HASH_SIZE]), ProcessorError>
+        where T: ProcessorDataInput, S: ProcessorDataOutput
+    {
+        let mut hasher = Sha256::new();
+        let len = self.write_signature_hashes(Some(&mut hasher))?;
+        Ok((len, hasher.finalize().into()))
+    }
+
+    // Returns length of input file
+    fn write_signature_hashes(&mut self, mut hasher: Option<&mut Sha256>) -> Result<u64, ProcessorError>
+        where T: ProcessorDataInput, S: ProcessorDataOutput
+    {
+        let mut len = 0;
+        loop {            
+            self.progress.report(ProgressPhase::Signature, self.input_file);
+
+            let input_file_chunk = self.input_file.get_next_data();
             if input_file_chunk.is_empty() { break } // reached end of file
-                        
-            let hash = calculate_chunk_hash(input_file_chunk);
+            if let Some(hasher) = &mut hasher {
+                hasher.update(input_file_chunk);
+            }
+            len += input_file_chunk.len() as u64;
+             
+            let hash = calculate_signature_hash(input_file_chunk);
             
-            let sig_hash = signature_file.get_next_data();
-            if sig_hash.is_empty() { 
-                // end of signature file -> all data from input file needs to be added to delta
-                while !input_file_chunk.is_empty() {
-                    self.output_file.write_data(&TAG_DIFFERENT_HASH);                
-                    self.output_file.write_data(input_file_chunk);
-                
-                    input_file_chunk = self.input_file.get_next_data();
-                }
-                break
+            if !self.output_file.write_data(&hash) {
+                return Err(ProcessorError::FileWrite)
             }
+        }
+        
+        Ok(len)
+    }
 
-            if hash == sig_hash {
-                // chunks are the same
-                if !self.output_file.write_data(&TAG_SAME_HASH) {
-                    return Err(ProcessorError::FileWrite)
-                }
-            } else {
-                let input_file_chunk_prev = input_file_chunk.to_owned();
-                let input_file_chunk = self.input_file.get_next_data();
-                let hash_next = calculate_chunk_hash(input_file_chunk);
-                
-                let sig_hash_prev = sig_hash.to_owned();
-                let sig_hash = signature_file.get_next_data();
-                
-                if sig_hash_prev == hash_next {
-                    // current sigature hash is same as next input file hash -> previous chunk in new file was inserted
-                    if !self.output_file.write_data(&TAG_INSERTED_CHUNK) || 
-                       !self.output_file.write_data(&input_file_chunk_prev) ||
-                       !self.output_file.write_data(&TAG_SAME_HASH) {
+    // Patch command logic, input file is the old file which delta file was created against
+    pub fn process_patch<U>(&mut self, delta_file: &mut U, chunk_size: usize) -> Result<(), ProcessorError>
+        where T: ProcessorDataInput, S: ProcessorDataOutput, U: ProcessorDataInput
+    {
+        self.input_file.set_chunk_size(chunk_size);
+        let mut output_len: u64 = 0;
+
+        loop {
+            self.progress.report(ProgressPhase::Patch, delta_file);
+
+            delta_file.set_chunk_size(TAG_SIZE);
+            let tag = match delta_file.get_next_data() {
+                [] => break, // reached end of file
+                tag => [tag[0]]
+            };
+
+            match tag {
+                TAG_SAME_HASH => {
+                    // copy chunk from old file
+                    let old_file_chunk = self.input_file.get_next_data();
+                    if old_file_chunk.is_empty() {
+                        return Err(ProcessorError::InvalidDelta)
+                    }
+                    if !write_chunk(self.output_file, old_file_chunk) {
                         return Err(ProcessorError::FileWrite)
                     }
-                    if !signature_file.move_back_last_read() {
-                        return Err(ProcessorError::FileSeek)
+                    output_len += old_file_chunk.len() as u64;
+                }
+                TAG_DIFFERENT_HASH | TAG_INSERTED_CHUNK => {
+                    if tag == TAG_DIFFERENT_HASH {
+                        // chunk of old file is replaced, it may not exist if data was appended
+                        self.input_file.get_next_data();
                     }
-                } else if sig_hash == hash {
-                    // current input file hash is same as next sigature hash -> previous chunk in old file was removed
-                    if !self.output_file.write_data(&TAG_REMOVED_CHUNK) || !self.output_file.write_data(&TAG_SAME_HASH) {
+                    // only last chunk of the file can be shorter than chunk size
+                    delta_file.set_chunk_size(chunk_size);
+                    let delta_file_chunk = delta_file.get_next_data();
+                    if delta_file_chunk.is_empty() {
+                        return Err(ProcessorError::InvalidDelta)
+                    }
+                    if !write_chunk(self.output_file, delta_file_chunk) {
                         return Err(ProcessorError::FileWrite)
                     }
-                    if !self.input_file.move_back_last_read() {
+                    output_len += delta_file_chunk.len() as u64;
+                }
+                TAG_REMOVED_CHUNK => {
+                    // skip chunk of old file
+                    self.input_file.get_next_data();
+                }
+                TAG_COPY_FROM_OUTPUT => {
+                    // copy full chunk which was already written to output
+                    delta_file.set_chunk_size(COPY_OFFSET_SIZE);
+                    let offset = match <[u8; COPY_OFFSET_SIZE]>::try_from(delta_file.get_next_data()) {
+                        Ok(offset) => u64::from_le_bytes(offset),
+                        Err(_) => return Err(ProcessorError::InvalidDelta)
+                    };
+                    if offset.checked_add(chunk_size as u64).is_none_or(|end| end > output_len) {
+                        return Err(ProcessorError::InvalidDelta)
+                    }
+                    let mut chunk = vec![0; chunk_size];
+                    if !self.output_file.read_back(offset, &mut chunk) {
                         return Err(ProcessorError::FileSeek)
                     }
-                } else {
-                    // chunks are different
-    
-                    if !self.output_file.write_data(&TAG_DIFFERENT_HASH) || !self.output_file.write_data(&input_file_chunk_prev) {
+                    if !write_chunk(self.output_file, &chunk) {
                         return Err(ProcessorError::FileWrite)
                     }
-                    if !self.input_file.move_back_last_read() || !signature_file.move_back_last_read() {
-                        return Err(ProcessorError::FileSeek)
+                    output_len += chunk_size as u64;
+                }
+                TAG_ZERO_CHUNK => {
+                    // chunk of zeros, hole is left in sparse output
+                    let len = read_zero_chunk_len(delta_file, chunk_size)?;
+                    if !self.output_file.write_zeros(len as u64) {
+                        return Err(ProcessorError::FileWrite)
                     }
-                }             
-            }            
+                    output_len += len as u64;
+                }
+                _ => return Err(ProcessorError::InvalidDelta)
+            }
         }
-        
+        self.progress.report(ProgressPhase::Patch, delta_file);
+
         Ok(())
-    }    
-    
-    // Signature command logic
-    pub fn process_signature(&mut self) -> Result<(), ProcessorError>
+    }
+
+    // Patch command logic for identical delta, old file is copied and has to match length and digest of new file
+    pub fn process_identical_patch(&mut self, digest: (u64, [u8; HASH_SIZE]), chunk_size: usize) -> Result<(), ProcessorError>
         where T: ProcessorDataInput, S: ProcessorDataOutput
     {
-        loop {            
-            let input_file_chunk = self.input_file.get_next_data();
-            if input_file_chunk.is_empty() { break } // reached end of file
-             
-            let hash = calculate_chunk_hash(input_file_chunk);
-            
-            if !self.output_file.write_data(&hash) {
+        self.input_file.set_chunk_size(chunk_size);
+        let mut hasher = Sha256::new();
+        let mut len: u64 = 0;
+        loop {
+            self.progress.report(ProgressPhase::Patch, self.input_file);
+
+            let old_file_chunk = self.input_file.get_next_data();
+            if old_file_chunk.is_empty() { break } // reached end of file
+
+            hasher.update(old_file_chunk);
+            len += old_file_chunk.len() as u64;
+            if !write_chunk(self.output_file, old_file_chunk) {
                 return Err(ProcessorError::FileWrite)
             }
         }
-        
+
+        if (len, <[u8; HASH_SIZE]>::from(hasher.finalize())) != digest {
+            return Err(ProcessorError::OldFileMismatch)
+        }
         Ok(())
     }
 }
 
+// Kind of chunk range reported by signature comparison
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkChange {
+    Same,
+    Changed,
+    Inserted,
+    Removed
+}
+impl std::fmt::Display for ChunkChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChunkChange::Same => write!(f, "identical"),
+            ChunkChange::Changed => write!(f, "changed"),
+            ChunkChange::Inserted => write!(f, "inserted"),
+            ChunkChange::Removed => write!(f, "removed")
+        }
+    }
+}
+
+// Result of signature comparison, ranges are chunk indexes of old and new file
+// delta size is estimated with all chunks being full, so last chunk of new file can make it larger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureDiff {
+    pub ranges: Vec<(ChunkChange, std::ops::Range<u64>, std::ops::Range<u64>)>,
+    pub delta_size: u64
+}
+
+impl SignatureDiff {
+    fn add(&mut self, change: ChunkChange, old_chunks: u64, new_chunks: u64) {
+        let (old_end, new_end) = self.ranges.last().map(|(_, old, new)| (old.end, new.end)).unwrap_or((0, 0));
+        match self.ranges.last_mut() {
+            Some((last_change, old, new)) if *last_change == change => {
+                old.end += old_chunks;
+                new.end += new_chunks;
+            }
+            _ => self.ranges.push((change, old_end..old_end + old_chunks, new_end..new_end + new_chunks))
+        }
+    }
+
+    // Size of chunk which is not in old file, repeated chunk is copied from output of patch
+    fn add_new_chunk(&mut self, output_chunks: &mut OutputChunks, change: ChunkChange, tag: [u8; 1], hash: [u8; HASH_SIZE], chunk_size: usize) {
+        let reference = is_reference_smaller(tag, chunk_size);
+        self.delta_size += match output_chunks.find(&hash, chunk_size) {
+            _ if reference && hash == zero_chunk_hash(chunk_size) && tag == TAG_DIFFERENT_HASH => (2 * TAG_SIZE + ZERO_LEN_SIZE) as u64,
+            _ if reference && hash == zero_chunk_hash(chunk_size) => (TAG_SIZE + ZERO_LEN_SIZE) as u64,
+            Some(_) if reference && tag == TAG_DIFFERENT_HASH => (2 * TAG_SIZE + COPY_OFFSET_SIZE) as u64,
+            Some(_) if reference => (TAG_SIZE + COPY_OFFSET_SIZE) as u64,
+            _ => (TAG_SIZE + chunk_size) as u64
+        };
+        output_chunks.add(hash, chunk_size);
+        self.add(change, u64::from(change == ChunkChange::Changed), 1);
+    }
+
+    fn add_same_chunk(&mut self, output_chunks: &mut OutputChunks, hash: [u8; HASH_SIZE], chunk_size: usize) {
+        self.delta_size += TAG_SIZE as u64;
+        output_chunks.add(hash, chunk_size);
+        self.add(ChunkChange::Same, 1, 1);
+    }
+}
+
+// Sigdiff command logic, signatures are compared with the same alignment of chunks as in delta processing
+// chunks of old file left after the end of new file are reported as removed
+pub fn process_sigdiff<U, V>(old_signature: &mut U, new_signature: &mut V, chunk_size: usize) -> Result<SignatureDiff, ProcessorError>
+    where U: ProcessorDataInput, V: ProcessorDataInput
+{
+    old_signature.set_chunk_size(HASH_SIZE);
+    new_signature.set_chunk_size(HASH_SIZE);
+    let mut alignment = SigDiffAlignment { old_signature: &mut *old_signature, new_signature, chunk_size,
+                                           diff: SignatureDiff { ranges: Vec::new(), delta_size: 0 }, output_chunks: OutputChunks::new() };
+    align_chunks(&mut alignment)?;
+    let mut diff = alignment.diff;
+
+    while read_hash(old_signature)?.is_some() {
+        diff.add(ChunkChange::Removed, 1, 0);
+    }
+    Ok(diff)
+}
+
+// Update-signature command logic, signature of patched file is written without reading the file
+// chunks copied from old file keep their hashes from old signature, only chunks of delta are hashed
+// chunks of patched file have to stay aligned, as in deltas written by delta processing
+pub fn process_signature_update<U, V, S>(old_signature: &mut U, delta_file: &mut V, output_file: &mut S, chunk_size: usize) -> Result<(), ProcessorError>
+    where U: ProcessorDataInput, V: ProcessorDataInput, S: ProcessorDataOutput
+{
+    old_signature.set_chunk_size(HASH_SIZE);
+    let mut hashes: Vec<[u8; HASH_SIZE]> = Vec::new();
+
+    loop {
+        delta_file.set_chunk_size(TAG_SIZE);
+        let tag = match delta_file.get_next_data() {
+            [] => break, // reached end of file
+            tag => [tag[0]]
+        };
+        let hash = match tag {
+            TAG_SAME_HASH => {
+                // chunk is copied from old file
+                match read_hash(old_signature)? {
+                    Some(hash) => hash,
+                    None => return Err(ProcessorError::InvalidDelta)
+                }
+            }
+            TAG_DIFFERENT_HASH | TAG_INSERTED_CHUNK => {
+                if tag == TAG_DIFFERENT_HASH {
+                    // chunk of old file is replaced, it may not exist if data was appended
+                    read_hash(old_signature)?;
+                }
+                delta_file.set_chunk_size(chunk_size);
+                let delta_file_chunk = delta_file.get_next_data();
+                if delta_file_chunk.is_empty() {
+                    return Err(ProcessorError::InvalidDelta)
+                }
+                calculate_signature_hash(delta_file_chunk)
+            }
+            TAG_REMOVED_CHUNK => {
+                // skip chunk of old file
+                read_hash(old_signature)?;
+                continue
+            }
+            TAG_COPY_FROM_OUTPUT => {
+                // chunk copied from output has the same hash as chunk written earlier
+                delta_file.set_chunk_size(COPY_OFFSET_SIZE);
+                let offset = match <[u8; COPY_OFFSET_SIZE]>::try_from(delta_file.get_next_data()) {
+                    Ok(offset) => u64::from_le_bytes(offset),
+                    Err(_) => return Err(ProcessorError::InvalidDelta)
+                };
+                if offset % chunk_size as u64 != 0 {
+                    return Err(ProcessorError::InvalidDelta)
+                }
+                match hashes.get((offset / chunk_size as u64) as usize) {
+                    Some(hash) => *hash,
+                    None => return Err(ProcessorError::InvalidDelta)
+                }
+            }
+            TAG_ZERO_CHUNK => zero_chunk_hash(read_zero_chunk_len(delta_file, chunk_size)?),
+            _ => return Err(ProcessorError::InvalidDelta)
+        };
+
+        if !output_file.write_data(&hash) {
+            return Err(ProcessorError::FileWrite)
+        }
+        hashes.push(hash);
+    }
+    Ok(())
+}
+
+// Length of zero chunk, it cannot be longer than chunk size
+fn read_zero_chunk_len<U: ProcessorDataInput>(delta_file: &mut U, chunk_size: usize) -> Result<usize, ProcessorError> {
+    delta_file.set_chunk_size(ZERO_LEN_SIZE);
+    let len = match <[u8; ZERO_LEN_SIZE]>::try_from(delta_file.get_next_data()) {
+        Ok(len) => u64::from_le_bytes(len),
+        Err(_) => return Err(ProcessorError::InvalidDelta)
+    };
+    if len == 0 || len > chunk_size as u64 {
+        return Err(ProcessorError::InvalidDelta)
+    }
+    Ok(len as usize)
+}
+
+// Writes chunk of new file, chunk of zeros is written as zeros so sparse output can leave a hole
+fn write_chunk<S: ProcessorDataOutput>(output_file: &mut S, chunk: &[u8]) -> bool {
+    if is_zero_chunk(chunk) {
+        output_file.write_zeros(chunk.len() as u64)
+    } else {
+        output_file.write_data(chunk)
+    }
+}
+
+// Copies hashes of signature, signature of unchanged file stays the same
+pub fn copy_signature<U, S>(signature_file: &mut U, output_file: &mut S) -> Result<(), ProcessorError>
+    where U: ProcessorDataInput, S: ProcessorDataOutput
+{
+    signature_file.set_chunk_size(HASH_SIZE);
+    while let Some(hash) = read_hash(signature_file)? {
+        if !output_file.write_data(&hash) {
+            return Err(ProcessorError::FileWrite)
+        }
+    }
+    Ok(())
+}
+
+fn read_hash<U: ProcessorDataInput>(signature_file: &mut U) -> Result<Option<[u8; HASH_SIZE]>, ProcessorError> {
+    match signature_file.get_next_data() {
+        [] => Ok(None),
+        hash => <[u8; HASH_SIZE]>::try_from(hash).map(Some).map_err(|_| ProcessorError::InvalidSignature)
+    }
+}
+
+// Hash of all data from input
+pub fn calculate_data_digest<T: ProcessorDataInput>(input_file: &mut T) -> [u8; HASH_SIZE] {
+    let mut hasher = Sha256::new();
+    loop {
+        let chunk = input_file.get_next_data();
+        if chunk.is_empty() { break } // reached end of file
+        hasher.update(chunk);
+    }
+    hasher.finalize().into()
+}
+
+// Chunk size close to square root of file length, rounded up to power of two
+// larger chunks give smaller signature, smaller chunks give smaller delta for scattered changes
+pub fn auto_chunk_size(len: u64) -> usize {
+    ((len as f64).sqrt() as u64).next_power_of_two().clamp(MIN_AUTO_CHUNK_SIZE as u64, MAX_AUTO_CHUNK_SIZE as u64) as usize
+}
+
 // Hash calculation
-fn calculate_chunk_hash(chunk: &[u8]) -> [u8; HASH_SIZE] {
+pub fn calculate_chunk_hash(chunk: &[u8]) -> [u8; HASH_SIZE] {
     let mut hasher = Sha256::new();
     hasher.update(chunk);
     let ret = hasher.finalize();
     ret.into()
 }
 
+// Hash of chunk written to signature, chunk of zeros is not hashed and zero chunk record is used instead
+pub fn calculate_signature_hash(chunk: &[u8]) -> [u8; HASH_SIZE] {
+    if is_zero_chunk(chunk) {
+        zero_chunk_hash(chunk.len())
+    } else {
+        calculate_chunk_hash(chunk)
+    }
+}
+
+// Zero chunk record: zero bytes followed by u64 chunk length, so zero chunks of different length are different
+pub fn zero_chunk_hash(chunk_len: usize) -> [u8; HASH_SIZE] {
+    let mut hash = [0; HASH_SIZE];
+    hash[ZERO_RECORD_PREFIX_SIZE..].copy_from_slice(&(chunk_len as u64).to_le_bytes());
+    hash
+}
+
+pub fn is_zero_chunk(chunk: &[u8]) -> bool {
+    !chunk.is_empty() && chunk.iter().all(|byte| *byte == 0)
+}
+
 
 // Processor tests
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::file_header::*;
 
     // helper object for testing processor
     struct MemData {
@@ -194,12 +874,24 @@ mod tests {
             self.location -= self.last_read_size;
             true  
         }
+        fn set_chunk_size(&mut self, chunk_size: usize) {
+            self.chunk_size = chunk_size;
+        }
+        fn total_len(&self) -> u64 {
+            self.data.len() as u64
+        }
+        fn remaining_len(&self) -> u64 {
+            self.data.len().saturating_sub(self.location) as u64
+        }
     }
     impl ProcessorDataOutput for MemData {
         fn write_data(&mut self, data: &[u8]) -> bool {
             self.data.extend_from_slice(data);
             true
         }
+        fn read_back(&mut self, offset: u64, data: &mut [u8]) -> bool {
+            self.data.read_back(offset, data)
+        }
     }
 
     #[test]
@@ -294,6 +986,23 @@ mod tests {
         assert_eq!( output.data, [] );
     }
     
+    #[test]
+    fn test_sig_zero() {
+        // signature test
+        // scenario: chunks of zeros are written as zero chunk records with their length
+
+        let mut input = MemData::new_input(4, &[0,0,0,0,1,2,3,4,0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( proc.process_signature().is_ok() );
+
+        let mut output_hash = zero_chunk_hash(4).to_vec();
+        output_hash.extend_from_slice(&calculate_chunk_hash(&[1,2,3,4]));
+        output_hash.extend_from_slice(&[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2,0,0,0,0,0,0,0]);
+        assert_eq!( output.data, output_hash );
+    }
+
     #[test]
     fn test_del_1() {
         // delta test
@@ -538,5 +1247,405 @@ mod tests {
 
         assert_eq!( output.data, [0,3,0,0] );
     }
-}
 
+    fn signature(chunk_size: usize, data: &[u8]) -> Vec<u8> {
+        let mut output = MemData::new_output();
+        Processor::new(&mut MemData::new_input(chunk_size, data), &mut output).process_signature().unwrap();
+        output.data
+    }
+
+    #[test]
+    fn test_del_zero() {
+        // delta test
+        // scenario: changed, inserted and appended chunks of zeros are written only as their length,
+        //           chunks smaller than zero chunk record are written as data (chunk size: 16)
+        // old file: 1 x 16, 2 x 16, 3 x 16
+        // new file: 0 x 16, 2 x 16, 0 x 16, 3 x 16, 0 x 12 | 0 x 2
+
+        let old = [[1; 16], [2; 16], [3; 16]].concat();
+        let new = [[0; 16], [2; 16], [0; 16], [3; 16]].concat();
+        for (tail, tail_delta) in [(&[0; 12][..], &[3,5,12,0,0,0,0,0,0,0][..]), (&[0; 2][..], &[1,0,0][..])] {
+            let mut input = MemData::new_input(16, &[&new[..], tail].concat());
+            let mut input_sig = MemData::new_input(HASH_SIZE, &signature(16, &old));
+            let mut output = MemData::new_output();
+
+            let mut proc = Processor::new(&mut input, &mut output);
+            assert!( proc.process_delta(&mut input_sig).is_ok() );
+
+            assert_eq!( output.data, [&[3,5,16,0,0,0,0,0,0,0, 0, 5,16,0,0,0,0,0,0,0, 0][..], tail_delta].concat() );
+        }
+
+        let mut input = MemData::new_input(4, &[0,0,0,0,5,6,7,8]);
+        let mut input_sig = MemData::new_input(HASH_SIZE, &signature(4, &[1,2,3,4,5,6,7,8]));
+        let mut output = MemData::new_output();
+        assert!( Processor::new(&mut input, &mut output).process_delta(&mut input_sig).is_ok() );
+        assert_eq!( output.data, [1,0,0,0,0, 0] );
+    }
+
+    #[test]
+    fn test_del_copy() {
+        // delta test
+        // scenario: chunk repeated in new file is copied from output, unless copy record is larger than chunk (chunk size: 16, 4)
+        // old file: 1 x 16
+        // new file: 1 x 16, 5..20, 5..20
+
+        let repeated: Vec<u8> = (5..=20).collect();
+        let new = [&[1; 16][..], &repeated, &repeated].concat();
+        let mut input = MemData::new_input(16, &new);
+        let mut input_sig = MemData::new_input(HASH_SIZE, &signature(16, &[1; 16]));
+        let mut output = MemData::new_output();
+        assert!( Processor::new(&mut input, &mut output).process_delta(&mut input_sig).is_ok() );
+        assert_eq!( output.data, [&[0, 1][..], &repeated, &[3, 4,16,0,0,0,0,0,0,0]].concat() );
+
+        let mut input = MemData::new_input(4, &[1,2,3,4,5,6,7,8,5,6,7,8]);
+        let mut input_sig = MemData::new_input(HASH_SIZE, &signature(4, &[1,2,3,4]));
+        let mut output = MemData::new_output();
+        assert!( Processor::new(&mut input, &mut output).process_delta(&mut input_sig).is_ok() );
+        assert_eq!( output.data, [0,1,5,6,7,8,1,5,6,7,8] );
+    }
+
+    #[test]
+    fn test_sigdiff_1() {
+        // signature comparison test
+        // scenario: changed, inserted and removed chunks are found, estimated size equals size of delta (chunk size: 4)
+        // old file: 1,2,3,4, 5,6,7,8, 9,9,9,9, 1,1,1,1, 2,2,2,2
+        // new file: 1,2,3,4, 0,0,0,0, 5,6,7,8, 9,9,9,9, 2,2,2,2, 3,3,3,3, 1,2,3,4
+
+        let old = [1,2,3,4,5,6,7,8,9,9,9,9,1,1,1,1,2,2,2,2];
+        let new = [1,2,3,4,0,0,0,0,5,6,7,8,9,9,9,9,2,2,2,2,3,3,3,3,1,2,3,4];
+        let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+        let mut new_sig = MemData::new_input(HASH_SIZE, &signature(4, &new));
+        let diff = process_sigdiff(&mut old_sig, &mut new_sig, 4).unwrap();
+        assert_eq!( diff.ranges, [(ChunkChange::Same, 0..1, 0..1), (ChunkChange::Inserted, 1..1, 1..2), (ChunkChange::Same, 1..3, 2..4),
+                                  (ChunkChange::Removed, 3..4, 4..4), (ChunkChange::Same, 4..5, 4..5), (ChunkChange::Inserted, 5..5, 5..7)] );
+
+        let mut output = MemData::new_output();
+        let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+        assert!( Processor::new(&mut MemData::new_input(4, &new), &mut output).process_delta(&mut old_sig).is_ok() );
+        assert_eq!( diff.delta_size, output.data.len() as u64 );
+    }
+
+    #[test]
+    fn test_sigdiff_2() {
+        // signature comparison test
+        // scenario: changed chunk, chunk repeated in new file and removed end of old file (chunk size: 4)
+        // old file: 1,2,3,4, 5,6,7,8, 9,9,9,9, 9,9,9,9
+        // new file: 1,2,3,4, 0,0,0,0, 0,0,0,0
+
+        let old = [1,2,3,4,5,6,7,8,9,9,9,9,9,9,9,9];
+        let new = [1,2,3,4,0,0,0,0,0,0,0,0];
+        let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+        let mut new_sig = MemData::new_input(HASH_SIZE, &signature(4, &new));
+        let diff = process_sigdiff(&mut old_sig, &mut new_sig, 4).unwrap();
+        assert_eq!( diff.ranges, [(ChunkChange::Same, 0..1, 0..1), (ChunkChange::Changed, 1..3, 1..3), (ChunkChange::Removed, 3..4, 3..3)] );
+
+        let mut output = MemData::new_output();
+        let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+        assert!( Processor::new(&mut MemData::new_input(4, &new), &mut output).process_delta(&mut old_sig).is_ok() );
+        assert_eq!( diff.delta_size, output.data.len() as u64 );
+
+        let mut old_sig = MemData::new_input(HASH_SIZE, &[0; 40]);
+        let mut new_sig = MemData::new_input(HASH_SIZE, &signature(4, &new));
+        assert!( matches!( process_sigdiff(&mut old_sig, &mut new_sig, 4), Err(ProcessorError::InvalidSignature) ) );
+    }
+
+    fn updated_signature(old: &[u8], new: &[u8]) -> Result<Vec<u8>, ProcessorError> {
+        let mut delta = MemData::new_output();
+        let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, old));
+        assert!( Processor::new(&mut MemData::new_input(4, new), &mut delta).process_delta(&mut old_sig).is_ok() );
+
+        let mut output = MemData::new_output();
+        let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, old));
+        process_signature_update(&mut old_sig, &mut MemData::new_input(1, &delta.data), &mut output, 4)?;
+        Ok(output.data)
+    }
+
+    #[test]
+    fn test_sig_digest() {
+        // signature test
+        // scenario: length and digest of whole input are calculated together with signature
+
+        let data = [1,2,3,4,5,6,7,8,9,0];
+        let mut output = MemData::new_output();
+        let digest = Processor::new(&mut MemData::new_input(4, &data), &mut output).process_signature_digest().unwrap();
+        assert_eq!( digest, (10, calculate_data_digest(&mut MemData::new_input(3, &data))) );
+        assert_eq!( output.data, signature(4, &data) );
+    }
+
+    #[test]
+    fn test_del_digest() {
+        // delta test
+        // scenario: length and digest of whole new file are calculated together with delta,
+        //           chunks read again after inserted or removed chunk are counted once (chunk size: 4)
+
+        let old = [1,2,3,4,5,6,7,8,9,9,9,9,1,1,1,1];
+        for new in [&[0,0,0,0,1,2,3,4,5,6,7,8,9,9,9,9,1,1,1,1][..], &[1,2,3,4,9,9,9,9,1,1,1,1,2][..], &[7,7,7,7,7,7][..]] {
+            let mut delta = MemData::new_output();
+            let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+            let digest = Processor::new(&mut MemData::new_input(4, new), &mut delta).process_delta_digest(&mut old_sig).unwrap();
+            assert_eq!( digest, (new.len() as u64, calculate_data_digest(&mut MemData::new_input(4, new))) );
+
+            let mut expected = MemData::new_output();
+            let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+            Processor::new(&mut MemData::new_input(4, new), &mut expected).process_delta(&mut old_sig).unwrap();
+            assert_eq!( delta.data, expected.data );
+        }
+    }
+
+    #[test]
+    fn test_sig_update_1() {
+        // signature update test
+        // scenario: signature updated from delta is same as signature of patched file (chunk size: 4)
+
+        let old = [1,2,3,4,5,6,7,8,9,9,9,9,1,1,1,1,2,2,2,2];
+        for new in [&[1,2,3,4,0,0,0,0,5,6,7,8,9,9,9,9,2,2,2,2,3,3,3,3,1,2,3,4][..],
+                    &[1,2,3,4,5,6,7,8,9,9,9,9,1,1,1,1,2,2,2,2,7,7][..],
+                    &[0,0,0,0,0,0,0,0,0,0,0,0][..],
+                    &[1,2,3,4,6,6,6,6,9,9,9,9][..],
+                    &[1,2][..],
+                    &[][..]] {
+            assert_eq!( updated_signature(&old, new).unwrap(), signature(4, new) );
+        }
+        assert_eq!( updated_signature(&[], &[1,2,3,4,1,2,3,4,5]).unwrap(), signature(4, &[1,2,3,4,1,2,3,4,5]) );
+    }
+
+    // signature file with header as written by signature and update-signature commands
+    fn signature_file(header: &FileHeader, hashes: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        header.write(&mut output).unwrap();
+        output.extend_from_slice(hashes);
+        output
+    }
+
+    #[test]
+    fn test_sig_update_file() {
+        // signature update test
+        // scenario: updated signature file is the same as signature file of patched file including header with digest of whole file,
+        //           signature updated from delta of identical file keeps digest (chunk size: 4)
+
+        let old = [1,2,3,4,5,6,7,8,9,9,9,9];
+        for new in [&[1,2,3,4,0,0,0,0,9,9,9,9,7][..], &[0,0,0,0,1,2,3,4,5,6,7,8,9,9,9,9][..], &[1,2,3,4,9,9,9,9][..], &[][..]] {
+            let mut new_header = FileHeader::new(FileKind::Signature, 4, None);
+            new_header.digest = Some((new.len() as u64, calculate_data_digest(&mut MemData::new_input(4, new))));
+            let new_file = signature_file(&new_header, &signature(4, new));
+
+            let mut delta = MemData::new_output();
+            let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+            let mut delta_header = FileHeader::new(FileKind::Delta, 4, None);
+            delta_header.digest = Some(Processor::new(&mut MemData::new_input(4, new), &mut delta).process_delta_digest(&mut old_sig).unwrap());
+
+            let mut updated_file = Vec::new();
+            FileHeader::updated_signature(4, Some(&delta_header)).write(&mut updated_file).unwrap();
+            let mut old_sig = MemData::new_input(HASH_SIZE, &signature(4, &old));
+            process_signature_update(&mut old_sig, &mut MemData::new_input(1, &delta.data), &mut updated_file, 4).unwrap();
+            assert_eq!( updated_file, new_file );
+        }
+
+        let mut delta_header = FileHeader::new(FileKind::Delta, 4, None);
+        delta_header.identical = true;
+        delta_header.digest = Some((old.len() as u64, calculate_data_digest(&mut MemData::new_input(4, &old))));
+        let mut old_header = FileHeader::new(FileKind::Signature, 4, None);
+        old_header.digest = delta_header.digest;
+        let updated_file = signature_file(&FileHeader::updated_signature(4, Some(&delta_header)), &signature(4, &old));
+        assert_eq!( updated_file, signature_file(&old_header, &signature(4, &old)) );
+    }
+
+    #[test]
+    fn test_sig_update_invalid() {
+        // signature update test
+        // scenario: delta which does not keep chunks aligned or refers to missing chunks is refused (chunk size: 4)
+
+        let old_sig = signature(4, &[1,2,3,4]);
+        for delta in [&[4,0,0,0,0,0,0,0,0][..], &[2,1,2,3,4,4,0,0][..], &[2,1,2,3,4,4,2,0,0,0,0,0,0,0][..], &[2,1,2,3,4,4,4,0,0,0,0,0,0,0][..], &[0,0][..], &[5][..]] {
+            let mut output = MemData::new_output();
+            let result = process_signature_update(&mut MemData::new_input(HASH_SIZE, &old_sig), &mut MemData::new_input(1, delta), &mut output, 4);
+            assert!( matches!( result, Err(ProcessorError::InvalidDelta) ) );
+        }
+    }
+
+    #[test]
+    fn test_patch_1() {
+        // patch test
+        // scenario: delta contains only same chunks
+        // old file: 1,2,3,4, 1,2,3,4, 5,6
+
+        let mut input = MemData::new_input(4, &[1,2,3,4,1,2,3,4,5,6]);
+        let mut input_delta = MemData::new_input(4, &[0,0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( proc.process_patch(&mut input_delta, 4).is_ok() );
+
+        assert_eq!( output.data, [1,2,3,4,1,2,3,4,5,6] );
+    }
+
+    #[test]
+    fn test_patch_2() {
+        // patch test
+        // scenario: delta from test_del_10 applied to its old file
+        // old file: 1,2,3,4, 1,2,3,4, 9,0,1,2, 1,2,3,4, 1,2,3,4, 5,6
+        // new file: 1,2,3,4, 1,2,3,4, 5,6,7,8, 1,2,3,4, 1,2,3,4, 5,6
+
+        let mut input = MemData::new_input(4, &[1,2,3,4,1,2,3,4,9,0,1,2,1,2,3,4,1,2,3,4,5,6]);
+        let mut input_delta = MemData::new_input(4, &[0,0,1,5,6,7,8,0,0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( proc.process_patch(&mut input_delta, 4).is_ok() );
+
+        assert_eq!( output.data, [1,2,3,4,1,2,3,4,5,6,7,8,1,2,3,4,1,2,3,4,5,6] );
+    }
+
+    #[test]
+    fn test_patch_3() {
+        // patch test
+        // scenario: delta with inserted, removed and appended chunks
+        // old file: 1,2,3,4, 5,6,7,8, 1,2,3,4
+        // new file: 1,2,3,4, 9,9,9,9, 1,2,3,4, 5,6
+
+        let mut input = MemData::new_input(4, &[1,2,3,4,5,6,7,8,1,2,3,4]);
+        let mut input_delta = MemData::new_input(4, &[0,3,2,9,9,9,9,0,1,5,6]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( proc.process_patch(&mut input_delta, 4).is_ok() );
+
+        assert_eq!( output.data, [1,2,3,4,9,9,9,9,1,2,3,4,5,6] );
+    }
+
+    #[test]
+    fn test_patch_copy() {
+        // patch test
+        // scenario: delta of files from test_del_9 with repeated chunk copied from output applied to its old file
+        // old file: 1,2,3,4, 1,2,3,4, 1,2,3,4
+        // new file: 5,6,7,8, 5,6,7,8, 5,6,7,8, 5,6,7,8
+
+        let mut input = MemData::new_input(4, &[1,2,3,4,1,2,3,4,1,2,3,4]);
+        let mut input_delta = MemData::new_input(4, &[1,5,6,7,8,3,4,0,0,0,0,0,0,0,0,3,4,0,0,0,0,0,0,0,0,3,4,0,0,0,0,0,0,0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( proc.process_patch(&mut input_delta, 4).is_ok() );
+
+        assert_eq!( output.data, [5,6,7,8,5,6,7,8,5,6,7,8,5,6,7,8] );
+    }
+
+    #[test]
+    fn test_patch_copy_invalid() {
+        // patch test
+        // scenario: delta copies data which was not written to output yet
+
+        let mut input = MemData::new_input(4, &[1,2,3,4]);
+        let mut input_delta = MemData::new_input(4, &[1,5,6,7,8,4,1,0,0,0,0,0,0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( matches!( proc.process_patch(&mut input_delta, 4), Err(ProcessorError::InvalidDelta) ) );
+
+        let mut input = MemData::new_input(4, &[1,2,3,4]);
+        let mut input_delta = MemData::new_input(4, &[1,5,6,7,8,4,0,0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( matches!( proc.process_patch(&mut input_delta, 4), Err(ProcessorError::InvalidDelta) ) );
+    }
+
+    #[test]
+    fn test_patch_zero() {
+        // patch test
+        // scenario: delta from test_del_zero applied to its old file, zero chunk longer than chunk size is refused
+
+        let mut input = MemData::new_input(4, &[1,2,3,4,5,6,7,8,1,1,1,1]);
+        let mut input_delta = MemData::new_input(4, &[3,5,4,0,0,0,0,0,0,0, 0, 5,4,0,0,0,0,0,0,0, 0, 3,5,2,0,0,0,0,0,0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( proc.process_patch(&mut input_delta, 4).is_ok() );
+        assert_eq!( output.data, [0,0,0,0,5,6,7,8,0,0,0,0,1,1,1,1,0,0] );
+
+        for delta in [&[5,5,0,0,0,0,0,0,0][..], &[5,0,0,0,0,0,0,0,0][..], &[5,4,0][..]] {
+            let mut input = MemData::new_input(4, &[]);
+            let mut output = MemData::new_output();
+            let result = Processor::new(&mut input, &mut output).process_patch(&mut MemData::new_input(4, delta), 4);
+            assert!( matches!( result, Err(ProcessorError::InvalidDelta) ) );
+        }
+    }
+
+    #[test]
+    fn test_patch_identical() {
+        // patch test
+        // scenario: old file is copied for identical delta, old file with different digest or length is refused
+
+        let old = [1,2,3,4,0,0,0,0,5];
+        let digest = (old.len() as u64, calculate_chunk_hash(&old));
+        let mut input = MemData::new_input(4, &old);
+        let mut output = MemData::new_output();
+        assert!( Processor::new(&mut input, &mut output).process_identical_patch(digest, 4).is_ok() );
+        assert_eq!( output.data, old );
+
+        for old in [&[1,2,3,4,0,0,0,0,6][..], &[1,2,3,4,0,0,0,0][..]] {
+            let mut input = MemData::new_input(4, old);
+            let mut output = MemData::new_output();
+            let result = Processor::new(&mut input, &mut output).process_identical_patch(digest, 4);
+            assert!( matches!( result, Err(ProcessorError::OldFileMismatch) ) );
+        }
+    }
+
+    #[test]
+    fn test_patch_invalid() {
+        // patch test
+        // scenario: delta refers to chunk which does not exist in old file
+
+        let mut input = MemData::new_input(4, &[1,2,3,4]);
+        let mut input_delta = MemData::new_input(4, &[0,0]);
+        let mut output = MemData::new_output();
+
+        let mut proc = Processor::new(&mut input, &mut output);
+        assert!( proc.process_patch(&mut input_delta, 4).is_err() );
+    }
+
+    #[test]
+    fn test_auto_chunk_size() {
+        // chunk size test
+        // scenario: chunk size follows square root of file length within limits
+
+        assert_eq!( auto_chunk_size(0), MIN_AUTO_CHUNK_SIZE );
+        assert_eq!( auto_chunk_size(1024 * 1024), 1024 );
+        assert_eq!( auto_chunk_size(1025 * 1025), 2048 );
+        assert_eq!( auto_chunk_size(4 * 1024 * 1024 * 1024), 64 * 1024 );
+        assert_eq!( auto_chunk_size(u64::MAX), MAX_AUTO_CHUNK_SIZE );
+    }
+
+    #[test]
+    fn test_progress_1() {
+        // progress test
+        // scenario: progress of small file is reported once when processing is finished
+
+        let mut input = MemData::new_input(4, &[1,2,3,4,5,6,7,8,9,0]);
+        let mut output = MemData::new_output();
+        let mut reports = Vec::new();
+        let mut callback = |progress: &Progress| reports.push(*progress);
+
+        let mut proc = Processor::new(&mut input, &mut output).with_progress(&mut callback);
+        assert!( proc.process_signature().is_ok() );
+
+        assert_eq!( reports, [Progress { phase: ProgressPhase::Signature, processed_bytes: 10, total_bytes: 10 }] );
+    }
+
+    #[test]
+    fn test_progress_2() {
+        // progress test
+        // scenario: progress of large file is reported in intervals
+
+        let data = vec![1; 3 * 1024 * 1024];
+        let mut input = MemData::new_input(1024, &data);
+        let mut output = MemData::new_output();
+        let mut reports = Vec::new();
+        let mut callback = |progress: &Progress| reports.push(progress.processed_bytes);
+
+        let mut proc = Processor::new(&mut input, &mut output).with_progress(&mut callback);
+        assert!( proc.process_signature().is_ok() );
+
+        assert_eq!( reports, [1024 * 1024, 2 * 1024 * 1024, 3 * 1024 * 1024] );
+    }
+}
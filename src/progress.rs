@@ -0,0 +1,96 @@
+use std::{io::Write, time::{Duration, Instant}};
+use super::processor::*;
+
+// Minimal time between redraws of progress bar
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+// Number of characters of progress bar
+const BAR_WIDTH: usize = 30;
+
+
+
+// Progress bar drawn in a single line of stderr
+pub struct ProgressBar {
+    enabled: bool,
+    last_draw: Option<Instant>
+}
+
+impl ProgressBar {
+
+    // Disabled progress bar ignores all updates, e.g. when stderr is not a terminal
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, last_draw: None }
+    }
+
+    pub fn update(&mut self, progress: &Progress) {
+        if !self.enabled {
+            return
+        }
+        let finished = progress.processed_bytes == progress.total_bytes;
+        if let Some(last_draw) = self.last_draw {
+            if !finished && last_draw.elapsed() < REDRAW_INTERVAL {
+                return
+            }
+        }
+        self.last_draw = Some(Instant::now());
+
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "\r{}", format_progress(progress));
+        let _ = stderr.flush();
+    }
+
+    // Moves to next line, so following messages do not overwrite progress bar
+    pub fn finish(&mut self) {
+        if self.last_draw.take().is_some() {
+            eprintln!();
+        }
+    }
+}
+
+
+fn format_progress(progress: &Progress) -> String {
+    let phase = match progress.phase {
+        ProgressPhase::Signature => "signature",
+        ProgressPhase::Delta => "delta",
+        ProgressPhase::Patch => "patch"
+    };
+    let percent = match progress.processed_bytes.saturating_mul(100).checked_div(progress.total_bytes) {
+        Some(percent) => std::cmp::min(percent, 100) as usize,
+        None => 100 // empty file
+    };
+    let filled = percent * BAR_WIDTH / 100;
+
+    format!("{:<9} [{}{}] {:>3}% {} / {}", phase, "#".repeat(filled), "-".repeat(BAR_WIDTH - filled), percent,
+            format_size(progress.processed_bytes), format_size(progress.total_bytes))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes)
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+
+// Progress bar tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_progress() {
+        let progress = Progress { phase: ProgressPhase::Delta, processed_bytes: 512 * 1024 * 1024, total_bytes: 2 * 1024 * 1024 * 1024 };
+        assert_eq!( format_progress(&progress), "delta     [#######-----------------------]  25% 512.0 MiB / 2.0 GiB" );
+
+        let progress = Progress { phase: ProgressPhase::Patch, processed_bytes: 0, total_bytes: 0 };
+        assert_eq!( format_progress(&progress), "patch     [##############################] 100% 0 B / 0 B" );
+    }
+}
@@ -0,0 +1,555 @@
+use std::{collections::HashMap, io::{Read, Seek, SeekFrom}};
+use blake2::{Blake2b, digest::consts::U32};
+use md4::{Digest, Md4};
+use super::processor::*;
+
+// Magic numbers of librsync files, signature magic selects weak and strong checksum
+pub const RS_DELTA_MAGIC: u32 = 0x72730236;
+pub const RS_MD4_SIG_MAGIC: u32 = 0x72730136;
+pub const RS_BLAKE2_SIG_MAGIC: u32 = 0x72730137;
+pub const RS_RK_MD4_SIG_MAGIC: u32 = 0x72730146;
+pub const RS_RK_BLAKE2_SIG_MAGIC: u32 = 0x72730147;
+
+const MD4_SIZE: usize = 16;
+const BLAKE2_SIZE: usize = 32;
+const WEAK_SUM_SIZE: usize = 4;
+
+// Larger blocks are refused, so malformed signature cannot exhaust memory
+const MAX_BLOCK_LEN: usize = 64 * 1024 * 1024;
+
+// delta commands, literal and copy commands are followed by big endian integers of 1, 2, 4 or 8 bytes
+const OP_END: u8 = 0x00;
+const OP_LITERAL_1: u8 = 0x01;
+const OP_LITERAL_64: u8 = 0x40;
+const OP_LITERAL_N1: u8 = 0x41;
+const OP_LITERAL_N8: u8 = 0x44;
+const OP_COPY_N1_N1: u8 = 0x45;
+const OP_COPY_N8_N8: u8 = 0x54;
+
+// Literal data is written when it reaches this size, copied data is read in parts of the same size
+const LITERAL_BUFFER_SIZE: usize = 64 * 1024;
+
+// rsync rolling checksum, every byte is offset so runs of zero bytes change the checksum
+const ROLLSUM_CHAR_OFFSET: u32 = 31;
+
+// Rabin-Karp rolling hash, inverse of multiplier is used to remove bytes from the start of the window
+const RABINKARP_SEED: u32 = 1;
+const RABINKARP_MULT: u32 = 0x08104225;
+const RABINKARP_INVM: u32 = 0x98f009ad;
+const RABINKARP_ADJ: u32 = 0x08104224;
+
+
+// Weak checksum of a window, it can be moved by one byte without reading whole window
+#[derive(Debug, Clone, Copy)]
+pub enum WeakSum {
+    Rollsum { count: u32, s1: u32, s2: u32 },
+    RabinKarp { hash: u32, mult: u32 }
+}
+
+impl WeakSum {
+    pub fn new(rabin_karp: bool, data: &[u8]) -> Self {
+        let mut sum = if rabin_karp { WeakSum::RabinKarp { hash: RABINKARP_SEED, mult: 1 } } else { WeakSum::Rollsum { count: 0, s1: 0, s2: 0 } };
+        for byte in data {
+            match &mut sum {
+                WeakSum::Rollsum { count, s1, s2 } => {
+                    *s1 = s1.wrapping_add(*byte as u32 + ROLLSUM_CHAR_OFFSET);
+                    *s2 = s2.wrapping_add(*s1);
+                    *count += 1;
+                }
+                WeakSum::RabinKarp { hash, mult } => {
+                    *hash = hash.wrapping_mul(RABINKARP_MULT).wrapping_add(*byte as u32);
+                    *mult = mult.wrapping_mul(RABINKARP_MULT);
+                }
+            }
+        }
+        sum
+    }
+
+    // Window moves by one byte
+    pub fn rotate(&mut self, out: u8, input: u8) {
+        match self {
+            WeakSum::Rollsum { count, s1, s2 } => {
+                *s1 = s1.wrapping_add(input as u32).wrapping_sub(out as u32);
+                *s2 = s2.wrapping_add(*s1).wrapping_sub(count.wrapping_mul(out as u32 + ROLLSUM_CHAR_OFFSET));
+            }
+            WeakSum::RabinKarp { hash, mult } => {
+                *hash = hash.wrapping_mul(RABINKARP_MULT).wrapping_add(input as u32).wrapping_sub(mult.wrapping_mul(out as u32 + RABINKARP_ADJ));
+            }
+        }
+    }
+
+    // First byte of window is removed, used at the end of file
+    pub fn rollout(&mut self, out: u8) {
+        match self {
+            WeakSum::Rollsum { count, s1, s2 } => {
+                *s1 = s1.wrapping_sub(out as u32 + ROLLSUM_CHAR_OFFSET);
+                *s2 = s2.wrapping_sub(count.wrapping_mul(out as u32 + ROLLSUM_CHAR_OFFSET));
+                *count -= 1;
+            }
+            WeakSum::RabinKarp { hash, mult } => {
+                *mult = mult.wrapping_mul(RABINKARP_INVM);
+                *hash = hash.wrapping_sub(mult.wrapping_mul(out as u32 + RABINKARP_ADJ));
+            }
+        }
+    }
+
+    pub fn digest(&self) -> u32 {
+        match self {
+            WeakSum::Rollsum { s1, s2, .. } => (s2 << 16) | (s1 & 0xffff),
+            WeakSum::RabinKarp { hash, .. } => *hash
+        }
+    }
+}
+
+
+// Format of librsync signature and delta files, used by rdiff
+// signature format:
+// u32 magic, u32 block length, u32 strong checksum length, weak and strong checksum of every block
+// delta format:
+// u32 magic, commands which copy data from old file or add literal data, end command
+// all integers are big endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RdiffFormat {
+    magic: u32,
+    block_len: usize,
+    strong_len: usize
+}
+
+impl RdiffFormat {
+
+    // Signature written with rsync rolling checksum and BLAKE2, it is readable by all librsync versions since 1.0
+    pub fn new(block_len: usize) -> Self {
+        Self { magic: RS_BLAKE2_SIG_MAGIC, block_len, strong_len: BLAKE2_SIZE }
+    }
+
+    // Reads header of signature file, input is left at checksums of first block
+    pub fn read_signature_header<U: ProcessorDataInput>(signature_file: &mut U) -> Result<Self, ProcessorError> {
+        signature_file.set_chunk_size(12);
+        let header = signature_file.get_next_data();
+        if header.len() != 12 {
+            return Err(ProcessorError::InvalidSignature)
+        }
+        let magic = read_u32(&header[0..4]);
+        let block_len = read_u32(&header[4..8]) as usize;
+        let strong_len = read_u32(&header[8..12]) as usize;
+        let max_strong_len = match magic {
+            RS_MD4_SIG_MAGIC | RS_RK_MD4_SIG_MAGIC => MD4_SIZE,
+            RS_BLAKE2_SIG_MAGIC | RS_RK_BLAKE2_SIG_MAGIC => BLAKE2_SIZE,
+            _ => return Err(ProcessorError::InvalidSignature)
+        };
+        if block_len == 0 || block_len > MAX_BLOCK_LEN || strong_len == 0 || strong_len > max_strong_len {
+            return Err(ProcessorError::InvalidSignature)
+        }
+        Ok(Self { magic, block_len, strong_len })
+    }
+
+    pub fn block_len(&self) -> usize {
+        self.block_len
+    }
+
+    fn rabin_karp(&self) -> bool {
+        self.magic == RS_RK_MD4_SIG_MAGIC || self.magic == RS_RK_BLAKE2_SIG_MAGIC
+    }
+
+    // Strong checksum truncated to length stored in signature
+    fn strong_sum(&self, data: &[u8]) -> Vec<u8> {
+        let mut sum = match self.magic {
+            RS_MD4_SIG_MAGIC | RS_RK_MD4_SIG_MAGIC => Md4::digest(data).to_vec(),
+            _ => Blake2b::<U32>::digest(data).to_vec()
+        };
+        sum.truncate(self.strong_len);
+        sum
+    }
+
+}
+
+impl DeltaFormat for RdiffFormat {
+    fn process_signature(&self, input_file: &mut dyn ProcessorDataInput, output_file: &mut dyn ProcessorDataOutput,
+                         progress: &mut ProgressReporter) -> Result<(), ProcessorError> {
+        let mut header = Vec::with_capacity(12);
+        header.extend_from_slice(&self.magic.to_be_bytes());
+        header.extend_from_slice(&(self.block_len as u32).to_be_bytes());
+        header.extend_from_slice(&(self.strong_len as u32).to_be_bytes());
+        if !output_file.write_data(&header) {
+            return Err(ProcessorError::FileWrite)
+        }
+
+        input_file.set_chunk_size(self.block_len);
+        loop {
+            progress.report(ProgressPhase::Signature, &*input_file);
+
+            let block = input_file.get_next_data();
+            if block.is_empty() { break } // reached end of file
+
+            let weak_sum = WeakSum::new(self.rabin_karp(), block).digest();
+            if !output_file.write_data(&weak_sum.to_be_bytes()) || !output_file.write_data(&self.strong_sum(block)) {
+                return Err(ProcessorError::FileWrite)
+            }
+        }
+        Ok(())
+    }
+
+    // Window of block length is moved over new file by one byte until its checksums match a block of old file
+    // window shrinks at the end of file, so last block of old file which may be shorter is matched too
+    fn process_delta(&self, input_file: &mut dyn ProcessorDataInput, signature_file: &mut dyn ProcessorDataInput,
+                     output_file: &mut dyn ProcessorDataOutput, progress: &mut ProgressReporter) -> Result<(), ProcessorError> {
+        let mut blocks: HashMap<u32, Vec<(u64, Vec<u8>)>> = HashMap::new();
+        signature_file.set_chunk_size(WEAK_SUM_SIZE + self.strong_len);
+        let mut offset = 0;
+        loop {
+            let block = signature_file.get_next_data();
+            if block.is_empty() { break } // reached end of file
+            if block.len() != WEAK_SUM_SIZE + self.strong_len {
+                return Err(ProcessorError::InvalidSignature)
+            }
+            blocks.entry(read_u32(&block[..WEAK_SUM_SIZE])).or_default().push((offset, block[WEAK_SUM_SIZE..].to_vec()));
+            offset += self.block_len as u64;
+        }
+
+        let mut writer = DeltaWriter::new(output_file)?;
+        input_file.set_chunk_size(self.block_len);
+        let mut buffer = Vec::new();
+        let mut start = 0;
+        let mut end_of_file = false;
+        let mut weak_sum = None;
+        loop {
+            // window is followed by one more byte, which is moved into window if it does not match
+            if buffer.len() - start <= self.block_len && !end_of_file {
+                progress.report(ProgressPhase::Delta, &*input_file);
+                buffer.drain(..start);
+                start = 0;
+                while buffer.len() <= self.block_len && !end_of_file {
+                    let data = input_file.get_next_data();
+                    end_of_file = data.is_empty();
+                    buffer.extend_from_slice(data);
+                }
+            }
+            let window_len = std::cmp::min(self.block_len, buffer.len() - start);
+            if window_len == 0 { break }
+            let window = &buffer[start..start + window_len];
+
+            let sum = weak_sum.get_or_insert_with(|| WeakSum::new(self.rabin_karp(), window));
+            if let Some(candidates) = blocks.get(&sum.digest()) {
+                let strong_sum = self.strong_sum(window);
+                let mut matches = candidates.iter().filter(|(_, block_sum)| *block_sum == strong_sum).map(|(offset, _)| *offset);
+                // block following previous copy is preferred, so copies are merged
+                let next_offset = writer.next_copy_offset();
+                if let Some(offset) = matches.clone().find(|offset| Some(*offset) == next_offset).or_else(|| matches.next()) {
+                    writer.copy(offset, window_len as u64)?;
+                    start += window_len;
+                    weak_sum = None;
+                    continue
+                }
+            }
+
+            let out = buffer[start];
+            match buffer.get(start + window_len) {
+                Some(input) if window_len == self.block_len => sum.rotate(out, *input),
+                _ => sum.rollout(out)
+            }
+            writer.literal(out)?;
+            start += 1;
+        }
+        progress.report(ProgressPhase::Delta, &*input_file);
+
+        writer.finish()
+    }
+}
+
+// Writes delta commands, consecutive literal bytes and consecutive copies are joined
+struct DeltaWriter<'a> {
+    output_file: &'a mut dyn ProcessorDataOutput,
+    literal: Vec<u8>,
+    copy: Option<(u64, u64)>
+}
+
+impl<'a> DeltaWriter<'a> {
+    fn new(output_file: &'a mut dyn ProcessorDataOutput) -> Result<Self, ProcessorError> {
+        if !output_file.write_data(&RS_DELTA_MAGIC.to_be_bytes()) {
+            return Err(ProcessorError::FileWrite)
+        }
+        Ok(Self { output_file, literal: Vec::new(), copy: None })
+    }
+
+    fn next_copy_offset(&self) -> Option<u64> {
+        self.copy.map(|(offset, len)| offset + len)
+    }
+
+    fn literal(&mut self, byte: u8) -> Result<(), ProcessorError> {
+        self.write_copy()?;
+        self.literal.push(byte);
+        if self.literal.len() >= LITERAL_BUFFER_SIZE {
+            self.write_literal()?;
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, offset: u64, len: u64) -> Result<(), ProcessorError> {
+        self.write_literal()?;
+        match &mut self.copy {
+            Some((copy_offset, copy_len)) if *copy_offset + *copy_len == offset => *copy_len += len,
+            _ => {
+                self.write_copy()?;
+                self.copy = Some((offset, len));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), ProcessorError> {
+        self.write_literal()?;
+        self.write_copy()?;
+        self.write(&[OP_END])
+    }
+
+    fn write_literal(&mut self) -> Result<(), ProcessorError> {
+        if self.literal.is_empty() {
+            return Ok(())
+        }
+        let len = self.literal.len() as u64;
+        let mut command = Vec::new();
+        if len <= (OP_LITERAL_64 - OP_LITERAL_1 + 1) as u64 {
+            command.push(OP_LITERAL_1 - 1 + len as u8);
+        } else {
+            let width = integer_width(len);
+            command.push(OP_LITERAL_N1 + width);
+            write_integer(&mut command, len, width);
+        }
+        self.write(&command)?;
+        let literal = std::mem::take(&mut self.literal);
+        self.write(&literal)
+    }
+
+    fn write_copy(&mut self) -> Result<(), ProcessorError> {
+        let (offset, len) = match self.copy.take() {
+            Some(copy) => copy,
+            None => return Ok(())
+        };
+        let (offset_width, len_width) = (integer_width(offset), integer_width(len));
+        let mut command = vec![OP_COPY_N1_N1 + offset_width * 4 + len_width];
+        write_integer(&mut command, offset, offset_width);
+        write_integer(&mut command, len, len_width);
+        self.write(&command)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), ProcessorError> {
+        if self.output_file.write_data(data) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+    }
+}
+
+
+// Checks if input starts with magic of librsync signature or delta, input is left at the same position
+pub fn is_rdiff_signature<U: ProcessorDataInput>(input_file: &mut U) -> bool {
+    matches!(peek_magic(input_file), Some(RS_MD4_SIG_MAGIC | RS_BLAKE2_SIG_MAGIC | RS_RK_MD4_SIG_MAGIC | RS_RK_BLAKE2_SIG_MAGIC))
+}
+
+pub fn is_rdiff_delta<U: ProcessorDataInput>(input_file: &mut U) -> bool {
+    peek_magic(input_file) == Some(RS_DELTA_MAGIC)
+}
+
+fn peek_magic<U: ProcessorDataInput>(input_file: &mut U) -> Option<u32> {
+    input_file.set_chunk_size(4);
+    let data = input_file.get_next_data();
+    let magic = if data.len() == 4 { Some(read_u32(data)) } else { None };
+    if input_file.move_back_last_read() { magic } else { None }
+}
+
+// Applies librsync delta to old file, copied data is read at positions given by delta
+pub fn process_rdiff_patch<R, U, S>(old_file: &mut R, delta_file: &mut U, output_file: &mut S) -> Result<(), ProcessorError>
+    where R: Read + Seek, U: ProcessorDataInput, S: ProcessorDataOutput
+{
+    if read_integer(delta_file, 4)? != RS_DELTA_MAGIC as u64 {
+        return Err(ProcessorError::InvalidDelta)
+    }
+
+    let mut buffer = vec![0; LITERAL_BUFFER_SIZE];
+    loop {
+        let command = read_integer(delta_file, 1)? as u8;
+        match command {
+            OP_END => {
+                // end command has to be last data of delta
+                delta_file.set_chunk_size(1);
+                if !delta_file.get_next_data().is_empty() {
+                    return Err(ProcessorError::InvalidDelta)
+                }
+                return Ok(())
+            }
+            OP_LITERAL_1..=OP_LITERAL_N8 => {
+                let mut len = match command {
+                    OP_LITERAL_1..=OP_LITERAL_64 => (command - OP_LITERAL_1 + 1) as u64,
+                    _ => read_integer(delta_file, 1 << (command - OP_LITERAL_N1))?
+                };
+                while len > 0 {
+                    delta_file.set_chunk_size(std::cmp::min(len, LITERAL_BUFFER_SIZE as u64) as usize);
+                    let data = delta_file.get_next_data();
+                    if data.is_empty() {
+                        return Err(ProcessorError::InvalidDelta)
+                    }
+                    if !output_file.write_data(data) {
+                        return Err(ProcessorError::FileWrite)
+                    }
+                    len -= data.len() as u64;
+                }
+            }
+            OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                let widths = command - OP_COPY_N1_N1;
+                let offset = read_integer(delta_file, 1 << (widths / 4))?;
+                let mut len = read_integer(delta_file, 1 << (widths % 4))?;
+                if old_file.seek(SeekFrom::Start(offset)).is_err() {
+                    return Err(ProcessorError::FileSeek)
+                }
+                while len > 0 {
+                    let part = &mut buffer[..std::cmp::min(len, LITERAL_BUFFER_SIZE as u64) as usize];
+                    // copy outside of old file means delta was made for other file
+                    if old_file.read_exact(part).is_err() {
+                        return Err(ProcessorError::InvalidDelta)
+                    }
+                    if !output_file.write_data(part) {
+                        return Err(ProcessorError::FileWrite)
+                    }
+                    len -= part.len() as u64;
+                }
+            }
+            _ => return Err(ProcessorError::InvalidDelta)
+        }
+    }
+}
+
+fn read_integer<U: ProcessorDataInput>(delta_file: &mut U, width: usize) -> Result<u64, ProcessorError> {
+    delta_file.set_chunk_size(width);
+    let data = delta_file.get_next_data();
+    if data.len() != width {
+        return Err(ProcessorError::InvalidDelta)
+    }
+    Ok(data.iter().fold(0, |value, byte| (value << 8) | *byte as u64))
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+// Width of integer is encoded as 0 to 3 for 1, 2, 4 or 8 bytes
+fn integer_width(value: u64) -> u8 {
+    match value {
+        0..=0xff => 0,
+        0x100..=0xffff => 1,
+        0x1_0000..=0xffff_ffff => 2,
+        _ => 3
+    }
+}
+
+fn write_integer(output: &mut Vec<u8>, value: u64, width: u8) {
+    output.extend_from_slice(&value.to_be_bytes()[8 - (1 << width)..]);
+}
+
+
+// librsync format tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mem_data::*;
+
+    fn signature(format: &RdiffFormat, old: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        Processor::new(&mut MemInput::new(old, 1), &mut output).with_format(format).process_signature().unwrap();
+        output
+    }
+
+    fn delta(signature: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut signature_file = MemInput::new(signature, 1);
+        let format = RdiffFormat::read_signature_header(&mut signature_file).unwrap();
+        let mut output = Vec::new();
+        Processor::new(&mut MemInput::new(new, 1), &mut output).with_format(&format).process_delta(&mut signature_file).unwrap();
+        output
+    }
+
+    fn patch(old: &[u8], delta: &[u8]) -> Result<Vec<u8>, ProcessorError> {
+        let mut output = Vec::new();
+        process_rdiff_patch(&mut std::io::Cursor::new(old), &mut MemInput::new(delta, 1), &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_rdiff_checksums() {
+        // librsync format test
+        // scenario: checksums of known data, rolling checksums moved over data equal checksums calculated from scratch
+
+        assert_eq!( WeakSum::new(false, &[1,2,3]).digest(), (196 << 16) | 99 );
+        assert_eq!( WeakSum::new(true, &[1,2,3]).digest(), 0x3b673983 );
+        assert_eq!( RABINKARP_MULT.wrapping_mul(RABINKARP_INVM), 1 );
+        assert_eq!( RdiffFormat::new(4).strong_sum(b"abc"), [0xbd,0xdd,0x81,0x3c,0x63,0x42,0x39,0x72,0x31,0x71,0xef,0x3f,0xee,0x98,0x57,0x9b,
+                                                             0x94,0x96,0x4e,0x3b,0xb1,0xcb,0x3e,0x42,0x72,0x62,0xc8,0xc0,0x68,0xd5,0x23,0x19] );
+        let md4 = RdiffFormat { magic: RS_MD4_SIG_MAGIC, block_len: 4, strong_len: 8 };
+        assert_eq!( md4.strong_sum(b"abc"), [0xa4,0x48,0x01,0x7a,0xaf,0x21,0xd8,0x52] );
+
+        let data: Vec<u8> = (0..100).map(|i| (i * 37 % 256) as u8).collect();
+        for rabin_karp in [false, true] {
+            let mut sum = WeakSum::new(rabin_karp, &data[..16]);
+            for i in 0..84 {
+                sum.rotate(data[i], data[i + 16]);
+                assert_eq!( sum.digest(), WeakSum::new(rabin_karp, &data[i + 1..i + 17]).digest() );
+            }
+            for i in 84..99 {
+                sum.rollout(data[i]);
+                assert_eq!( sum.digest(), WeakSum::new(rabin_karp, &data[i + 1..]).digest() );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rdiff_signature() {
+        // librsync format test
+        // scenario: signature contains header and checksums of every block, last block is shorter (block length: 4)
+
+        let format = RdiffFormat::new(4);
+        let output = signature(&format, b"abcdefghij");
+        assert_eq!( output[..12], [0x72,0x73,0x01,0x37, 0,0,0,4, 0,0,0,32] );
+        assert_eq!( output.len(), 12 + 3 * 36 );
+        assert_eq!( output[12..16], WeakSum::new(false, b"abcd").digest().to_be_bytes() );
+        assert_eq!( output[16..48], format.strong_sum(b"abcd") );
+        assert_eq!( output[88..], format.strong_sum(b"ij") );
+
+        let mut signature_file = MemInput::new(&output, 1);
+        assert!( is_rdiff_signature(&mut signature_file) );
+        assert_eq!( RdiffFormat::read_signature_header(&mut signature_file).unwrap(), format );
+        assert!( !is_rdiff_delta(&mut signature_file) );
+        assert!( RdiffFormat::read_signature_header(&mut MemInput::new(&[0x72,0x73,0x01,0x36, 0,0,0,4, 0,0,0,17], 1)).is_err() );
+    }
+
+    #[test]
+    fn test_rdiff_delta() {
+        // librsync format test
+        // scenario: blocks are found at any offset of new file, shorter last block is found at the end (block length: 4)
+        // old file: abcd efgh ij
+        // new file: X abcd efgh Z ij
+
+        for magic in [RS_MD4_SIG_MAGIC, RS_BLAKE2_SIG_MAGIC, RS_RK_MD4_SIG_MAGIC, RS_RK_BLAKE2_SIG_MAGIC] {
+            let format = RdiffFormat { magic, block_len: 4, strong_len: 8 };
+            let output = delta(&signature(&format, b"abcdefghij"), b"XabcdefghZij");
+            assert_eq!( output, [0x72,0x73,0x02,0x36, 0x01,b'X', 0x45,0x00,0x08, 0x01,b'Z', 0x45,0x08,0x02, 0x00] );
+            assert_eq!( patch(b"abcdefghij", &output).unwrap(), b"XabcdefghZij" );
+        }
+
+        let mut seed: u32 = 1;
+        let old: Vec<u8> = (0..100_000).map(|_| { seed = seed.wrapping_mul(1103515245).wrapping_add(12345); (seed >> 16) as u8 }).collect();
+        let mut new = old[..30_000].to_vec();
+        new.extend_from_slice(&[1; 100]);
+        new.extend_from_slice(&old[30_003..]);
+        new.extend_from_slice(&old[..5000]);
+        let output = delta(&signature(&RdiffFormat::new(512), &old), &new);
+        assert!( output.len() < 1300 );
+        assert_eq!( patch(&old, &output).unwrap(), new );
+    }
+
+    #[test]
+    fn test_rdiff_patch() {
+        // librsync format test
+        // scenario: commands with wide integers are applied, truncated delta and copy outside of old file are refused
+
+        let delta = [0x72,0x73,0x02,0x36, 0x42,0x00,0x02,b'x',b'y', 0x49,0x00,0x01,0x03, 0x4e,0x00,0x00,0x00,0x00,0x00,0x01, 0x00];
+        assert_eq!( patch(b"abcd", &delta).unwrap(), b"xybcda" );
+        assert!( matches!( patch(b"abcd", &delta[..delta.len() - 1]), Err(ProcessorError::InvalidDelta) ) );
+        assert!( matches!( patch(b"ab", &delta), Err(ProcessorError::InvalidDelta) ) );
+        assert!( matches!( patch(b"abcd", &[0x72,0x73,0x02,0x36, 0x55, 0x00]), Err(ProcessorError::InvalidDelta) ) );
+    }
+}
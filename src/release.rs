@@ -0,0 +1,267 @@
+use std::{error::Error, fs, path::{Path, PathBuf}, sync::{Mutex, atomic::{AtomicUsize, Ordering}}};
+use super::processor::*;
+use super::input_file::*;
+use super::output_file::*;
+use super::mem_data::*;
+use super::file_header::*;
+use super::metadata::*;
+use super::dir_processor::*;
+use super::chunk_store::*;
+
+// Name of index file in output directory
+pub const INDEX_FILE_NAME: &str = "index";
+
+// kinds of index entries
+const ENTRY_DELTA: &str = "delta";
+const ENTRY_FULL: &str = "full";
+
+// Size of data read at once when target file is copied
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum ReleaseError {
+    InvalidIndex(usize)
+}
+impl std::fmt::Display for ReleaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReleaseError::InvalidIndex(line) => write!(f, "Index file is malformed at line: {}", line)
+        }
+    }
+}
+impl Error for ReleaseError {}
+
+
+// Delta available on update server, delta path is relative to index file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub base_digest: [u8; HASH_SIZE],
+    pub delta_path: String,
+    pub delta_size: u64,
+    pub target_digest: [u8; HASH_SIZE]
+}
+
+// Whole file available on update server, used when it is cheaper than deltas
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FullEntry {
+    pub digest: [u8; HASH_SIZE],
+    pub path: String,
+    pub size: u64
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Index {
+    pub deltas: Vec<IndexEntry>,
+    pub full: Vec<FullEntry>
+}
+
+
+// Release command logic
+// delta from every base file to target file is written to output directory, deltas are generated in parallel
+// target file is copied to output directory too, for clients which have no base file
+// deltas listed by existing index of output directory are kept in the index, so clients can update over several releases
+pub fn process_release(base_files: &[PathBuf], target_file: &Path, output_dir: &Path, chunk_size: usize) -> Result<Vec<IndexEntry>, Box<dyn Error>> {
+    fs::create_dir_all(output_dir)?;
+    let index_path = output_dir.join(INDEX_FILE_NAME);
+    let mut index = if index_path.exists() { read_index(&index_path)? } else { Index::default() };
+
+    let target_digest = file_digest(target_file)?;
+    let target_metadata = FileMetadata::from_file(target_file).ok();
+
+    // every worker takes next base file until all of them are processed
+    let next_base = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    let workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(base_files.len());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next_base.fetch_add(1, Ordering::Relaxed);
+                let base_file = match base_files.get(index) {
+                    Some(base_file) => base_file,
+                    None => break
+                };
+                let result = make_delta(base_file, target_file, &target_digest, target_metadata.as_ref(), output_dir, chunk_size)
+                    .map_err(|x| format!("{}: {}", base_file.display(), x));
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner()?;
+    results.sort_by_key(|(index, _)| *index);
+    let mut entries = Vec::new();
+    for (_, result) in results {
+        let entry = result?;
+        // newly generated delta replaces listed delta between the same files
+        index.deltas.retain(|listed| listed.base_digest != entry.base_digest || listed.target_digest != entry.target_digest);
+        index.deltas.push(entry.clone());
+        entries.push(entry);
+    }
+
+    let full_entry = copy_target(target_file, &target_digest, output_dir)?;
+    index.full.retain(|listed| listed.digest != full_entry.digest);
+    index.full.push(full_entry);
+
+    let mut output_file = OutputFile::new(&index_path)?;
+    if !output_file.write_data(format_index(&index).as_bytes()) {
+        return Err(Box::new(ProcessorError::FileWrite))
+    }
+    output_file.finish()?;
+
+    Ok(entries)
+}
+
+// Delta file is named by digests of base and target files
+fn make_delta(base_file: &Path, target_file: &Path, target_digest: &[u8; HASH_SIZE], target_metadata: Option<&FileMetadata>,
+              output_dir: &Path, chunk_size: usize) -> Result<IndexEntry, Box<dyn Error>> {
+    let base_digest = file_digest(base_file)?;
+    let signature = file_signature(base_file, chunk_size)?;
+    let delta_path = format!("{}-{}.delta", to_hex(&base_digest), to_hex(target_digest));
+
+    let mut input_file = InputFile::new(target_file, chunk_size)?;
+    let mut output_file = OutputFile::new(output_dir.join(&delta_path))?;
+    FileHeader::new(FileKind::Delta, chunk_size, target_metadata.cloned()).write(&mut output_file)?;
+    Processor::new(&mut input_file, &mut output_file).process_delta(&mut MemInput::new(&signature, HASH_SIZE))?;
+    output_file.finish()?;
+
+    let delta_size = fs::metadata(output_dir.join(&delta_path))?.len();
+    Ok(IndexEntry { base_digest, delta_path, delta_size, target_digest: *target_digest })
+}
+
+// Copy of target file is named by its digest
+fn copy_target(target_file: &Path, target_digest: &[u8; HASH_SIZE], output_dir: &Path) -> Result<FullEntry, Box<dyn Error>> {
+    let path = format!("{}.full", to_hex(target_digest));
+    let mut input_file = InputFile::new(target_file, COPY_BUFFER_SIZE)?;
+    let mut output_file = OutputFile::new(output_dir.join(&path))?;
+    let mut size = 0;
+    loop {
+        let data = input_file.get_next_data();
+        if data.is_empty() { break } // reached end of file
+        if !output_file.write_data(data) {
+            return Err(Box::new(ProcessorError::FileWrite))
+        }
+        size += data.len() as u64;
+    }
+    output_file.finish()?;
+    Ok(FullEntry { digest: *target_digest, path, size })
+}
+
+fn file_digest(file_path: &Path) -> Result<[u8; HASH_SIZE], Box<dyn Error>> {
+    Ok(calculate_data_digest(&mut InputFile::new(file_path, CHUNK_SIZE)?))
+}
+
+
+// Index file format, one entry per line:
+// delta <base digest> <delta path> <delta size> <target digest>
+// full <digest> <path> <size>
+// digests are hex encoded SHA-256 of whole files
+pub fn read_index(index_path: &Path) -> Result<Index, Box<dyn Error>> {
+    parse_index(&fs::read_to_string(index_path)?)
+}
+
+pub fn parse_index(text: &str) -> Result<Index, Box<dyn Error>> {
+    let mut index = Index::default();
+    for (number, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue
+        }
+        if parse_index_line(line, &mut index).is_none() {
+            return Err(Box::new(ReleaseError::InvalidIndex(number + 1)))
+        }
+    }
+    Ok(index)
+}
+
+fn parse_index_line(line: &str, index: &mut Index) -> Option<()> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        [ENTRY_DELTA, base_digest, delta_path, delta_size, target_digest] => index.deltas.push(IndexEntry {
+            base_digest: parse_digest(base_digest)?,
+            delta_path: delta_path.to_string(),
+            delta_size: delta_size.parse().ok()?,
+            target_digest: parse_digest(target_digest)?
+        }),
+        [ENTRY_FULL, digest, path, size] => index.full.push(FullEntry {
+            digest: parse_digest(digest)?,
+            path: path.to_string(),
+            size: size.parse().ok()?
+        }),
+        _ => return None
+    }
+    Some(())
+}
+
+pub fn parse_digest(text: &str) -> Option<[u8; HASH_SIZE]> {
+    from_hex(text)?.try_into().ok()
+}
+
+pub fn format_index(index: &Index) -> String {
+    let deltas = index.deltas.iter()
+        .map(|entry| format!("{} {} {} {} {}\n", ENTRY_DELTA, to_hex(&entry.base_digest), entry.delta_path, entry.delta_size, to_hex(&entry.target_digest)));
+    let full = index.full.iter()
+        .map(|entry| format!("{} {} {} {}\n", ENTRY_FULL, to_hex(&entry.digest), entry.path, entry.size));
+    deltas.chain(full).collect()
+}
+
+
+// Release tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    fn apply_delta(base: &[u8], delta_path: &Path) -> Vec<u8> {
+        let mut delta_file = InputFile::new(delta_path, 1).unwrap();
+        let header = FileHeader::read(&mut delta_file).unwrap().unwrap();
+        let mut output = Vec::new();
+        let mut base_input = MemInput::new(base, header.chunk_size);
+        Processor::new(&mut base_input, &mut output).process_patch(&mut delta_file, header.chunk_size).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_release() {
+        // release test
+        // scenario: deltas from all bases are listed in index, index of next release keeps them
+
+        let dir = TestDir::new("release");
+        let v1 = dir.write("v1", &[1,1,1,1,2,2,2,2]);
+        let v2 = dir.write("v2", &[1,1,1,1,2,2,2,2,3,3]);
+        let v3 = dir.write("v3", &[4,4,4,4,2,2,2,2,3,3]);
+        let out = dir.path.join("out");
+
+        let entries = process_release(&[v1.clone(), v2.clone()], &v3, &out, 4).unwrap();
+        assert_eq!( entries.len(), 2 );
+        for (entry, base) in entries.iter().zip([&[1,1,1,1,2,2,2,2][..], &[1,1,1,1,2,2,2,2,3,3][..]]) {
+            assert_eq!( entry.base_digest, calculate_chunk_hash(base) );
+            assert_eq!( entry.target_digest, calculate_chunk_hash(&[4,4,4,4,2,2,2,2,3,3]) );
+            assert_eq!( entry.delta_size, fs::metadata(out.join(&entry.delta_path)).unwrap().len() );
+            assert_eq!( apply_delta(base, &out.join(&entry.delta_path)), [4,4,4,4,2,2,2,2,3,3] );
+        }
+        let index = read_index(&out.join(INDEX_FILE_NAME)).unwrap();
+        assert_eq!( index.deltas, entries );
+        assert_eq!( index.full, [FullEntry { digest: entries[0].target_digest, path: format!("{}.full", to_hex(&entries[0].target_digest)), size: 10 }] );
+        assert_eq!( fs::read(out.join(&index.full[0].path)).unwrap(), [4,4,4,4,2,2,2,2,3,3] );
+
+        let next_entries = process_release(&[v2], &v1, &out, 4).unwrap();
+        let index = read_index(&out.join(INDEX_FILE_NAME)).unwrap();
+        assert_eq!( index.deltas.len(), 3 );
+        assert_eq!( index.deltas[..2], entries[..] );
+        assert_eq!( index.deltas[2..], next_entries[..] );
+        assert_eq!( index.full.len(), 2 );
+    }
+
+    #[test]
+    fn test_index_invalid() {
+        // release test
+        // scenario: malformed index lines are reported with line number
+
+        let digest = to_hex(&[0; HASH_SIZE]);
+        let index = parse_index(&format!("delta {} a.delta 10 {}\n\nfull {} a.full 20\n", digest, digest, digest)).unwrap();
+        assert_eq!( (index.deltas.len(), index.full.len()), (1, 1) );
+        let error = parse_index(&format!("delta {} a.delta 10 {}\ndelta {} a.delta x {}\n", digest, digest, digest, digest)).unwrap_err();
+        assert!( matches!( error.downcast_ref::<ReleaseError>(), Some(ReleaseError::InvalidIndex(2)) ) );
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::{Read, Write};
+use super::processor::*;
+
+// Maximal size of data in a single frame
+const FRAME_SIZE: usize = 64 * 1024;
+
+
+// Input data read from a stream, it is not possible to move back in a stream
+pub struct StreamInput<R> {
+    reader: R,
+    chunk_size: usize,
+    chunk: Vec<u8>,
+    read_bytes: u64
+}
+
+impl<R: Read> StreamInput<R> {
+
+    pub fn new(reader: R, chunk_size: usize) -> Self {
+        Self { reader, chunk_size, chunk: Vec::new(), read_bytes: 0 }
+    }
+
+    // Number of bytes read so far
+    pub fn read_bytes(&self) -> u64 {
+        self.read_bytes
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+}
+
+impl<R: Read> ProcessorDataInput for StreamInput<R> {
+    fn get_next_data(&mut self) -> &[u8] {
+        self.chunk.resize(self.chunk_size, 0);
+
+        let mut size = 0;
+        while size < self.chunk_size {
+            match self.reader.read(&mut self.chunk[size..]) {
+                Ok(0) => break,
+                Ok(n) => size += n,
+                Err(x) if x.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    // in case of any error return empty array
+                    size = 0;
+                    break
+                }
+            }
+        }
+
+        self.chunk.truncate(size);
+        self.read_bytes += size as u64;
+        &self.chunk
+    }
+
+    fn move_back_last_read(&mut self) -> bool {
+        false
+    }
+
+    fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
+}
+
+// Output data written to a stream
+pub struct StreamOutput<W> {
+    writer: W
+}
+
+impl<W: Write> StreamOutput<W> {
+
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+}
+
+impl<W: Write> ProcessorDataOutput for StreamOutput<W> {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        self.writer.write_all(data).is_ok()
+    }
+}
+
+// Writer splitting data into frames, so end of data can be detected in a stream which is still open
+// frame format: u32 data length, data; zero length frame ends the data
+pub struct FramedWriter<W: Write> {
+    writer: W,
+    buffer: Vec<u8>
+}
+
+impl<W: Write> FramedWriter<W> {
+
+    pub fn new(writer: W) -> Self {
+        Self { writer, buffer: Vec::with_capacity(FRAME_SIZE) }
+    }
+
+    // Writes remaining data and end of data marker
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if !self.buffer.is_empty() {
+            self.write_frame()?;
+        }
+        self.write_frame()?;
+        Ok(self.writer)
+    }
+
+    fn write_frame(&mut self) -> std::io::Result<()> {
+        self.writer.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+}
+
+impl<W: Write> Write for FramedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let size = std::cmp::min(data.len(), FRAME_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..size]);
+        if self.buffer.len() == FRAME_SIZE {
+            self.write_frame()?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Reader of data written by FramedWriter, it reports end of data at end of frames
+pub struct FramedReader<R: Read> {
+    reader: R,
+    frame_len: usize,
+    finished: bool
+}
+
+impl<R: Read> FramedReader<R> {
+
+    pub fn new(reader: R) -> Self {
+        Self { reader, frame_len: 0, finished: false }
+    }
+
+    // True if end of data marker was read
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+}
+
+impl<R: Read> Read for FramedReader<R> {
+    fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        if self.frame_len == 0 && !self.finished {
+            let mut frame_len = [0u8; 4];
+            self.reader.read_exact(&mut frame_len)?;
+            self.frame_len = u32::from_le_bytes(frame_len) as usize;
+            if self.frame_len > FRAME_SIZE {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame is too large"))
+            }
+            self.finished = self.frame_len == 0;
+        }
+        if self.finished {
+            return Ok(0)
+        }
+
+        let size = std::cmp::min(data.len(), self.frame_len);
+        let size = self.reader.read(&mut data[..size])?;
+        if size == 0 && !data.is_empty() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+        }
+        self.frame_len -= size;
+        Ok(size)
+    }
+}
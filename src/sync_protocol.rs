@@ -0,0 +1,516 @@
+use std::{error::Error, io::{Read, Write, BufReader, BufWriter}, net::{TcpListener, TcpStream, ToSocketAddrs}, path::{Path, PathBuf}, process::{Command, Stdio}, convert::TryFrom, sync::{Arc, Condvar, Mutex}};
+use sha2::{Sha256, Digest};
+use super::processor::*;
+use super::input_file::*;
+use super::output_file::*;
+use super::mem_data::*;
+use super::stream_data::*;
+use super::file_header::*;
+use super::dir_processor::{file_signature, to_file_path, DirProcessorError};
+
+// Magic value at the beginning of sync request
+const REQUEST_MAGIC: &[u8; 6] = b"HDSYNC";
+const PROTOCOL_VERSION: u8 = 2;
+
+// status of response and digest confirmation
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+// Limits protecting against malformed requests and responses
+const MAX_NAME_SIZE: usize = 4096;
+const MAX_MESSAGE_SIZE: usize = 4096;
+const MAX_SIGNATURE_SIZE: u64 = 1024 * 1024 * 1024; // signature of 128 GiB file with 4 KiB chunks
+const MAX_CONNECTIONS: usize = 4; // every connection can keep signature of the largest size in memory
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum SyncError {
+    InvalidRequest,
+    InvalidResponse,
+    DigestMismatch,
+    Remote(String)
+}
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SyncError::InvalidRequest => write!(f, "Sync request is malformed"),
+            SyncError::InvalidResponse => write!(f, "Sync response is malformed"),
+            SyncError::DigestMismatch => write!(f, "Digest of patched file does not match digest of remote file"),
+            SyncError::Remote(message) => write!(f, "Remote error: {}", message)
+        }
+    }
+}
+impl Error for SyncError {}
+
+// Request sent by client
+// request format:
+// magic, u8 version, u32 name length, name of remote file, signature header, u64 signature length, signature
+// response format:
+// u8 status, for error it is followed by u32 message length, message
+// for success it is followed by delta header, framed delta data and SHA-256 digest of remote file
+// client answers with u8 status confirming that digest of patched file matches
+struct SyncRequest {
+    chunk_size: usize,
+    name: String,
+    signature: Vec<u8>
+}
+
+// Output which calculates digest of all written data
+struct DigestOutput<S> {
+    output_file: S,
+    hasher: Sha256
+}
+
+impl<S: ProcessorDataOutput> DigestOutput<S> {
+    fn new(output_file: S) -> Self {
+        Self { output_file, hasher: Sha256::new() }
+    }
+
+    fn finish(self) -> (S, [u8; HASH_SIZE]) {
+        (self.output_file, self.hasher.finalize().into())
+    }
+}
+
+impl<S: ProcessorDataOutput> ProcessorDataOutput for DigestOutput<S> {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        self.hasher.update(data);
+        self.output_file.write_data(data)
+    }
+
+    fn read_back(&mut self, offset: u64, data: &mut [u8]) -> bool {
+        self.output_file.read_back(offset, data)
+    }
+
+    fn write_zeros(&mut self, len: u64) -> bool {
+        let zeros = [0; 4096];
+        let mut len_to_hash = len;
+        while len_to_hash > 0 {
+            let len = std::cmp::min(len_to_hash, zeros.len() as u64) as usize;
+            self.hasher.update(&zeros[..len]);
+            len_to_hash -= len as u64;
+        }
+        self.output_file.write_zeros(len)
+    }
+}
+
+
+// Number of connections handled at once, slot is released when dropped
+struct ConnectionSlot {
+    slots: Arc<(Mutex<usize>, Condvar)>
+}
+
+impl ConnectionSlot {
+    // Waits until some connection finishes if all slots are taken
+    fn acquire(slots: &Arc<(Mutex<usize>, Condvar)>) -> Self {
+        let (count, released) = &**slots;
+        let mut count = count.lock().unwrap_or_else(|x| x.into_inner());
+        while *count >= MAX_CONNECTIONS {
+            count = released.wait(count).unwrap_or_else(|x| x.into_inner());
+        }
+        *count += 1;
+        Self { slots: Arc::clone(slots) }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let (count, released) = &*self.slots;
+        *count.lock().unwrap_or_else(|x| x.into_inner()) -= 1;
+        released.notify_one();
+    }
+}
+
+// Serve command logic, files from root directory are served, every connection is handled in its own thread
+// connections over the limit wait in backlog of listener until some connection finishes
+pub fn serve(listener: TcpListener, root_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let slots = Arc::new((Mutex::new(0), Condvar::new()));
+    loop {
+        let slot = ConnectionSlot::acquire(&slots);
+        let (stream, _) = listener.accept()?;
+        let root_dir = root_dir.to_path_buf();
+        std::thread::spawn(move || {
+            if let Err(x) = serve_connection(stream, &root_dir) {
+                eprintln!("Connection error: {}", x);
+            }
+            drop(slot);
+        });
+    }
+}
+
+pub fn serve_connection(stream: TcpStream, root_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    serve_stream(&mut reader, &mut writer, root_dir)
+}
+
+// Generates delta of requested file against signature received from client
+pub fn serve_stream<R: Read, W: Write>(reader: &mut R, writer: &mut W, root_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let (request, mut input_file, digest) = match read_request(reader).and_then(|request| {
+        let file_name = served_file_path(root_dir, &request.name)?;
+        let digest = calculate_data_digest(&mut InputFile::new(&file_name, request.chunk_size)?);
+        let input_file = InputFile::new(&file_name, request.chunk_size)?;
+        Ok((request, input_file, digest))
+    }) {
+        Ok(v) => v,
+        Err(x) => {
+            // error is reported to client as well
+            let message = x.to_string();
+            let message = &message.as_bytes()[..std::cmp::min(message.len(), MAX_MESSAGE_SIZE)];
+            writer.write_all(&[STATUS_ERROR])?;
+            writer.write_all(&(message.len() as u32).to_le_bytes())?;
+            writer.write_all(message)?;
+            writer.flush()?;
+            return Err(x)
+        }
+    };
+
+    writer.write_all(&[STATUS_OK])?;
+    FileHeader::new(FileKind::Delta, request.chunk_size, None).write(&mut StreamOutput::new(&mut *writer))?;
+
+    let mut output = StreamOutput::new(FramedWriter::new(&mut *writer));
+    let mut signature = MemInput::new(&request.signature, HASH_SIZE);
+    Processor::new(&mut input_file, &mut output).process_delta(&mut signature)?;
+    output.into_inner().finish()?;
+
+    writer.write_all(&digest)?;
+    writer.flush()?;
+
+    // wait for confirmation that client has same file now
+    let mut status = [0u8; 1];
+    reader.read_exact(&mut status)?;
+    if status[0] != STATUS_OK {
+        return Err(Box::new(SyncError::DigestMismatch))
+    }
+    Ok(())
+}
+
+// Pull command logic, local file is replaced with remote file, returns number of received delta bytes
+pub fn pull<A: ToSocketAddrs>(address: A, local_file: &Path, remote_name: &str, chunk_size: usize) -> Result<u64, Box<dyn Error>> {
+    let stream = TcpStream::connect(address)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    pull_stream(&mut reader, &mut writer, local_file, remote_name, chunk_size)
+}
+
+// Same as pull, command is started and sync protocol is spoken over its stdin and stdout
+pub fn pull_command(command: &str, local_file: &Path, remote_name: &str, chunk_size: usize) -> Result<u64, Box<dyn Error>> {
+    let mut child = shell_command(command).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+    let mut reader = BufReader::new(child.stdout.take().ok_or(SyncError::InvalidResponse)?);
+    let mut writer = BufWriter::new(child.stdin.take().ok_or(SyncError::InvalidRequest)?);
+
+    let result = pull_stream(&mut reader, &mut writer, local_file, remote_name, chunk_size);
+
+    // closing stdin ends the remote side
+    drop(writer);
+    let status = child.wait()?;
+    match result {
+        Ok(_) if !status.success() => Err(Box::new(SyncError::Remote(format!("command failed: {}", status)))),
+        result => result
+    }
+}
+
+pub fn pull_stream<R: Read, W: Write>(reader: &mut R, writer: &mut W, local_file: &Path, remote_name: &str, chunk_size: usize) -> Result<u64, Box<dyn Error>> {
+    // missing local file is downloaded completely
+    let local_file_exists = local_file.exists();
+    let signature = if local_file_exists { file_signature(local_file, chunk_size)? } else { Vec::new() };
+
+    write_request(writer, &SyncRequest { chunk_size, name: remote_name.to_owned(), signature })?;
+    writer.flush()?;
+
+    read_status(reader)?;
+    match FileHeader::read(&mut StreamInput::new(&mut *reader, 1)) {
+        Ok(Some(header)) if header.kind == FileKind::Delta && header.chunk_size == chunk_size => {}
+        _ => return Err(Box::new(SyncError::InvalidResponse))
+    }
+
+    let mut delta = StreamInput::new(FramedReader::new(&mut *reader), chunk_size);
+    let mut output_file = DigestOutput::new(OutputFile::new(local_file)?);
+    if local_file_exists {
+        let mut old_file = InputFile::new(local_file, chunk_size)?;
+        Processor::new(&mut old_file, &mut output_file).process_patch(&mut delta, chunk_size)?;
+    } else {
+        let mut old_file = MemInput::new(&[], chunk_size);
+        Processor::new(&mut old_file, &mut output_file).process_patch(&mut delta, chunk_size)?;
+    }
+
+    // whole delta has to be received
+    let received = delta.read_bytes();
+    if !delta.into_inner().is_finished() {
+        return Err(Box::new(SyncError::InvalidResponse))
+    }
+
+    let mut digest = [0u8; HASH_SIZE];
+    reader.read_exact(&mut digest).map_err(|_| SyncError::InvalidResponse)?;
+    let (output_file, output_digest) = output_file.finish();
+    let status = if digest == output_digest { STATUS_OK } else { STATUS_ERROR };
+    writer.write_all(&[status])?;
+    writer.flush()?;
+
+    // local file is replaced only if it is same as remote file
+    if status != STATUS_OK {
+        return Err(Box::new(SyncError::DigestMismatch))
+    }
+    output_file.finish()?;
+
+    Ok(received)
+}
+
+
+// Path of served file, links leading out of root directory are refused
+fn served_file_path(root_dir: &Path, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let file_name = std::fs::canonicalize(to_file_path(root_dir, name)?)?;
+    if !file_name.starts_with(std::fs::canonicalize(root_dir)?) {
+        return Err(Box::new(DirProcessorError::InvalidPath(name.to_owned())))
+    }
+    Ok(file_name)
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+fn write_request<W: Write>(writer: &mut W, request: &SyncRequest) -> Result<(), Box<dyn Error>> {
+    writer.write_all(REQUEST_MAGIC)?;
+    writer.write_all(&[PROTOCOL_VERSION])?;
+    writer.write_all(&u32::try_from(request.name.len())?.to_le_bytes())?;
+    writer.write_all(request.name.as_bytes())?;
+    FileHeader::new(FileKind::Signature, request.chunk_size, None).write(&mut StreamOutput::new(&mut *writer))?;
+    writer.write_all(&(request.signature.len() as u64).to_le_bytes())?;
+    writer.write_all(&request.signature)?;
+    Ok(())
+}
+
+fn read_request<R: Read>(reader: &mut R) -> Result<SyncRequest, Box<dyn Error>> {
+    let mut magic = [0u8; 7];
+    reader.read_exact(&mut magic).map_err(|_| SyncError::InvalidRequest)?;
+    if magic[..6] != REQUEST_MAGIC[..] || magic[6] != PROTOCOL_VERSION {
+        return Err(Box::new(SyncError::InvalidRequest))
+    }
+
+    let name = read_bytes(reader, MAX_NAME_SIZE).map_err(|_| SyncError::InvalidRequest)?;
+    let name = String::from_utf8(name).map_err(|_| SyncError::InvalidRequest)?;
+
+    let chunk_size = match FileHeader::read(&mut StreamInput::new(&mut *reader, 1)) {
+        Ok(Some(header)) if header.kind == FileKind::Signature => header.chunk_size,
+        _ => return Err(Box::new(SyncError::InvalidRequest))
+    };
+
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len).map_err(|_| SyncError::InvalidRequest)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_SIGNATURE_SIZE {
+        return Err(Box::new(SyncError::InvalidRequest))
+    }
+    let mut signature = Vec::new();
+    reader.take(len).read_to_end(&mut signature)?;
+    if signature.len() as u64 != len || signature.len() % HASH_SIZE != 0 {
+        return Err(Box::new(SyncError::InvalidRequest))
+    }
+
+    Ok(SyncRequest { chunk_size, name, signature })
+}
+
+fn read_status<R: Read>(reader: &mut R) -> Result<(), Box<dyn Error>> {
+    let mut status = [0u8; 1];
+    reader.read_exact(&mut status).map_err(|_| SyncError::InvalidResponse)?;
+    match status[0] {
+        STATUS_OK => Ok(()),
+        STATUS_ERROR => {
+            let message = read_bytes(reader, MAX_MESSAGE_SIZE).map_err(|_| SyncError::InvalidResponse)?;
+            Err(Box::new(SyncError::Remote(String::from_utf8_lossy(&message).into_owned())))
+        }
+        _ => Err(Box::new(SyncError::InvalidResponse))
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut data = [0u8; 4];
+    reader.read_exact(&mut data)?;
+    Ok(u32::from_le_bytes(data))
+}
+
+// Reads data preceded by its length
+fn read_bytes<R: Read>(reader: &mut R, max_size: usize) -> std::io::Result<Vec<u8>> {
+    let size = read_u32(reader)? as usize;
+    if size > max_size {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "data is too large"))
+    }
+    let mut data = vec![0u8; size];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+
+// Sync protocol tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    // Starts server handling single connection on loopback interface
+    fn start_server(root_dir: &Path) -> (std::net::SocketAddr, std::thread::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let root_dir = root_dir.to_path_buf();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &root_dir).is_ok()
+        });
+        (address, server)
+    }
+
+    #[test]
+    fn test_pull_changed() {
+        // sync test
+        // scenario: local file differs from remote file in one chunk, only that chunk is transferred
+
+        let server_dir = TestDir::new("pull-changed-server");
+        let client_dir = TestDir::new("pull-changed-client");
+        let new_data: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+        let mut old_data = new_data.clone();
+        old_data[1500] = 0xff;
+        std::fs::write(server_dir.path.join("file"), &new_data).unwrap();
+        std::fs::write(client_dir.path.join("file"), &old_data).unwrap();
+
+        let (address, server) = start_server(&server_dir.path);
+        let received = pull(address, &client_dir.path.join("file"), "file", 1024).unwrap();
+        assert!( server.join().unwrap() );
+
+        assert_eq!( std::fs::read(client_dir.path.join("file")).unwrap(), new_data );
+        assert_eq!( received, 3 + 1 + 1024 );
+    }
+
+    #[test]
+    fn test_pull_missing_local() {
+        // sync test
+        // scenario: local file does not exist, whole remote file is transferred
+
+        let server_dir = TestDir::new("pull-missing-server");
+        let client_dir = TestDir::new("pull-missing-client");
+        std::fs::write(server_dir.path.join("file"), [1,2,3,4,5,6]).unwrap();
+
+        let (address, server) = start_server(&server_dir.path);
+        assert!( pull(address, &client_dir.path.join("file"), "file", 4).is_ok() );
+        assert!( server.join().unwrap() );
+
+        assert_eq!( std::fs::read(client_dir.path.join("file")).unwrap(), [1,2,3,4,5,6] );
+    }
+
+    #[test]
+    fn test_pull_pipe() {
+        // sync test
+        // scenario: sync protocol is used over in-process pipes
+
+        let server_dir = TestDir::new("pull-pipe-server");
+        let client_dir = TestDir::new("pull-pipe-client");
+        std::fs::write(server_dir.path.join("file"), [1,2,3,4,5,6,7,8,9]).unwrap();
+        std::fs::write(client_dir.path.join("file"), [1,2,3,4,0,0,0,0,9]).unwrap();
+
+        let (mut server_reader, mut client_writer) = std::io::pipe().unwrap();
+        let (mut client_reader, mut server_writer) = std::io::pipe().unwrap();
+        let root_dir = server_dir.path.clone();
+        let server = std::thread::spawn(move || serve_stream(&mut server_reader, &mut server_writer, &root_dir).is_ok());
+
+        let received = pull_stream(&mut client_reader, &mut client_writer, &client_dir.path.join("file"), "file", 4).unwrap();
+        assert!( server.join().unwrap() );
+
+        assert_eq!( std::fs::read(client_dir.path.join("file")).unwrap(), [1,2,3,4,5,6,7,8,9] );
+        assert_eq!( received, 1 + 1 + 4 + 1 );
+    }
+
+    #[test]
+    fn test_pull_digest_mismatch() {
+        // sync test
+        // scenario: remote digest does not match patched file, local file is unchanged
+
+        let client_dir = TestDir::new("pull-mismatch-client");
+        std::fs::write(client_dir.path.join("file"), [1,2,3,4]).unwrap();
+
+        let mut response = vec![STATUS_OK];
+        FileHeader::new(FileKind::Delta, 4, None).write(&mut response).unwrap();
+        response.extend_from_slice(&[5,0,0,0, 1,5,6,7,8, 0,0,0,0]);
+        response.extend_from_slice(&[0; HASH_SIZE]);
+        let mut request = Vec::new();
+
+        let result = pull_stream(&mut response.as_slice(), &mut request, &client_dir.path.join("file"), "file", 4);
+
+        assert!( matches!( result.unwrap_err().downcast_ref::<SyncError>(), Some(SyncError::DigestMismatch) ) );
+        assert_eq!( request.last(), Some(&STATUS_ERROR) );
+        assert_eq!( std::fs::read(client_dir.path.join("file")).unwrap(), [1,2,3,4] );
+    }
+
+    #[test]
+    fn test_pull_invalid_name() {
+        // sync test
+        // scenario: client requests file outside of served directory, also through symbolic link
+
+        let server_dir = TestDir::new("pull-invalid-server");
+        let client_dir = TestDir::new("pull-invalid-client");
+
+        let (address, server) = start_server(&server_dir.path);
+        let result = pull(address, &client_dir.path.join("file"), "../file", 4);
+        assert!( !server.join().unwrap() );
+
+        assert!( result.unwrap_err().to_string().starts_with("Remote error: Invalid path") );
+        assert!( !client_dir.path.join("file").exists() );
+
+        // link inside of served directory cannot lead out of it
+        #[cfg(unix)]
+        {
+            std::fs::write(client_dir.path.join("secret"), [1,2,3]).unwrap();
+            std::os::unix::fs::symlink(client_dir.path.join("secret"), server_dir.path.join("link")).unwrap();
+            let (address, server) = start_server(&server_dir.path);
+            let result = pull(address, &client_dir.path.join("file"), "link", 4);
+            assert!( !server.join().unwrap() );
+            assert!( result.unwrap_err().to_string().starts_with("Remote error: Invalid path") );
+            assert!( !client_dir.path.join("file").exists() );
+        }
+    }
+
+    #[test]
+    fn test_request_signature_size() {
+        // sync test
+        // scenario: request announcing signature larger than limit is refused before signature is read
+
+        let mut request = Vec::new();
+        write_request(&mut request, &SyncRequest { chunk_size: 4, name: "file".to_owned(), signature: vec![0; HASH_SIZE] }).unwrap();
+        assert!( read_request(&mut request.as_slice()).is_ok() );
+
+        let len_start = request.len() - HASH_SIZE - 8;
+        request.truncate(len_start);
+        request.extend_from_slice(&(MAX_SIGNATURE_SIZE + HASH_SIZE as u64).to_le_bytes());
+        let error = read_request(&mut request.as_slice()).err().unwrap();
+        assert!( matches!( error.downcast_ref::<SyncError>(), Some(SyncError::InvalidRequest) ) );
+    }
+
+    #[test]
+    fn test_connection_slots() {
+        // sync test
+        // scenario: connection over the limit waits until other connection releases its slot
+
+        let slots = Arc::new((Mutex::new(0), Condvar::new()));
+        let mut taken: Vec<ConnectionSlot> = (0..MAX_CONNECTIONS).map(|_| ConnectionSlot::acquire(&slots)).collect();
+        let waiting = {
+            let slots = Arc::clone(&slots);
+            std::thread::spawn(move || drop(ConnectionSlot::acquire(&slots)))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!( !waiting.is_finished() );
+
+        taken.pop();
+        waiting.join().unwrap();
+        drop(taken);
+        assert_eq!( *slots.0.lock().unwrap(), 0 );
+    }
+}
@@ -0,0 +1,40 @@
+use std::{fs, path::PathBuf};
+
+
+
+// Temporary directory of a test, removed with all its files when dropped
+pub struct TestDir {
+    pub path: PathBuf
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = test_file_name(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    // Writes file in the directory, parent directories of the file are created
+    pub fn write(&self, name: &str, data: &[u8]) -> PathBuf {
+        let file_path = self.path.join(name);
+        fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        fs::write(&file_path, data).unwrap();
+        file_path
+    }
+
+    pub fn read(&self, name: &str) -> Vec<u8> {
+        fs::read(self.path.join(name)).unwrap()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Path of temporary file of a test
+pub fn test_file_name(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("hdiff-test-{}-{}", std::process::id(), name))
+}
@@ -0,0 +1,84 @@
+use std::{error::Error, path::{Path, PathBuf}};
+use super::processor::*;
+use super::input_file::*;
+use super::output_file::*;
+
+// Chunk sizes tried by tune command
+pub const TUNE_CHUNK_SIZES: [usize; 6] = [256, 1024, 4 * 1024, 16 * 1024, 64 * 1024, 128 * 1024];
+
+
+// Sizes of signature and delta data created with one chunk size, headers are not included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TuneResult {
+    pub chunk_size: usize,
+    pub signature_size: u64,
+    pub delta_size: u64
+}
+
+// Output which counts written data without keeping it
+struct SizeOutput {
+    len: u64
+}
+
+impl ProcessorDataOutput for SizeOutput {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        self.len += data.len() as u64;
+        true
+    }
+}
+
+// Signature written to temporary file, file is removed when dropped
+struct TempSignature {
+    path: PathBuf
+}
+
+impl Drop for TempSignature {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Tune command logic, signature of old file and delta of new file are created with every chunk size
+// signature goes through temporary file, so small chunks of large file don't have to fit in memory, delta is only counted
+pub fn tune_chunk_size(old_file: &Path, new_file: &Path, chunk_sizes: &[usize]) -> Result<Vec<TuneResult>, Box<dyn Error>> {
+    let mut results = Vec::new();
+    for &chunk_size in chunk_sizes {
+        let signature = TempSignature { path: std::env::temp_dir().join(format!("hdiff-tune-{}-{}", std::process::id(), chunk_size)) };
+        let mut signature_file = OutputFile::new_private(&signature.path, false)?;
+        Processor::new(&mut InputFile::new(old_file, chunk_size)?, &mut signature_file).process_signature()?;
+        let signature_size = signature_file.written_len()?;
+        signature_file.finish()?;
+
+        let mut delta = SizeOutput { len: 0 };
+        let mut input_file = InputFile::new(new_file, chunk_size)?;
+        Processor::new(&mut input_file, &mut delta).process_delta(&mut InputFile::new(&signature.path, HASH_SIZE)?)?;
+
+        results.push(TuneResult { chunk_size, signature_size, delta_size: delta.len });
+    }
+    Ok(results)
+}
+
+
+// Tune tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+
+    #[test]
+    fn test_tune() {
+        // tune test
+        // scenario: single changed byte costs one chunk in delta, larger chunks give smaller signature and larger delta
+
+        let dir = TestDir::new("tune");
+        let old: Vec<u8> = (0..64 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let mut new = old.clone();
+        new[5000] ^= 1;
+        let old_file = dir.write("old", &old);
+        let new_file = dir.write("new", &new);
+
+        let results = tune_chunk_size(&old_file, &new_file, &[1024, 4096]).unwrap();
+        assert_eq!( results, [TuneResult { chunk_size: 1024, signature_size: 64 * HASH_SIZE as u64, delta_size: 64 + 1024 },
+                              TuneResult { chunk_size: 4096, signature_size: 16 * HASH_SIZE as u64, delta_size: 16 + 4096 }] );
+    }
+}
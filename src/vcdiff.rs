@@ -0,0 +1,791 @@
+use std::io::{Read, Seek, SeekFrom};
+use super::processor::*;
+
+// VCDIFF (RFC 3284) header: magic with version 0
+const VCDIFF_MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+
+// bits of header indicator
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// bits of window indicator, checksum is an extension used by xdelta3 and open-vcdiff
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+// instruction types of code table
+const NOOP: u8 = 0;
+const ADD: u8 = 1;
+const RUN: u8 = 2;
+const COPY: u8 = 3;
+
+// address modes, modes after near cache modes use same cache
+const VCD_SELF: u8 = 0;
+const VCD_HERE: u8 = 1;
+const NEAR_CACHE_SIZE: usize = 4;
+const SAME_CACHE_SIZE: usize = 3;
+
+// Windows written by delta command cover this much of new file
+const WINDOW_SIZE: u64 = 1024 * 1024;
+
+// Source segment of written window spans at most this much of old or new file, unless single copy is longer
+// window is finished earlier when copies are far apart, e.g. after large part of old file was removed
+const MAX_SEGMENT_SIZE: u64 = 4 * WINDOW_SIZE;
+
+// Larger windows and segments of read deltas are refused, so malformed delta cannot exhaust memory
+const MAX_WINDOW_SIZE: u64 = 256 * 1024 * 1024;
+
+// Literal chunks of one repeated byte are written as RUN instruction
+const MIN_RUN_SIZE: usize = 4;
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum VcdiffError {
+    InvalidFormat,
+    Unsupported(&'static str),
+    ChecksumMismatch,
+    FileRead,
+    FileWrite
+}
+impl std::fmt::Display for VcdiffError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VcdiffError::InvalidFormat => write!(f, "Invalid VCDIFF data"),
+            VcdiffError::Unsupported(feature) => write!(f, "Unsupported VCDIFF feature: {}", feature),
+            VcdiffError::ChecksumMismatch => write!(f, "Checksum of VCDIFF window does not match"),
+            VcdiffError::FileRead => write!(f, "Unable to read source data"),
+            VcdiffError::FileWrite => write!(f, "Unable to write to output file")
+        }
+    }
+}
+impl std::error::Error for VcdiffError {}
+
+
+// Where data copied by COPY instruction comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CopyKind {
+    Source,     // old file
+    Target,     // new file written by previous windows
+    Window      // new file written by current window
+}
+
+#[derive(Debug)]
+enum Instruction {
+    Add(u64),
+    Run(u8, u64),
+    Copy(CopyKind, u64, u64) // kind, offset in old or new file, length
+}
+
+// Window of new file collected before it is written
+struct Window {
+    start: u64,
+    len: u64,
+    segment: Option<CopyKind>,
+    segment_start: u64,
+    segment_end: u64,
+    instructions: Vec<Instruction>,
+    literals: Vec<u8>
+}
+
+impl Window {
+    fn new(start: u64) -> Self {
+        Self { start, len: 0, segment: None, segment_start: u64::MAX, segment_end: 0, instructions: Vec::new(), literals: Vec::new() }
+    }
+}
+
+
+// Output which converts delta tag stream to VCDIFF
+// same chunks are copied from old file, chunks copied from output are copied from new file
+// window can use only one of them as source segment, so window is finished when the other one is needed
+// length of new file is required, as length of chunks is not stored in tag stream
+pub struct VcdiffOutput<S> {
+    output_file: S,
+    chunk_size: usize,
+    target_len: u64,
+    pending: Vec<u8>, // tag stream data which does not form whole instruction yet
+    position: u64,    // offset in new file
+    old_offset: u64,  // offset of next chunk in old file
+    window: Window,
+    error: Option<VcdiffError>
+}
+
+impl<S: ProcessorDataOutput> VcdiffOutput<S> {
+
+    pub fn new(mut output_file: S, chunk_size: usize, target_len: u64) -> Result<Self, VcdiffError> {
+        // header without secondary compression and custom code table
+        if !output_file.write_data(&VCDIFF_MAGIC) || !output_file.write_data(&[0]) {
+            return Err(VcdiffError::FileWrite)
+        }
+        Ok(Self { output_file, chunk_size, target_len, pending: Vec::new(), position: 0, old_offset: 0, window: Window::new(0), error: None })
+    }
+
+    // Writes last window
+    pub fn finish(mut self) -> Result<S, VcdiffError> {
+        if let Some(x) = self.error {
+            return Err(x)
+        }
+        if !self.pending.is_empty() || self.position != self.target_len {
+            return Err(VcdiffError::InvalidFormat)
+        }
+        self.write_window()?;
+        Ok(self.output_file)
+    }
+
+    // Converts whole instructions of pending tag stream, returns number of consumed bytes
+    fn convert_pending(&mut self) -> Result<usize, VcdiffError> {
+        let mut location = 0;
+        while let Some(&tag) = self.pending.get(location) {
+            let chunk_len = std::cmp::min(self.chunk_size as u64, self.target_len - self.position);
+            let tag = [tag];
+            if tag != TAG_REMOVED_CHUNK && chunk_len == 0 {
+                return Err(VcdiffError::InvalidFormat) // tag stream is longer than new file
+            }
+            let instruction_len = match tag {
+                TAG_DIFFERENT_HASH | TAG_INSERTED_CHUNK => TAG_SIZE + chunk_len as usize,
+                TAG_COPY_FROM_OUTPUT => TAG_SIZE + COPY_OFFSET_SIZE,
+                TAG_ZERO_CHUNK => TAG_SIZE + ZERO_LEN_SIZE,
+                _ => TAG_SIZE
+            };
+            if self.pending.len() - location < instruction_len {
+                break // rest of instruction was not written yet
+            }
+            let data = &self.pending[location + TAG_SIZE..location + instruction_len];
+
+            match tag {
+                TAG_SAME_HASH => {
+                    self.add_copy(CopyKind::Source, self.old_offset, chunk_len)?;
+                    self.old_offset += self.chunk_size as u64;
+                }
+                TAG_DIFFERENT_HASH | TAG_INSERTED_CHUNK => {
+                    let data = data.to_vec();
+                    self.add_literal(&data)?;
+                    if tag == TAG_DIFFERENT_HASH {
+                        self.old_offset += self.chunk_size as u64;
+                    }
+                }
+                TAG_REMOVED_CHUNK => self.old_offset += self.chunk_size as u64,
+                TAG_COPY_FROM_OUTPUT => {
+                    let offset = u64::from_le_bytes(data.try_into().map_err(|_| VcdiffError::InvalidFormat)?);
+                    if offset.checked_add(chunk_len).is_none_or(|end| end > self.position) {
+                        return Err(VcdiffError::InvalidFormat)
+                    }
+                    self.add_copy(CopyKind::Target, offset, chunk_len)?;
+                }
+                TAG_ZERO_CHUNK => {
+                    // zero chunk becomes a run of zeros
+                    let len = u64::from_le_bytes(data.try_into().map_err(|_| VcdiffError::InvalidFormat)?);
+                    if len != chunk_len {
+                        return Err(VcdiffError::InvalidFormat)
+                    }
+                    self.add_literal(&vec![0; len as usize])?;
+                }
+                _ => return Err(VcdiffError::InvalidFormat)
+            }
+            location += instruction_len;
+        }
+        Ok(location)
+    }
+
+    fn add_literal(&mut self, data: &[u8]) -> Result<(), VcdiffError> {
+        if self.window.len >= WINDOW_SIZE {
+            self.write_window()?;
+        }
+        let len = data.len() as u64;
+        let run = data.len() >= MIN_RUN_SIZE && data.iter().all(|byte| *byte == data[0]);
+        match self.window.instructions.last_mut() {
+            Some(Instruction::Run(byte, run_len)) if run && *byte == data[0] => *run_len += len,
+            Some(Instruction::Add(add_len)) if !run => {
+                *add_len += len;
+                self.window.literals.extend_from_slice(data);
+            }
+            _ if run => self.window.instructions.push(Instruction::Run(data[0], len)),
+            _ => {
+                self.window.instructions.push(Instruction::Add(len));
+                self.window.literals.extend_from_slice(data);
+            }
+        }
+        self.window.len += len;
+        self.position += len;
+        Ok(())
+    }
+
+    fn add_copy(&mut self, kind: CopyKind, offset: u64, len: u64) -> Result<(), VcdiffError> {
+        if self.window.len >= WINDOW_SIZE {
+            self.write_window()?;
+        }
+        let kind = if kind == CopyKind::Target && offset >= self.window.start { CopyKind::Window } else { kind };
+        if kind != CopyKind::Window {
+            // segment spans all data copied from old file or previous windows
+            let segment_start = std::cmp::min(self.window.segment_start, offset);
+            let segment_end = std::cmp::max(self.window.segment_end, offset + len);
+            if self.window.segment.is_some_and(|segment| segment != kind || segment_end - segment_start > MAX_SEGMENT_SIZE) {
+                self.write_window()?;
+            }
+            self.window.segment = Some(kind);
+            self.window.segment_start = std::cmp::min(self.window.segment_start, offset);
+            self.window.segment_end = std::cmp::max(self.window.segment_end, offset + len);
+        }
+        match self.window.instructions.last_mut() {
+            Some(Instruction::Copy(last_kind, last_offset, last_len)) if *last_kind == kind && *last_offset + *last_len == offset => *last_len += len,
+            _ => self.window.instructions.push(Instruction::Copy(kind, offset, len))
+        }
+        self.window.len += len;
+        self.position += len;
+        Ok(())
+    }
+
+    // Window format:
+    // indicator, [source segment length, source segment position], length of delta encoding,
+    // length of new data, delta indicator, lengths of data, instructions and addresses sections, sections
+    fn write_window(&mut self) -> Result<(), VcdiffError> {
+        let window = std::mem::replace(&mut self.window, Window::new(self.position));
+        if window.instructions.is_empty() {
+            return Ok(())
+        }
+
+        let segment_start = window.segment_start;
+        let segment_len = window.segment_end.saturating_sub(segment_start);
+
+        let mut data = Vec::new();
+        let mut instructions = Vec::new();
+        let mut addresses = Vec::new();
+        let mut literals = window.literals.as_slice();
+        let mut here = segment_len;
+        for instruction in &window.instructions {
+            match *instruction {
+                Instruction::Add(len) => {
+                    let (literal, rest) = literals.split_at(len as usize);
+                    data.extend_from_slice(literal);
+                    literals = rest;
+                    write_opcode(&mut instructions, ADD, len, 0);
+                    here += len;
+                }
+                Instruction::Run(byte, len) => {
+                    data.push(byte);
+                    write_opcode(&mut instructions, RUN, len, 0);
+                    here += len;
+                }
+                Instruction::Copy(kind, offset, len) => {
+                    let address = match kind {
+                        CopyKind::Window => segment_len + offset - window.start,
+                        _ => offset - segment_start
+                    };
+                    // address relative to current position is used when it is shorter
+                    let (mode, value) = if integer_len(here - address) < integer_len(address) { (VCD_HERE, here - address) } else { (VCD_SELF, address) };
+                    write_opcode(&mut instructions, COPY, len, mode);
+                    write_integer(&mut addresses, value);
+                    here += len;
+                }
+            }
+        }
+
+        let mut encoding = Vec::new();
+        write_integer(&mut encoding, window.len);
+        encoding.push(0);
+        write_integer(&mut encoding, data.len() as u64);
+        write_integer(&mut encoding, instructions.len() as u64);
+        write_integer(&mut encoding, addresses.len() as u64);
+        encoding.extend_from_slice(&data);
+        encoding.extend_from_slice(&instructions);
+        encoding.extend_from_slice(&addresses);
+
+        let mut header = Vec::new();
+        match window.segment {
+            Some(kind) => {
+                header.push(if kind == CopyKind::Source { VCD_SOURCE } else { VCD_TARGET });
+                write_integer(&mut header, segment_len);
+                write_integer(&mut header, segment_start);
+            }
+            None => header.push(0)
+        }
+        write_integer(&mut header, encoding.len() as u64);
+
+        if !self.output_file.write_data(&header) || !self.output_file.write_data(&encoding) {
+            return Err(VcdiffError::FileWrite)
+        }
+        Ok(())
+    }
+
+}
+
+impl<S: ProcessorDataOutput> ProcessorDataOutput for VcdiffOutput<S> {
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        if self.error.is_some() {
+            return false
+        }
+        self.pending.extend_from_slice(data);
+        match self.convert_pending() {
+            Ok(location) => {
+                self.pending.drain(..location);
+                true
+            }
+            Err(x) => {
+                self.error = Some(x);
+                false
+            }
+        }
+    }
+}
+
+// Writes instruction with default code table, only single instructions are used
+fn write_opcode(instructions: &mut Vec<u8>, kind: u8, len: u64, mode: u8) {
+    let (opcode, explicit_size) = match kind {
+        ADD if (1..=17).contains(&len) => (1 + len as u8, false),
+        ADD => (1, true),
+        COPY if (4..=18).contains(&len) => (19 + mode * 16 + (len - 3) as u8, false),
+        COPY => (19 + mode * 16, true),
+        _ => (0, true)
+    };
+    instructions.push(opcode);
+    if explicit_size {
+        write_integer(instructions, len);
+    }
+}
+
+
+// Checks if input starts with VCDIFF header, input is left at the same position
+pub fn is_vcdiff<U: ProcessorDataInput>(delta_file: &mut U) -> bool {
+    delta_file.set_chunk_size(VCDIFF_MAGIC.len() - 1);
+    let magic = delta_file.get_next_data() == &VCDIFF_MAGIC[..VCDIFF_MAGIC.len() - 1];
+    delta_file.move_back_last_read() && magic
+}
+
+// Applies VCDIFF delta to old file
+// source segments are read from old file, target segments are read back from output
+pub fn process_vcdiff_patch<R, U, S>(old_file: &mut R, delta_file: &mut U, output_file: &mut S) -> Result<(), VcdiffError>
+    where R: Read + Seek, U: ProcessorDataInput, S: ProcessorDataOutput
+{
+    let magic = read_bytes(delta_file, VCDIFF_MAGIC.len())?;
+    if magic[..VCDIFF_MAGIC.len() - 1] != VCDIFF_MAGIC[..VCDIFF_MAGIC.len() - 1] {
+        return Err(VcdiffError::InvalidFormat)
+    }
+    if magic[VCDIFF_MAGIC.len() - 1] != VCDIFF_MAGIC[VCDIFF_MAGIC.len() - 1] {
+        return Err(VcdiffError::Unsupported("version"))
+    }
+    let indicator = read_bytes(delta_file, 1)?[0];
+    if indicator & VCD_DECOMPRESS != 0 {
+        return Err(VcdiffError::Unsupported("secondary compression"))
+    }
+    if indicator & VCD_CODETABLE != 0 {
+        return Err(VcdiffError::Unsupported("custom code table"))
+    }
+    if indicator & !(VCD_DECOMPRESS | VCD_CODETABLE | VCD_APPHEADER) != 0 {
+        return Err(VcdiffError::InvalidFormat)
+    }
+    if indicator & VCD_APPHEADER != 0 {
+        // application data is not used
+        let len = read_window_size(delta_file)?;
+        read_bytes(delta_file, len)?;
+    }
+
+    let code_table = default_code_table();
+    let mut written = 0;
+    loop {
+        delta_file.set_chunk_size(1);
+        let indicator = match delta_file.get_next_data() {
+            [] => break, // reached end of file
+            data => data[0]
+        };
+        if indicator & !(VCD_SOURCE | VCD_TARGET | VCD_ADLER32) != 0 || indicator & (VCD_SOURCE | VCD_TARGET) == (VCD_SOURCE | VCD_TARGET) {
+            return Err(VcdiffError::InvalidFormat)
+        }
+
+        let mut segment = Vec::new();
+        if indicator & (VCD_SOURCE | VCD_TARGET) != 0 {
+            segment.resize(read_window_size(delta_file)?, 0);
+            let position = read_integer(delta_file)?;
+            if indicator & VCD_SOURCE != 0 {
+                if old_file.seek(SeekFrom::Start(position)).is_err() || old_file.read_exact(&mut segment).is_err() {
+                    return Err(VcdiffError::FileRead)
+                }
+            } else {
+                if position.checked_add(segment.len() as u64).is_none_or(|end| end > written) {
+                    return Err(VcdiffError::InvalidFormat)
+                }
+                if !output_file.read_back(position, &mut segment) {
+                    return Err(VcdiffError::FileRead)
+                }
+            }
+        }
+
+        let len = read_window_size(delta_file)?;
+        let encoding = read_bytes(delta_file, len)?;
+        let target = decode_window(&code_table, &segment, encoding, indicator & VCD_ADLER32 != 0)?;
+        if !output_file.write_data(&target) {
+            return Err(VcdiffError::FileWrite)
+        }
+        written += target.len() as u64;
+    }
+    Ok(())
+}
+
+fn decode_window(code_table: &[Code; 256], segment: &[u8], encoding: &[u8], checksum: bool) -> Result<Vec<u8>, VcdiffError> {
+    let mut reader = SliceReader { data: encoding };
+    let target_len = reader.integer()?;
+    if target_len > MAX_WINDOW_SIZE {
+        return Err(VcdiffError::InvalidFormat)
+    }
+    if reader.bytes(1)?[0] != 0 {
+        return Err(VcdiffError::Unsupported("compressed sections"))
+    }
+    let data_len = reader.integer()?;
+    let instructions_len = reader.integer()?;
+    let addresses_len = reader.integer()?;
+    let expected_checksum = if checksum { Some(u32::from_be_bytes(reader.bytes(4)?.try_into().unwrap())) } else { None };
+    let mut data = SliceReader { data: reader.bytes(data_len)? };
+    let mut instructions = SliceReader { data: reader.bytes(instructions_len)? };
+    let mut addresses = SliceReader { data: reader.bytes(addresses_len)? };
+    if !reader.data.is_empty() {
+        return Err(VcdiffError::InvalidFormat)
+    }
+
+    let segment_len = segment.len() as u64;
+    let mut target = Vec::with_capacity(target_len as usize);
+    let mut cache = AddressCache::new();
+    while !instructions.data.is_empty() {
+        let code = &code_table[instructions.bytes(1)?[0] as usize];
+        for (kind, size, mode) in [(code.inst1, code.size1, code.mode1), (code.inst2, code.size2, code.mode2)] {
+            if kind == NOOP {
+                continue
+            }
+            let size = if size == 0 { instructions.integer()? } else { size as u64 };
+            if size > target_len - target.len() as u64 {
+                return Err(VcdiffError::InvalidFormat)
+            }
+            match kind {
+                ADD => target.extend_from_slice(data.bytes(size)?),
+                RUN => {
+                    let byte = data.bytes(1)?[0];
+                    target.resize(target.len() + size as usize, byte);
+                }
+                _ => {
+                    // address space is source segment followed by target window, copy can overlap data it writes
+                    let here = segment_len + target.len() as u64;
+                    let address = cache.decode(mode, here, &mut addresses)?;
+                    if address + size <= segment_len {
+                        target.extend_from_slice(&segment[address as usize..(address + size) as usize]);
+                    } else {
+                        for location in address..address + size {
+                            let byte = if location < segment_len { segment[location as usize] } else { target[(location - segment_len) as usize] };
+                            target.push(byte);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if target.len() as u64 != target_len || !data.data.is_empty() || !addresses.data.is_empty() {
+        return Err(VcdiffError::InvalidFormat)
+    }
+    if expected_checksum.is_some_and(|expected| expected != adler32(&target)) {
+        return Err(VcdiffError::ChecksumMismatch)
+    }
+    Ok(target)
+}
+
+
+// Entry of code table, size 0 means size follows instruction
+#[derive(Debug, Clone, Copy, Default)]
+struct Code {
+    inst1: u8,
+    size1: u8,
+    mode1: u8,
+    inst2: u8,
+    size2: u8,
+    mode2: u8
+}
+
+// Default code table from RFC 3284 section 5.6
+fn default_code_table() -> [Code; 256] {
+    let mut table = [Code::default(); 256];
+    let modes = 2 + NEAR_CACHE_SIZE as u8 + SAME_CACHE_SIZE as u8;
+    table[0] = Code { inst1: RUN, ..Code::default() };
+    let mut index = 1;
+    let mut push = |code: Code| {
+        table[index] = code;
+        index += 1;
+    };
+    for size in 0..=17 {
+        push(Code { inst1: ADD, size1: size, ..Code::default() });
+    }
+    for mode in 0..modes {
+        push(Code { inst1: COPY, mode1: mode, ..Code::default() });
+        for size in 4..=18 {
+            push(Code { inst1: COPY, size1: size, mode1: mode, ..Code::default() });
+        }
+    }
+    for mode in 0..modes {
+        let copy_sizes = if mode < 2 + NEAR_CACHE_SIZE as u8 { 4..=6 } else { 4..=4 };
+        for add_size in 1..=4 {
+            for copy_size in copy_sizes.clone() {
+                push(Code { inst1: ADD, size1: add_size, mode1: 0, inst2: COPY, size2: copy_size, mode2: mode });
+            }
+        }
+    }
+    for mode in 0..modes {
+        push(Code { inst1: COPY, size1: 4, mode1: mode, inst2: ADD, size2: 1, mode2: 0 });
+    }
+    table
+}
+
+// Caches of recent addresses used by address modes
+struct AddressCache {
+    near: [u64; NEAR_CACHE_SIZE],
+    next_slot: usize,
+    same: [u64; SAME_CACHE_SIZE * 256]
+}
+
+impl AddressCache {
+    fn new() -> Self {
+        Self { near: [0; NEAR_CACHE_SIZE], next_slot: 0, same: [0; SAME_CACHE_SIZE * 256] }
+    }
+
+    fn decode(&mut self, mode: u8, here: u64, addresses: &mut SliceReader) -> Result<u64, VcdiffError> {
+        let mode = mode as usize;
+        let address = match mode {
+            0 => addresses.integer()?,
+            1 => here.checked_sub(addresses.integer()?).ok_or(VcdiffError::InvalidFormat)?,
+            _ if mode < 2 + NEAR_CACHE_SIZE => self.near[mode - 2].checked_add(addresses.integer()?).ok_or(VcdiffError::InvalidFormat)?,
+            _ => self.same[(mode - 2 - NEAR_CACHE_SIZE) * 256 + addresses.bytes(1)?[0] as usize]
+        };
+        if address >= here {
+            return Err(VcdiffError::InvalidFormat)
+        }
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % NEAR_CACHE_SIZE;
+        self.same[(address % (SAME_CACHE_SIZE * 256) as u64) as usize] = address;
+        Ok(address)
+    }
+}
+
+// Reads sections of window
+struct SliceReader<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> SliceReader<'a> {
+    fn bytes(&mut self, len: u64) -> Result<&'a [u8], VcdiffError> {
+        if len > self.data.len() as u64 {
+            return Err(VcdiffError::InvalidFormat)
+        }
+        let (bytes, rest) = self.data.split_at(len as usize);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn integer(&mut self) -> Result<u64, VcdiffError> {
+        let data = &mut self.data;
+        read_integer_with(|| {
+            let (byte, rest) = (*data).split_first()?;
+            *data = rest;
+            Some(*byte)
+        })
+    }
+}
+
+fn read_bytes<U: ProcessorDataInput>(delta_file: &mut U, len: usize) -> Result<&[u8], VcdiffError> {
+    delta_file.set_chunk_size(len);
+    match delta_file.get_next_data() {
+        data if data.len() == len => Ok(data),
+        _ => Err(VcdiffError::InvalidFormat)
+    }
+}
+
+fn read_integer<U: ProcessorDataInput>(delta_file: &mut U) -> Result<u64, VcdiffError> {
+    delta_file.set_chunk_size(1);
+    read_integer_with(|| delta_file.get_next_data().first().copied())
+}
+
+fn read_window_size<U: ProcessorDataInput>(delta_file: &mut U) -> Result<usize, VcdiffError> {
+    match read_integer(delta_file)? {
+        size if size <= MAX_WINDOW_SIZE => Ok(size as usize),
+        _ => Err(VcdiffError::InvalidFormat)
+    }
+}
+
+// Integers are written in base 128, most significant digit first, all digits except last have highest bit set
+fn read_integer_with<F: FnMut() -> Option<u8>>(mut next_byte: F) -> Result<u64, VcdiffError> {
+    let mut value: u64 = 0;
+    loop {
+        let byte = next_byte().ok_or(VcdiffError::InvalidFormat)?;
+        if value > u64::MAX >> 7 {
+            return Err(VcdiffError::InvalidFormat)
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+}
+
+fn write_integer(output: &mut Vec<u8>, value: u64) {
+    let len = integer_len(value);
+    for digit in (0..len).rev() {
+        let byte = ((value >> (7 * digit)) & 0x7f) as u8;
+        output.push(if digit > 0 { byte | 0x80 } else { byte });
+    }
+}
+
+fn integer_len(value: u64) -> usize {
+    let bits = 64 - value.leading_zeros() as usize;
+    std::cmp::max(1, bits.div_ceil(7))
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for part in data.chunks(5552) {
+        for byte in part {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+    (b << 16) | a
+}
+
+
+// VCDIFF tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mem_data::*;
+
+    fn patch(old: &[u8], delta: &[u8]) -> Result<Vec<u8>, VcdiffError> {
+        let mut output = Vec::new();
+        process_vcdiff_patch(&mut std::io::Cursor::new(old), &mut MemInput::new(delta, 1), &mut output)?;
+        Ok(output)
+    }
+
+    fn delta(old: &[u8], new: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut signature = Vec::new();
+        Processor::new(&mut MemInput::new(old, chunk_size), &mut signature).process_signature().unwrap();
+        let mut output = VcdiffOutput::new(Vec::new(), chunk_size, new.len() as u64).unwrap();
+        Processor::new(&mut MemInput::new(new, chunk_size), &mut output).process_delta(&mut MemInput::new(&signature, HASH_SIZE)).unwrap();
+        output.finish().unwrap()
+    }
+
+    #[test]
+    fn test_vcdiff_patch_1() {
+        // VCDIFF test
+        // scenario: example of RFC 3284 with copies from source and overlapping copy from target, ADD and COPY share opcode
+        // old file: abcdefghijklmnop
+        // new file: abcdwxyzefghefghefghefghzzzz
+
+        let delta = [0xd6,0xc3,0xc4,0x00,0x00,
+                     0x01,0x10,0x00,0x12,
+                     0x1c,0x00,0x05,0x05,0x03,
+                     b'w',b'x',b'y',b'z',b'z',
+                     0x14,0xac,0x2c,0x00,0x04,
+                     0x00,0x04,0x04];
+        assert_eq!( patch(b"abcdefghijklmnop", &delta).unwrap(), b"abcdwxyzefghefghefghefghzzzz" );
+    }
+
+    #[test]
+    fn test_vcdiff_patch_2() {
+        // VCDIFF test
+        // scenario: application header, checksum, near and same cache addresses, second window copies from first window
+        // new file: hello hellohellohellohell!
+
+        let delta = [0xd6,0xc3,0xc4,0x00,0x04,0x03,b'a',b'b',b'c',
+                     0x04,0x16,
+                     0x15,0x00,0x06,0x04,0x03,0x5b,0x1d,0x08,0x71,
+                     b'h',b'e',b'l',b'l',b'o',b' ',
+                     0x07,0x15,0x35,0x75,
+                     0x00,0x00,0x00,
+                     0x02,0x05,0x06,0x08,
+                     0x05,0x00,0x01,0x01,0x01,b'!',0xf7,0x00];
+        assert_eq!( patch(b"", &delta).unwrap(), b"hello hellohellohellohell!" );
+
+        let mut modified = delta;
+        modified[20] = b'j';
+        assert!( matches!( patch(b"", &modified), Err(VcdiffError::ChecksumMismatch) ) );
+    }
+
+    #[test]
+    fn test_vcdiff_patch_invalid() {
+        // VCDIFF test
+        // scenario: truncated delta, copy of data not decoded yet, source outside of old file and compressed delta are refused
+
+        let delta = [0xd6,0xc3,0xc4,0x00,0x00, 0x00,0x07, 0x04,0x00,0x00,0x01,0x01, 0x14,0x00];
+        assert!( matches!( patch(b"", &delta), Err(VcdiffError::InvalidFormat) ) );
+        assert!( matches!( patch(b"", &delta[..10]), Err(VcdiffError::InvalidFormat) ) );
+
+        let delta = [0xd6,0xc3,0xc4,0x00,0x00, 0x01,0x04,0x00,0x07, 0x04,0x00,0x00,0x01,0x01, 0x14,0x00];
+        assert!( matches!( patch(b"abc", &delta), Err(VcdiffError::FileRead) ) );
+        assert_eq!( patch(b"abcd", &delta).unwrap(), b"abcd" );
+
+        assert!( matches!( patch(b"", &[0xd6,0xc3,0xc4,0x00,0x01,0x02]), Err(VcdiffError::Unsupported(_)) ) );
+        assert!( !is_vcdiff(&mut MemInput::new(b"HDIF", 1)) );
+    }
+
+    #[test]
+    fn test_vcdiff_delta_1() {
+        // VCDIFF test
+        // scenario: same chunks are copied from old file, repeated new chunk is copied from window, uniform chunk is run (chunk size: 16)
+        // old file: 1..16, 1..16
+        // new file: 1..16, 5..20, 5..20, 0 x 16, 1,2
+
+        let old: Vec<u8> = [(1..=16).collect::<Vec<u8>>(), (1..=16).collect()].concat();
+        let new: Vec<u8> = [(1..=16).collect::<Vec<u8>>(), (5..=20).collect(), (5..=20).collect(), vec![0; 16], vec![1,2]].concat();
+        let output = delta(&old, &new, 16);
+        assert_eq!( output, [0xd6,0xc3,0xc4,0x00,0x00,
+                             0x01,0x10,0x00,0x20,
+                             0x42,0x00,0x13,0x06,0x02,
+                             5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,0,1,2,
+                             0x20,0x11,0x20,0x00,0x10,0x03,
+                             0x00,0x20] );
+        assert!( is_vcdiff(&mut MemInput::new(&output, 1)) );
+        assert_eq!( patch(&old, &output).unwrap(), new );
+    }
+
+    #[test]
+    fn test_vcdiff_delta_2() {
+        // VCDIFF test
+        // scenario: windows of large file copy data from old file and from previous windows
+
+        let old: Vec<u8> = (0..WINDOW_SIZE as usize * 2).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new[100] ^= 1;
+        new.extend_from_slice(&old[1024..2048]);
+        new.extend_from_within(..1024);
+        new.extend_from_slice(&old[WINDOW_SIZE as usize + 4096..]);
+        new.extend_from_within(WINDOW_SIZE as usize * 2 + 1024..WINDOW_SIZE as usize * 2 + 2048);
+        new.extend_from_slice(&[9; 10]);
+        let output = delta(&old, &new, 1024);
+        assert!( output.len() < 4096 );
+        assert_eq!( patch(&old, &output).unwrap(), new );
+    }
+
+    #[test]
+    fn test_vcdiff_delta_3() {
+        // VCDIFF test
+        // scenario: tag stream removes large part of old file, window is finished before its source segment exceeds limit
+
+        let old: Vec<u8> = (0..MAX_SEGMENT_SIZE as usize * 2).map(|i| (i * 7 % 251) as u8).collect();
+        let new = [&old[..1024], &old[old.len() - 1024..]].concat();
+        let mut output = VcdiffOutput::new(Vec::new(), 1024, new.len() as u64).unwrap();
+        let removed = vec![TAG_REMOVED_CHUNK[0]; old.len() / 1024 - 2];
+        assert!( output.write_data(&[&TAG_SAME_HASH[..], &removed, &TAG_SAME_HASH].concat()) );
+        let output = output.finish().unwrap();
+        assert_eq!( patch(&old, &output).unwrap(), new );
+
+        // segment length of every window is in limit
+        let mut input = MemInput::new(&output[5..], 1);
+        let mut windows = 0;
+        while let Ok(indicator) = read_bytes(&mut input, 1).map(|data| data[0]) {
+            if indicator & VCD_SOURCE != 0 {
+                assert!( read_integer(&mut input).unwrap() <= MAX_SEGMENT_SIZE );
+                read_integer(&mut input).unwrap();
+            }
+            let len = read_integer(&mut input).unwrap() as usize;
+            read_bytes(&mut input, len).unwrap();
+            windows += 1;
+        }
+        assert_eq!( windows, 2 );
+    }
+}
@@ -0,0 +1,588 @@
+use std::{collections::HashMap, error::Error, io::{BufRead, BufReader, Read, Seek, SeekFrom, Write}, net::TcpStream, path::{Path, PathBuf}};
+use sha2::{Sha256, Digest};
+use super::processor::*;
+use super::input_file::*;
+use super::output_file::*;
+use super::mem_data::*;
+use super::file_header::*;
+use super::rdiff::WeakSum;
+
+// Size of weak checksum stored for every chunk
+const WEAK_SUM_SIZE: usize = 4;
+
+// Data of old file and HTTP responses is processed in parts of this size
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// Limits protecting against malformed control files and responses
+const MAX_URL_SIZE: usize = 4096;
+const MAX_HEADER_LINE_SIZE: usize = 8192;
+const MAX_CONTROL_SIZE: u64 = 64 * 1024 * 1024;
+
+
+// Custom error codes
+#[derive(Debug)]
+pub enum ZsyncError {
+    InvalidUrl(String),
+    InvalidControl,
+    InvalidResponse,
+    RangeNotSupported,
+    HttpStatus(u16),
+    DigestMismatch,
+    ControlTooLarge,
+    InvalidFileName(String)
+}
+impl std::fmt::Display for ZsyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ZsyncError::InvalidUrl(url) => write!(f, "Unsupported URL: {}", url),
+            ZsyncError::InvalidControl => write!(f, "Control file is malformed"),
+            ZsyncError::InvalidResponse => write!(f, "HTTP response is malformed"),
+            ZsyncError::RangeNotSupported => write!(f, "HTTP server does not support range requests"),
+            ZsyncError::HttpStatus(status) => write!(f, "HTTP request failed with status {}", status),
+            ZsyncError::DigestMismatch => write!(f, "Digest of fetched file does not match digest in control file"),
+            ZsyncError::ControlTooLarge => write!(f, "Control file is larger than {} bytes", MAX_CONTROL_SIZE),
+            ZsyncError::InvalidFileName(url) => write!(f, "URL in control file has no usable file name: {}, local file has to be given", url)
+        }
+    }
+}
+impl Error for ZsyncError {}
+
+// Control file published next to a file on HTTP server
+// control file format:
+// header, u32 URL length, URL of the file relative to control file, u64 file length, SHA-256 digest of the file,
+// u32 rsync rolling checksum and SHA-256 hash of every chunk, all numbers are little endian
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Control {
+    pub chunk_size: usize,
+    pub url: String,
+    pub file_len: u64,
+    pub digest: [u8; HASH_SIZE],
+    pub chunks: Vec<(u32, [u8; HASH_SIZE])>
+}
+
+impl Control {
+
+    pub fn read(data: &[u8]) -> Result<Self, ZsyncError> {
+        let mut input = MemInput::new(data, 1);
+        let chunk_size = match FileHeader::read(&mut input) {
+            Ok(Some(header)) if header.kind == FileKind::Control => header.chunk_size,
+            _ => return Err(ZsyncError::InvalidControl)
+        };
+
+        let url_len = u32::from_le_bytes(read_array(&mut input)?) as usize;
+        if url_len > MAX_URL_SIZE {
+            return Err(ZsyncError::InvalidControl)
+        }
+        input.set_chunk_size(url_len);
+        let url = String::from_utf8(input.get_next_data().to_vec()).map_err(|_| ZsyncError::InvalidControl)?;
+        if url.len() != url_len {
+            return Err(ZsyncError::InvalidControl)
+        }
+        let file_len = u64::from_le_bytes(read_array(&mut input)?);
+        let digest = read_array(&mut input)?;
+
+        // number of chunks has to match file length
+        let chunk_count = file_len.div_ceil(chunk_size as u64);
+        if input.remaining_len() != chunk_count.saturating_mul((WEAK_SUM_SIZE + HASH_SIZE) as u64) {
+            return Err(ZsyncError::InvalidControl)
+        }
+        let mut chunks = Vec::new();
+        for _ in 0..chunk_count {
+            chunks.push((u32::from_le_bytes(read_array(&mut input)?), read_array(&mut input)?));
+        }
+
+        Ok(Self { chunk_size, url, file_len, digest, chunks })
+    }
+
+    // Length of chunk at given index, last chunk can be shorter
+    fn chunk_len(&self, index: usize) -> usize {
+        std::cmp::min(self.chunk_size as u64, self.file_len - (index * self.chunk_size) as u64) as usize
+    }
+}
+
+// Number of bytes taken from local file and fetched from server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FetchStats {
+    pub reused: u64,
+    pub fetched: u64
+}
+
+// Make-control command logic, url is location of the file relative to control file
+pub fn make_control<S: ProcessorDataOutput>(input_path: &Path, url: &str, chunk_size: usize, output_file: &mut S) -> Result<(), Box<dyn Error>> {
+    if url.len() > MAX_URL_SIZE {
+        return Err(Box::new(ZsyncError::InvalidUrl(url.to_owned())))
+    }
+    let mut input_file = InputFile::new(input_path, chunk_size)?;
+    let file_len = input_file.total_len();
+
+    let mut digest = Sha256::new();
+    let mut chunks = Vec::new();
+    loop {
+        let chunk = input_file.get_next_data();
+        if chunk.is_empty() {
+            break
+        }
+        digest.update(chunk);
+        chunks.extend_from_slice(&WeakSum::new(false, chunk).digest().to_le_bytes());
+        chunks.extend_from_slice(&calculate_chunk_hash(chunk));
+    }
+
+    FileHeader::new(FileKind::Control, chunk_size, None).write(output_file)?;
+    let mut data = Vec::new();
+    data.extend_from_slice(&(url.len() as u32).to_le_bytes());
+    data.extend_from_slice(url.as_bytes());
+    data.extend_from_slice(&file_len.to_le_bytes());
+    data.extend_from_slice(&digest.finalize());
+    data.extend_from_slice(&chunks);
+    if !output_file.write_data(&data) {
+        return Err(Box::new(ProcessorError::FileWrite))
+    }
+    Ok(())
+}
+
+// Zsync-fetch command logic, local file is replaced with the file described by control file
+// chunks found anywhere in local file are reused, missing ranges are fetched with HTTP range requests
+// local file defaults to file name from control file, it is always created in current directory
+pub fn zsync_fetch(control_url: &str, local_file: Option<&Path>) -> Result<FetchStats, Box<dyn Error>> {
+    let control = Control::read(&http_get(control_url, MAX_CONTROL_SIZE)?)?;
+    let file_url = resolve_url(control_url, &control.url);
+    let local_file = match local_file {
+        Some(local_file) => local_file.to_path_buf(),
+        None => local_file_name(&control.url)?
+    };
+
+    // missing local file is fetched completely
+    let mut old_file = match std::fs::File::open(&local_file) {
+        Ok(f) => Some(f),
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => None,
+        Err(x) => return Err(Box::new(x))
+    };
+    let old_offsets = match &mut old_file {
+        Some(f) => find_local_chunks(&mut BufReader::new(&mut *f), &control)?,
+        None => vec![None; control.chunks.len()]
+    };
+
+    let mut output_file = OutputFile::new(&local_file)?;
+    let mut digest = Sha256::new();
+    let mut stats = FetchStats { reused: 0, fetched: 0 };
+    let mut buffer = vec![0; std::cmp::max(BUFFER_SIZE, control.chunk_size)];
+    let mut index = 0;
+    while index < control.chunks.len() {
+        let start = (index * control.chunk_size) as u64;
+        if let (Some(offset), Some(f)) = (old_offsets[index], &mut old_file) {
+            let chunk = &mut buffer[..control.chunk_len(index)];
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(chunk)?;
+            digest.update(&*chunk);
+            write_output(&mut output_file, chunk)?;
+            stats.reused += chunk.len() as u64;
+            index += 1;
+            continue
+        }
+
+        // consecutive missing chunks are fetched with single request
+        let mut end = index + 1;
+        while end < control.chunks.len() && old_offsets[end].is_none() {
+            end += 1;
+        }
+        let end_offset = std::cmp::min((end * control.chunk_size) as u64, control.file_len);
+        let mut body = http_get_range(&file_url, start, end_offset)?;
+        let mut len = end_offset - start;
+        while len > 0 {
+            let part = &mut buffer[..std::cmp::min(len, BUFFER_SIZE as u64) as usize];
+            body.read_exact(part).map_err(|_| ZsyncError::InvalidResponse)?;
+            digest.update(&*part);
+            write_output(&mut output_file, part)?;
+            len -= part.len() as u64;
+        }
+        stats.fetched += end_offset - start;
+        index = end;
+    }
+
+    // local file is replaced only if it is same as file on server
+    if digest.finalize()[..] != control.digest {
+        return Err(Box::new(ZsyncError::DigestMismatch))
+    }
+    output_file.finish()?;
+    Ok(stats)
+}
+
+// Finds offsets of chunks in old data, rolling checksum is moved by one byte until it matches some chunk
+// last chunk shorter than chunk size is always fetched
+fn find_local_chunks<R: Read>(old_file: &mut R, control: &Control) -> Result<Vec<Option<u64>>, Box<dyn Error>> {
+    let chunk_size = control.chunk_size;
+    let mut offsets = vec![None; control.chunks.len()];
+    let mut weak_index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, (weak, _)) in control.chunks.iter().enumerate() {
+        if control.chunk_len(index) == chunk_size {
+            weak_index.entry(*weak).or_default().push(index);
+        }
+    }
+
+    // data holds window of old file starting at data_offset, it is refilled when window reaches its end
+    let mut data = Vec::new();
+    let mut data_offset = 0u64;
+    let mut start = 0;
+    let mut weak: Option<WeakSum> = None;
+    loop {
+        if data.len() - start < chunk_size + 1 {
+            data.drain(..start);
+            data_offset += start as u64;
+            start = 0;
+            let len = data.len();
+            data.resize(len + BUFFER_SIZE, 0);
+            let read = old_file.read(&mut data[len..])?;
+            data.truncate(len + read);
+            if data.len() < chunk_size {
+                return Ok(offsets)
+            }
+        }
+
+        let window = &data[start..start + chunk_size];
+        let sum = weak.get_or_insert_with(|| WeakSum::new(false, window));
+        let mut matched = false;
+        if let Some(indexes) = weak_index.get(&sum.digest()) {
+            let hash = calculate_chunk_hash(window);
+            for index in indexes {
+                if control.chunks[*index].1 == hash {
+                    matched = true;
+                    offsets[*index].get_or_insert(data_offset + start as u64);
+                }
+            }
+        }
+
+        // window moves past matched chunk, otherwise by one byte
+        if matched {
+            start += chunk_size;
+            weak = None;
+        } else if start + chunk_size < data.len() {
+            sum.rotate(data[start], data[start + chunk_size]);
+            start += 1;
+        } else {
+            return Ok(offsets)
+        }
+    }
+}
+
+fn write_output(output_file: &mut OutputFile, data: &[u8]) -> Result<(), ProcessorError> {
+    if output_file.write_data(data) { Ok(()) } else { Err(ProcessorError::FileWrite) }
+}
+
+fn read_array<const N: usize>(input: &mut MemInput) -> Result<[u8; N], ZsyncError> {
+    input.set_chunk_size(N);
+    <[u8; N]>::try_from(input.get_next_data()).map_err(|_| ZsyncError::InvalidControl)
+}
+
+// URL relative to control file is resolved against directory of control file
+fn resolve_url(control_url: &str, url: &str) -> String {
+    if url.contains("://") {
+        return url.to_owned()
+    }
+    match control_url.rfind('/') {
+        Some(index) if index > "http://".len() => format!("{}/{}", &control_url[..index], url),
+        _ => format!("{}/{}", control_url, url)
+    }
+}
+
+// Last segment of URL without query, name which could be hidden file or move out of current directory is refused
+fn local_file_name(url: &str) -> Result<PathBuf, ZsyncError> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    // host of absolute URL is not a file name
+    let path = match path.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("", |index| &rest[index..]),
+        None => path
+    };
+    let name = path.rsplit('/').next().unwrap_or_default();
+    if name.is_empty() || name.starts_with('.') || name.contains('\\') {
+        return Err(ZsyncError::InvalidFileName(url.to_owned()))
+    }
+    Ok(PathBuf::from(name))
+}
+
+// Location of resource on HTTP server, only plain HTTP is supported
+struct HttpUrl {
+    host: String,
+    address: String,
+    path: String
+}
+
+fn parse_url(url: &str) -> Result<HttpUrl, ZsyncError> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| ZsyncError::InvalidUrl(url.to_owned()))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/")
+    };
+    if host.is_empty() {
+        return Err(ZsyncError::InvalidUrl(url.to_owned()))
+    }
+    // default port is added when host does not end with port, IPv6 address ends with bracket
+    let address = match host.rsplit_once(':') {
+        Some((_, port)) if !port.contains(']') => host.to_owned(),
+        _ => format!("{}:80", host)
+    };
+    Ok(HttpUrl { host: host.to_owned(), address, path: path.to_owned() })
+}
+
+// Response of HTTP server, body is read from connection
+struct HttpResponse {
+    status: u16,
+    len: Option<u64>,
+    content_range: Option<String>,
+    body: BufReader<TcpStream>
+}
+
+// Whole resource is downloaded, resource larger than max_len is refused
+fn http_get(url: &str, max_len: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = http_request(&parse_url(url)?, None)?;
+    if response.status != 200 {
+        return Err(Box::new(ZsyncError::HttpStatus(response.status)))
+    }
+    if response.len.is_some_and(|len| len > max_len) {
+        return Err(Box::new(ZsyncError::ControlTooLarge))
+    }
+    // body without length is read until the limit is exceeded
+    let mut data = Vec::new();
+    response.body.take(std::cmp::min(response.len.unwrap_or(u64::MAX), max_len + 1)).read_to_end(&mut data)?;
+    if data.len() as u64 > max_len {
+        return Err(Box::new(ZsyncError::ControlTooLarge))
+    }
+    if response.len.is_some_and(|len| len != data.len() as u64) {
+        return Err(Box::new(ZsyncError::InvalidResponse))
+    }
+    Ok(data)
+}
+
+// Range of resource from start to end offset is downloaded, server has to answer with partial content
+fn http_get_range(url: &str, start: u64, end: u64) -> Result<impl Read, Box<dyn Error>> {
+    let response = http_request(&parse_url(url)?, Some((start, end)))?;
+    match response.status {
+        206 => {}
+        200 => return Err(Box::new(ZsyncError::RangeNotSupported)),
+        status => return Err(Box::new(ZsyncError::HttpStatus(status)))
+    }
+    let expected_range = format!("bytes {}-{}/", start, end - 1);
+    if !response.content_range.is_some_and(|range| range.starts_with(&expected_range)) || response.len.is_some_and(|len| len != end - start) {
+        return Err(Box::new(ZsyncError::InvalidResponse))
+    }
+    Ok(response.body.take(end - start))
+}
+
+// Sends GET request and reads headers of response
+// connection is closed by server after response, so body without length ends with the connection
+fn http_request(url: &HttpUrl, range: Option<(u64, u64)>) -> Result<HttpResponse, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(&url.address)?;
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: hdiff\r\nConnection: close\r\n", url.path, url.host);
+    if let Some((start, end)) = range {
+        request.push_str(&format!("Range: bytes={}-{}\r\n", start, end - 1));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    let mut reader = BufReader::new(stream);
+    let status_line = read_line(&mut reader)?;
+    let status = match status_line.split(' ').collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") => status.parse::<u16>().map_err(|_| ZsyncError::InvalidResponse)?,
+        _ => return Err(Box::new(ZsyncError::InvalidResponse))
+    };
+
+    let mut len = None;
+    let mut content_range = None;
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break
+        }
+        let (name, value) = line.split_once(':').ok_or(ZsyncError::InvalidResponse)?;
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => len = Some(value.trim().parse::<u64>().map_err(|_| ZsyncError::InvalidResponse)?),
+            "content-range" => content_range = Some(value.trim().to_owned()),
+            // chunked encoding is not used by servers answering with files
+            "transfer-encoding" if !value.trim().eq_ignore_ascii_case("identity") => return Err(Box::new(ZsyncError::InvalidResponse)),
+            _ => {}
+        }
+    }
+    Ok(HttpResponse { status, len, content_range, body: reader })
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, Box<dyn Error>> {
+    let mut line = Vec::new();
+    reader.take(MAX_HEADER_LINE_SIZE as u64).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(Box::new(ZsyncError::InvalidResponse))
+    }
+    let line = String::from_utf8(line).map_err(|_| ZsyncError::InvalidResponse)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+
+// Zsync tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::test_dir::*;
+    use std::{net::TcpListener, sync::{Arc, Mutex}};
+
+    // Minimal HTTP server answering given number of requests with files from memory, requested ranges are recorded
+    fn start_http_server(files: Vec<(&str, Vec<u8>)>, requests: usize, ranges: bool) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let files: HashMap<String, Vec<u8>> = files.into_iter().map(|(name, data)| (format!("/{}", name), data)).collect();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let server_log = log.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let request_line = read_line(&mut reader).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap().to_owned();
+                let mut range = None;
+                loop {
+                    let line = read_line(&mut reader).unwrap();
+                    if line.is_empty() {
+                        break
+                    }
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        let (start, end) = value.split_once('-').unwrap();
+                        range = Some((start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+                    }
+                }
+                let response = match (files.get(&path), range) {
+                    (Some(data), Some((start, end))) if ranges => {
+                        server_log.lock().unwrap().push(format!("{} {}-{}", path, start, end));
+                        let mut response = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                                                   end + 1 - start, start, end, data.len()).into_bytes();
+                        response.extend_from_slice(&data[start..=end]);
+                        response
+                    }
+                    (Some(data), _) => {
+                        server_log.lock().unwrap().push(path);
+                        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len()).into_bytes();
+                        response.extend_from_slice(data);
+                        response
+                    }
+                    (None, _) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec()
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, log)
+    }
+
+    fn control(data: &[u8], url: &str, chunk_size: usize) -> Vec<u8> {
+        let dir = TestDir::new(&format!("zsync-control-{}-{}", url, data.len()));
+        std::fs::write(dir.path.join("file"), data).unwrap();
+        let mut output = Vec::new();
+        make_control(&dir.path.join("file"), url, chunk_size, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_zsync_control() {
+        // zsync test
+        // scenario: control file contains checksums of all chunks, last chunk is shorter, malformed control is refused
+
+        let data = control(&[1,2,3,4,5,6], "file.bin", 4);
+        let control = Control::read(&data).unwrap();
+        assert_eq!( control.chunk_size, 4 );
+        assert_eq!( control.url, "file.bin" );
+        assert_eq!( control.file_len, 6 );
+        assert_eq!( control.digest[..], Sha256::digest([1,2,3,4,5,6])[..] );
+        assert_eq!( control.chunks, [(WeakSum::new(false, &[1,2,3,4]).digest(), calculate_chunk_hash(&[1,2,3,4])),
+                                     (WeakSum::new(false, &[5,6]).digest(), calculate_chunk_hash(&[5,6]))] );
+        assert_eq!( (control.chunk_len(0), control.chunk_len(1)), (4, 2) );
+
+        assert!( Control::read(&data[..data.len() - 1]).is_err() );
+        assert!( Control::read(&[&data[..], &[0]].concat()).is_err() );
+        assert!( Control::read(&data[11..]).is_err() );
+    }
+
+    #[test]
+    fn test_zsync_urls() {
+        // zsync test
+        // scenario: file URL is resolved against control URL, host and port are parsed, local file name is taken from file URL
+
+        assert_eq!( resolve_url("http://host/dir/file.control", "file.bin"), "http://host/dir/file.bin" );
+        assert_eq!( resolve_url("http://host", "file.bin"), "http://host/file.bin" );
+        assert_eq!( resolve_url("http://host/file.control", "http://other/file.bin"), "http://other/file.bin" );
+
+        let url = parse_url("http://host:8080/dir/file").unwrap();
+        assert_eq!( (url.host.as_str(), url.address.as_str(), url.path.as_str()), ("host:8080", "host:8080", "/dir/file") );
+        let url = parse_url("http://[::1]").unwrap();
+        assert_eq!( (url.address.as_str(), url.path.as_str()), ("[::1]:80", "/") );
+        assert!( parse_url("https://host/file").is_err() );
+        assert!( parse_url("http:///file").is_err() );
+
+        assert_eq!( local_file_name("dir/file.bin?version=2#top").unwrap(), PathBuf::from("file.bin") );
+        assert_eq!( local_file_name("http://host/file.bin").unwrap(), PathBuf::from("file.bin") );
+        for url in ["", "dir/", "..", "dir/..", ".bashrc", "file.bin/?x", "http://host", "http://host/", "dir\\file.bin"] {
+            assert!( matches!( local_file_name(url), Err(ZsyncError::InvalidFileName(_)) ), "{}", url );
+        }
+    }
+
+    #[test]
+    fn test_zsync_fetch_changed() {
+        // zsync test
+        // scenario: local file has shifted and changed data, only missing chunks are fetched
+
+        let new_data: Vec<u8> = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let mut old_data = new_data.clone();
+        old_data.splice(0..0, [9; 13]);
+        old_data[2000] = 0xff;
+        let (url, log) = start_http_server(vec![("file.control", control(&new_data, "file.bin", 256)), ("file.bin", new_data.clone())], 3, true);
+
+        let dir = TestDir::new("zsync-fetch-changed");
+        let local_file = dir.path.join("file.bin");
+        std::fs::write(&local_file, &old_data).unwrap();
+        let stats = zsync_fetch(&format!("{}/file.control", url), Some(&local_file)).unwrap();
+
+        assert_eq!( std::fs::read(&local_file).unwrap(), new_data );
+        assert_eq!( stats, FetchStats { reused: 4096 - 256, fetched: 256 } );
+        assert_eq!( *log.lock().unwrap(), ["/file.control", "/file.bin 1792-2047"] );
+    }
+
+    #[test]
+    fn test_zsync_fetch_missing() {
+        // zsync test
+        // scenario: missing local file is fetched with single range request
+
+        let new_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let (url, log) = start_http_server(vec![("file.control", control(&new_data, "file.bin", 256)), ("file.bin", new_data.clone())], 2, true);
+
+        let dir = TestDir::new("zsync-fetch-missing");
+        let local_file = dir.path.join("file.bin");
+        let stats = zsync_fetch(&format!("{}/file.control", url), Some(&local_file)).unwrap();
+
+        assert_eq!( std::fs::read(&local_file).unwrap(), new_data );
+        assert_eq!( stats, FetchStats { reused: 0, fetched: 1000 } );
+        assert_eq!( *log.lock().unwrap(), ["/file.control", "/file.bin 0-999"] );
+    }
+
+    #[test]
+    fn test_zsync_fetch_invalid() {
+        // zsync test
+        // scenario: server without range support and file not matching control file leave local file unchanged, large control file is refused
+
+        let new_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let dir = TestDir::new("zsync-fetch-invalid");
+        let local_file = dir.path.join("file.bin");
+        std::fs::write(&local_file, [1,2,3]).unwrap();
+
+        let (url, _) = start_http_server(vec![("file.control", control(&new_data, "file.bin", 256)), ("file.bin", new_data.clone())], 2, false);
+        let result = zsync_fetch(&format!("{}/file.control", url), Some(&local_file));
+        assert!( matches!( result.unwrap_err().downcast_ref::<ZsyncError>(), Some(ZsyncError::RangeNotSupported) ) );
+
+        let (url, _) = start_http_server(vec![("file.control", control(&new_data, "file.bin", 256)), ("file.bin", vec![0; 1000])], 2, true);
+        let result = zsync_fetch(&format!("{}/file.control", url), Some(&local_file));
+        assert!( matches!( result.unwrap_err().downcast_ref::<ZsyncError>(), Some(ZsyncError::DigestMismatch) ) );
+
+        let (url, _) = start_http_server(vec![], 1, true);
+        let result = zsync_fetch(&format!("{}/file.control", url), Some(&local_file));
+        assert!( matches!( result.unwrap_err().downcast_ref::<ZsyncError>(), Some(ZsyncError::HttpStatus(404)) ) );
+
+        assert_eq!( std::fs::read(&local_file).unwrap(), [1,2,3] );
+
+        let (url, _) = start_http_server(vec![("file.control", control(&new_data, "file.bin", 256))], 1, true);
+        let result = http_get(&format!("{}/file.control", url), 100);
+        assert!( matches!( result.unwrap_err().downcast_ref::<ZsyncError>(), Some(ZsyncError::ControlTooLarge) ) );
+    }
+}